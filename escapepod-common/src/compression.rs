@@ -0,0 +1,174 @@
+// compression of the payloads of buffers and files. each codec is behind the
+// cargo feature of the same name and offered in the hello, the origin then
+// compresses with one both peers support
+use std::{borrow::Cow, fmt, str::FromStr};
+
+use anyhow::{bail, Result};
+
+use crate::proto::{Compression, Payload};
+
+// the hello features of the codecs this build supports
+pub(crate) const CODECS: &[&str] = &[
+    #[cfg(feature = "zstd")]
+    "zstd",
+    #[cfg(feature = "lz4")]
+    "lz4",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Preference {
+    Off,
+    // less cpu time per byte
+    Speed,
+    // fewer bytes on the wire
+    Ratio,
+}
+
+impl FromStr for Preference {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "off" => Self::Off,
+            "speed" => Self::Speed,
+            "ratio" => Self::Ratio,
+            _ => bail!("unknown compression preference {s}, expected off, speed or ratio"),
+        })
+    }
+}
+
+impl fmt::Display for Preference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Off => "off",
+            Self::Speed => "speed",
+            Self::Ratio => "ratio",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Codec {
+    compression: Compression,
+    level: i32,
+}
+
+impl Codec {
+    pub const NONE: Self = Self {
+        compression: Compression::None,
+        level: 0,
+    };
+
+    // the codec best matching preference among the features of both peers
+    pub fn negotiate(features: &[String], preference: Preference) -> Self {
+        let has = |codec: &str| features.iter().any(|f| f == codec);
+        let candidates = match preference {
+            Preference::Off => vec![],
+            Preference::Speed => vec![(Compression::Lz4, 0), (Compression::Zstd, 1)],
+            Preference::Ratio => vec![(Compression::Zstd, 6), (Compression::Lz4, 0)],
+        };
+
+        candidates
+            .into_iter()
+            .find(|(compression, _)| match compression {
+                Compression::None => false,
+                Compression::Zstd => has("zstd"),
+                Compression::Lz4 => has("lz4"),
+            })
+            .map(|(compression, level)| Self { compression, level })
+            .unwrap_or(Self::NONE)
+    }
+
+    pub fn compression(&self) -> Compression {
+        self.compression
+    }
+
+    pub fn payload(&self, data: Vec<u8>) -> Result<Payload> {
+        let compressed: Option<Vec<u8>> = match self.compression {
+            Compression::None => None,
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => Some(zstd::bulk::compress(&data, self.level)?),
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => Some(lz4_flex::compress_prepend_size(&data)),
+            #[allow(unreachable_patterns)]
+            c => bail!("{c:?} compression is not supported by this build"),
+        };
+
+        Ok(match compressed {
+            Some(compressed) if compressed.len() < data.len() => Payload {
+                compression: self.compression,
+                data: compressed,
+            },
+            _ => Payload {
+                compression: Compression::None,
+                data,
+            },
+        })
+    }
+}
+
+impl Payload {
+    pub fn raw(data: Vec<u8>) -> Self {
+        Self {
+            compression: Compression::None,
+            data,
+        }
+    }
+
+    // fails rather than produce more than limit bytes, the peer sends pieces
+    // of a known size and anything larger is not to be trusted
+    pub fn decompress(&self, limit: usize) -> Result<Cow<'_, [u8]>> {
+        Ok(match self.compression {
+            Compression::None if self.data.len() > limit => {
+                bail!("payload of {} bytes exceeds {limit}", self.data.len())
+            }
+            Compression::None => Cow::Borrowed(&self.data),
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => Cow::Owned(zstd::bulk::decompress(&self.data, limit)?),
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => {
+                let Some(size) = self.data.first_chunk::<4>() else {
+                    bail!("lz4 payload without a size");
+                };
+                let size = u32::from_le_bytes(*size) as usize;
+                if size > limit {
+                    bail!("lz4 payload of {size} bytes exceeds {limit}");
+                }
+                Cow::Owned(lz4_flex::decompress_size_prepended(&self.data)?)
+            }
+            #[allow(unreachable_patterns)]
+            c => bail!("{c:?} compression is not supported by this build"),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_payload_roundtrip() {
+        let features = CODECS.iter().map(|c| c.to_string()).collect::<Vec<_>>();
+        let data = (0..64 * 1024u32)
+            .map(|i| (i / 512) as u8)
+            .collect::<Vec<_>>();
+        for preference in [Preference::Off, Preference::Speed, Preference::Ratio] {
+            let codec = Codec::negotiate(&features, preference);
+            let payload = codec.payload(data.clone()).unwrap();
+            assert_eq!(payload.compression, codec.compression());
+            assert_eq!(payload.decompress(data.len()).unwrap(), data.as_slice());
+        }
+    }
+
+    #[test]
+    fn test_payload_limit() {
+        let features = CODECS.iter().map(|c| c.to_string()).collect::<Vec<_>>();
+        let data = vec![0u8; 64 * 1024];
+        for preference in [Preference::Off, Preference::Speed, Preference::Ratio] {
+            let payload = Codec::negotiate(&features, preference)
+                .payload(data.clone())
+                .unwrap();
+            assert!(payload.decompress(data.len() - 1).is_err(), "{preference}");
+        }
+    }
+}
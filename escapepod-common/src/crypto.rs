@@ -0,0 +1,131 @@
+// authentication and encryption of the connection between origin and
+// destination. the origin makes up a key and hands it to the launch pod
+// command, each connection then derives its own keys from it and the nonces
+// of both sides, so only a peer holding the key can read or write anything
+use std::{fmt, str::FromStr};
+
+use anyhow::{anyhow, bail, Context, Result};
+use chacha20poly1305::{aead::AeadInPlace, ChaCha20Poly1305, KeyInit, Nonce};
+use hkdf::Hkdf;
+use nix::errno::Errno;
+use sha2::Sha256;
+
+// where the destination finds the key
+pub const KEY_ENV: &str = "ESCAPEE_KEY";
+
+const KEY_LEN: usize = 32;
+pub(crate) const NONCE_LEN: usize = 32;
+// what sealing adds to a message
+pub(crate) const TAG_LEN: usize = 16;
+
+#[derive(Clone)]
+pub struct Key([u8; KEY_LEN]);
+
+impl Key {
+    pub fn generate() -> Result<Self> {
+        Ok(Self(random()?))
+    }
+
+    pub fn from_env() -> Result<Self> {
+        let Ok(key) = std::env::var(KEY_ENV) else {
+            bail!("{KEY_ENV} is not set, the origin passes it to the launch pod command");
+        };
+        key.parse().with_context(|| format!("invalid {KEY_ENV}"))
+    }
+
+    pub fn to_hex(&self) -> String {
+        self.0.iter().map(|b| format!("{b:02x}")).collect()
+    }
+}
+
+impl FromStr for Key {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if s.len() != KEY_LEN * 2 || !s.is_ascii() {
+            bail!("expected {} hex digits", KEY_LEN * 2);
+        }
+        let mut key = [0u8; KEY_LEN];
+        for (i, b) in key.iter_mut().enumerate() {
+            *b = u8::from_str_radix(&s[i * 2..i * 2 + 2], 16)?;
+        }
+
+        Ok(Self(key))
+    }
+}
+
+// never printed
+impl fmt::Debug for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Key(..)")
+    }
+}
+
+pub(crate) fn random<const N: usize>() -> Result<[u8; N]> {
+    let mut buf = [0u8; N];
+    let mut len = 0;
+    while len < N {
+        let res = unsafe { libc::getrandom(buf[len..].as_mut_ptr().cast(), N - len, 0) };
+        match Errno::result(res) {
+            Ok(n) => len += n as usize,
+            Err(Errno::EINTR) => {}
+            Err(e) => return Err(e).context("failed to get random bytes"),
+        }
+    }
+
+    Ok(buf)
+}
+
+// one direction of a connection, messages are numbered so none can be
+// replayed, dropped or reordered without the peer noticing
+pub(crate) struct Cipher {
+    cipher: ChaCha20Poly1305,
+    counter: u64,
+}
+
+impl Cipher {
+    // the keys of both directions of a connection, the origin's first
+    pub(crate) fn pair(
+        key: &Key,
+        origin_nonce: &[u8; NONCE_LEN],
+        destination_nonce: &[u8; NONCE_LEN],
+    ) -> Result<(Self, Self)> {
+        let salt = [origin_nonce.as_slice(), destination_nonce].concat();
+        let hkdf = Hkdf::<Sha256>::new(Some(&salt), &key.0);
+        let cipher = |info: &[u8]| -> Result<Self> {
+            let mut key = [0u8; KEY_LEN];
+            hkdf.expand(info, &mut key)
+                .map_err(|_| anyhow!("failed to derive key"))?;
+            Ok(Self {
+                cipher: ChaCha20Poly1305::new(&key.into()),
+                counter: 0,
+            })
+        };
+
+        Ok((
+            cipher(b"escapepod origin")?,
+            cipher(b"escapepod destination")?,
+        ))
+    }
+
+    pub(crate) fn seal(&mut self, buf: &mut Vec<u8>) -> Result<()> {
+        let nonce = self.next_nonce();
+        self.cipher
+            .encrypt_in_place(&nonce, b"", buf)
+            .map_err(|_| anyhow!("failed to encrypt message"))
+    }
+
+    pub(crate) fn open(&mut self, buf: &mut Vec<u8>) -> Result<()> {
+        let nonce = self.next_nonce();
+        self.cipher
+            .decrypt_in_place(&nonce, b"", buf)
+            .map_err(|_| anyhow!("message failed to authenticate"))
+    }
+
+    fn next_nonce(&mut self) -> Nonce {
+        let mut nonce = Nonce::default();
+        nonce[4..].copy_from_slice(&self.counter.to_le_bytes());
+        self.counter += 1;
+        nonce
+    }
+}
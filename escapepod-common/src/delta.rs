@@ -0,0 +1,94 @@
+// rsync style delta transfer of files. the destination sends the signatures
+// of the blocks of its copy of a file and the origin answers with the data
+// the destination lacks along with references to the blocks it already has
+
+use std::{
+    fs::File,
+    io::{self, Read, Seek, SeekFrom},
+};
+
+use sha2::{Digest, Sha256};
+
+use crate::proto::Hash;
+
+const MIN_BLOCK_SIZE: u64 = 4 * 1024;
+const MAX_BLOCK_SIZE: u64 = 1024 * 1024;
+
+// larger files get larger blocks, which keeps their signatures small
+pub fn block_size(len: u64) -> u64 {
+    ((len as f64).sqrt() as u64)
+        .next_multiple_of(1024)
+        .clamp(MIN_BLOCK_SIZE, MAX_BLOCK_SIZE)
+}
+
+pub fn hash(data: &[u8]) -> Hash {
+    Sha256::digest(data).into()
+}
+
+pub fn hash_reader(mut reader: impl Read) -> io::Result<Hash> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut reader, &mut hasher)?;
+
+    Ok(hasher.finalize().into())
+}
+
+// the hash of len bytes of file from offset, fewer if the file ends first
+pub fn hash_range(mut file: &File, offset: u64, len: u64) -> io::Result<Hash> {
+    file.seek(SeekFrom::Start(offset))?;
+    hash_reader(file.take(len))
+}
+
+// the weak checksum of a block, which can be rolled along a file one byte at
+// a time to find blocks at any offset
+#[derive(Debug, Clone, Copy)]
+pub struct Checksum {
+    a: u32,
+    b: u32,
+    len: u32,
+}
+
+impl Checksum {
+    pub fn new(block: &[u8]) -> Self {
+        let (mut a, mut b) = (0u32, 0u32);
+        for (i, byte) in block.iter().enumerate() {
+            a = a.wrapping_add(*byte as u32);
+            b = b.wrapping_add(((block.len() - i) as u32).wrapping_mul(*byte as u32));
+        }
+
+        Self {
+            a,
+            b,
+            len: block.len() as u32,
+        }
+    }
+
+    // moves the block one byte forward, dropping out and taking in
+    pub fn roll(&mut self, out: u8, r#in: u8) {
+        self.a = self.a.wrapping_sub(out as u32).wrapping_add(r#in as u32);
+        self.b = self
+            .b
+            .wrapping_sub(self.len.wrapping_mul(out as u32))
+            .wrapping_add(self.a);
+    }
+
+    pub fn value(&self) -> u32 {
+        (self.a & 0xffff) | (self.b << 16)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rolled_checksum_matches_block() {
+        let data = (0..4096u32)
+            .map(|i| (i * 7919 % 251) as u8)
+            .collect::<Vec<_>>();
+        let mut sum = Checksum::new(&data[..1024]);
+        for i in 0..data.len() - 1024 {
+            assert_eq!(sum.value(), Checksum::new(&data[i..i + 1024]).value());
+            sum.roll(data[i], data[i + 1024]);
+        }
+    }
+}
@@ -1,3 +1,7 @@
+pub mod compression;
+pub mod crypto;
+pub mod delta;
+pub mod ready;
 pub mod tracing;
 pub mod transport;
 pub use anyhow;
@@ -8,3 +12,4 @@ pub use nix;
 pub use procfs;
 pub use serde;
 pub use serde_json;
+pub use sha2;
@@ -1,4 +1,4 @@
-use std::{net::SocketAddr, path::PathBuf};
+use std::{collections::HashMap, net::SocketAddr, path::PathBuf, time::Duration};
 
 use bincode::{Decode, Encode};
 use libc::{c_int, gid_t, mode_t, pid_t, uid_t};
@@ -8,49 +8,264 @@ use serde::{Deserialize, Serialize};
 pub enum EscapeeMessage {
     ProcessTrees(Vec<Process>),
     Buffer(Buffer),
+    // replaces pages of a buffer sent earlier
+    BufferUpdate(Buffer),
+    // pipes are sent ahead of the process trees that use them
+    Pipe(Pipe),
+    // and memfds
+    MemFd(MemFd),
+    // likewise the data queued on unix sockets
+    SocketQueue(SocketQueue),
+    // and the state of established tcp connections
+    TcpConnection(TcpConnection),
+    // the files under the synced paths, the destination answers with a
+    // FileRequest
+    FileManifest(Vec<File>),
+    // files deleted while open in the trees, their data follows in pieces
     File(File),
     FileData(FileData),
+    // blocks of a requested file which the destination already has
+    FileBlocks(FileBlocks),
     Done,
 }
 
+// bumped whenever a message changes, peers must speak the same version
+pub const PROTOCOL_VERSION: u32 = 10;
+
+// exchanged by both sides before anything else, the version has to stay the
+// first field so any peer can read it
+#[derive(Debug, Clone, PartialEq, Encode, Decode, Serialize, Deserialize)]
+pub struct Hello {
+    pub version: u32,
+    pub arch: String,
+    pub page_size: u64,
+    pub kernel: String,
+    pub features: Vec<String>,
+}
+
 pub type BufferId = u32;
 pub type FileId = u32;
+// sha-256
+pub type Hash = [u8; 32];
+
+// sent by the destination to the origin
+#[derive(Debug, Clone, PartialEq, Encode, Decode, Serialize, Deserialize)]
+pub enum DestinationMessage {
+    // the files of the manifest which are missing or differ here
+    FileRequest(Vec<FileRequest>),
+    // the mapped files which are missing or differ here, by the buffer each
+    // mapping is to be sent as instead
+    MappedFileRequest(Vec<BufferId>),
+}
 
+// a piece of the contents of a buffer. buffers are sent in pieces of bounded
+// size, at least one even if empty, and whatever no piece covers is left
+// zero-filled
 #[derive(Debug, Clone, PartialEq, Encode, Decode, Serialize, Deserialize)]
 pub struct Buffer {
     pub buffer: BufferId,
-    pub buf: Vec<u8>,
+    // relative to the start of the buffer
+    pub offset: u64,
+    pub data: Payload,
 }
 
 impl Buffer {
-    pub fn new(buffer: BufferId, buf: Vec<u8>) -> Self {
-        Self { buffer, buf }
+    pub fn new(buffer: BufferId, offset: u64, data: Payload) -> Self {
+        Self {
+            buffer,
+            offset,
+            data,
+        }
     }
 }
 
+// memory and file data is sent in pieces of at most these sizes so that
+// neither side ever holds a large mapping or file whole
+pub const MEMORY_CHUNK: u64 = 1024 * 1024;
+pub const FILE_CHUNK: usize = 4 * 1024 * 1024;
+
+// the data of a buffer or file, compressed with one of the codecs both peers
+// support unless that did not make it any smaller
+#[derive(Debug, Clone, PartialEq, Encode, Decode, Serialize, Deserialize)]
+pub struct Payload {
+    pub compression: Compression,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Encode, Decode, Serialize, Deserialize)]
+pub enum Compression {
+    None,
+    Zstd,
+    Lz4,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Encode, Decode, Serialize, Deserialize)]
+pub struct PageRun {
+    // relative to the start of the buffer
+    pub offset: u64,
+    pub len: u64,
+}
+
+impl PageRun {
+    pub fn new(offset: u64, len: u64) -> Self {
+        Self { offset, len }
+    }
+}
+
+// a pipe with both ends open in the process trees
+#[derive(Debug, Clone, PartialEq, Encode, Decode, Serialize, Deserialize)]
+pub struct Pipe {
+    pub pipe_id: u64,
+    pub size: c_int,
+    // data buffered in the pipe at freeze time
+    pub buf: Vec<u8>,
+}
+
+// a memfd open or mapped in the process trees, its data follows in pieces
+// like that of a deleted file
+#[derive(Debug, Clone, PartialEq, Encode, Decode, Serialize, Deserialize)]
+pub struct MemFd {
+    pub inode: u64,
+    pub name: String,
+    pub seals: c_int,
+    pub file: FileId,
+}
+
+// data queued for a unix socket of the process trees, one packet per
+// datagram or a single one holding the bytes of a stream
+#[derive(Debug, Clone, PartialEq, Encode, Decode, Serialize, Deserialize)]
+pub struct SocketQueue {
+    pub inode: u64,
+    pub packets: Vec<Vec<u8>>,
+}
+
+// what TCP_REPAIR needs to recreate an established connection
+#[derive(Debug, Clone, PartialEq, Encode, Decode, Serialize, Deserialize)]
+pub struct TcpConnection {
+    pub inode: u64,
+    // the sequence numbers following the last byte of each queue
+    pub send_seq: u32,
+    pub recv_seq: u32,
+    // data not yet acknowledged by the peer, the last unsent bytes of which
+    // were never sent at all
+    pub send_queue: Vec<u8>,
+    pub unsent: u32,
+    // data received but not yet read
+    pub recv_queue: Vec<u8>,
+    pub mss: u32,
+    // TCPI_OPT_* negotiated with the peer
+    pub options: u8,
+    pub snd_wscale: u8,
+    pub rcv_wscale: u8,
+    pub timestamp: u32,
+    pub window: TcpWindow,
+}
+
+// laid out as struct tcp_repair_window
+#[derive(Debug, Clone, Copy, Default, PartialEq, Encode, Decode, Serialize, Deserialize)]
+#[repr(C)]
+pub struct TcpWindow {
+    pub snd_wl1: u32,
+    pub snd_wnd: u32,
+    pub max_window: u32,
+    pub rcv_wnd: u32,
+    pub rcv_wup: u32,
+}
+
 #[derive(Debug, Clone, PartialEq, Encode, Decode, Serialize, Deserialize)]
 pub struct File {
     pub id: FileId,
     pub uid: uid_t,
     pub gid: gid_t,
     pub mode: mode_t,
+    pub mtime: i64,
+    pub mtime_nsec: i64,
+    // where the file is, or was before it was deleted
     pub path: PathBuf,
+    pub r#type: FileType,
 }
 
+#[derive(Debug, Clone, PartialEq, Encode, Decode, Serialize, Deserialize)]
+pub enum FileType {
+    Regular { size: u64, hash: Hash },
+    Directory,
+    Symlink(PathBuf),
+    // deleted while open in the trees, recreated without a name
+    Deleted,
+}
+
+// appended to the file
 #[derive(Debug, Clone, PartialEq, Encode, Decode, Serialize, Deserialize)]
 pub struct FileData {
     pub id: FileId,
-    pub data: Vec<u8>,
+    pub data: Payload,
+}
+
+// count blocks of the copy the destination has, starting at index, appended
+// to the file
+#[derive(Debug, Clone, PartialEq, Encode, Decode, Serialize, Deserialize)]
+pub struct FileBlocks {
+    pub id: FileId,
+    pub index: u64,
+    pub count: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Encode, Decode, Serialize, Deserialize)]
+pub struct FileRequest {
+    pub id: FileId,
+    pub block_size: u64,
+    // of the copy the destination has, empty if it has none
+    pub blocks: Vec<BlockSignature>,
+}
+
+#[derive(Debug, Clone, PartialEq, Encode, Decode, Serialize, Deserialize)]
+pub struct BlockSignature {
+    // see delta::Checksum
+    pub weak: u32,
+    pub strong: Hash,
+}
+
+// what the destination hands every restore stub, it is read from a memfd as
+// the process trees may not fit into the environment
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StubState {
+    pub procs: Vec<Process>,
+    // where to reopen each pipe, memfd and file
+    pub pipes: HashMap<u64, PathBuf>,
+    pub memfds: HashMap<u64, PathBuf>,
+    pub files: HashMap<FileId, PathBuf>,
+}
+
+// the bounds the kernel keeps of the code, data, heap, stack, arguments and
+// environment of a process, brk grows the heap from brk
+#[derive(Debug, Clone, PartialEq, Encode, Decode, Serialize, Deserialize)]
+pub struct MemoryLayout {
+    pub start_code: u64,
+    pub end_code: u64,
+    pub start_data: u64,
+    pub end_data: u64,
+    pub start_brk: u64,
+    pub brk: u64,
+    pub start_stack: u64,
+    pub arg_start: u64,
+    pub arg_end: u64,
+    pub env_start: u64,
+    pub env_end: u64,
 }
 
 #[derive(Debug, Clone, PartialEq, Encode, Decode, Serialize, Deserialize)]
 pub struct Process {
     pub pid: pid_t,
     pub mmaps: Vec<MemoryMapping>,
+    pub layout: MemoryLayout,
     pub fd_table: Vec<Fd>,
+    // dispositions of every signal but SIGKILL and SIGSTOP
+    pub sigactions: Vec<SigAction>,
+    // the first thread is the main thread
     pub threads: Vec<Thread>,
 }
 impl Process {
+    #[allow(clippy::needless_return)]
     pub fn self_and_descendents(&self) -> Vec<&Process> {
         let mut procs = vec![self];
 
@@ -70,9 +285,25 @@ pub struct Thread {
     pub uid: uid_t,
     pub gid: gid_t,
     pub reg: Vec<u8>, // libc::user_regs_struct
+    pub tls: u64,     // fs_base / tpidr_el0
+    pub clear_child_tid: u64,
+    pub sigmask: u64,
+    // head of the list of robust futexes the thread holds, and its size
+    pub robust_list: u64,
+    pub robust_list_len: u64,
     pub children: Vec<Process>,
 }
 
+// struct sigaction as the kernel takes it in rt_sigaction
+#[derive(Debug, Clone, PartialEq, Encode, Decode, Serialize, Deserialize)]
+pub struct SigAction {
+    pub signal: i32,
+    pub handler: u64,
+    pub flags: u64,
+    pub restorer: u64,
+    pub mask: u64,
+}
+
 #[derive(Debug, Clone, PartialEq, Encode, Decode, Serialize, Deserialize)]
 pub struct MemoryMapping {
     pub address: u64,
@@ -91,19 +322,48 @@ impl MemoryMapping {
 pub enum MemoryMappingData {
     Buffer(BufferId),
     File(MappedFile),
+    // a shared mapping of a memfd, private ones are sent as buffers
+    MemFd(MappedMemFd),
     KernelVvar,
+    // only sent to check it matches the vdso of the destination kernel
+    KernelVdso(BufferId),
 }
 
 #[derive(Debug, Clone, PartialEq, Encode, Decode, Serialize, Deserialize)]
 pub struct MappedFile {
-    pub fd: c_int,
+    pub path: PathBuf,
     pub offset: u64,
+    // the file may be a copy at the destination so it is identified by its
+    // size and the hash of the mapped range
+    pub size: u64,
+    pub hash: Hash,
+    // pages which were privately modified after being mapped
+    pub modified: Vec<ModifiedPages>,
+    // the whole mapping is sent as this buffer instead if the file differs
+    // at the destination
+    pub buffer: BufferId,
+}
+
+#[derive(Debug, Clone, PartialEq, Encode, Decode, Serialize, Deserialize)]
+pub struct MappedMemFd {
+    pub inode: u64,
+    pub offset: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Encode, Decode, Serialize, Deserialize)]
+pub struct ModifiedPages {
+    // relative to the start of the mapping
+    pub offset: u64,
+    pub len: u64,
+    pub buffer: BufferId,
 }
 
 #[derive(Debug, Clone, PartialEq, Encode, Decode, Serialize, Deserialize)]
 pub struct Fd {
     pub fd: i32,
     pub mode: u32,
+    // open flags, including O_CLOEXEC
+    pub flags: c_int,
     pub r#type: FdType,
 }
 
@@ -111,8 +371,14 @@ pub struct Fd {
 pub enum FdType {
     File(FdFile),
     Pipe(FdPipe),
+    MemFd(FdMemFd),
+    DeletedFile(FdDeletedFile),
     SocketUnix(FdSocketUnix),
     SocketIp(FdSocketIp),
+    EventFd(FdEventFd),
+    TimerFd(FdTimerFd),
+    SignalFd(FdSignalFd),
+    Epoll(FdEpoll),
 }
 
 #[derive(Debug, Clone, PartialEq, Encode, Decode, Serialize, Deserialize)]
@@ -121,16 +387,98 @@ pub struct FdFile {
     pub position: u64,
 }
 
+// a file which was deleted while open, see File
+#[derive(Debug, Clone, PartialEq, Encode, Decode, Serialize, Deserialize)]
+pub struct FdDeletedFile {
+    pub file: FileId,
+    pub position: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Encode, Decode, Serialize, Deserialize)]
+pub struct FdMemFd {
+    pub inode: u64,
+    pub position: u64,
+}
+
 #[derive(Debug, Clone, PartialEq, Encode, Decode, Serialize, Deserialize)]
-pub enum FdSocketUnix {
-    Bind(PathBuf),
+pub struct FdSocketUnix {
+    pub inode: u64,
+    // SOCK_STREAM, SOCK_DGRAM or SOCK_SEQPACKET
+    pub r#type: c_int,
+    // abstract names start with a nul byte
+    pub name: Option<PathBuf>,
+    pub state: SocketUnixState,
+}
+
+#[derive(Debug, Clone, PartialEq, Encode, Decode, Serialize, Deserialize)]
+pub enum SocketUnixState {
+    Unconnected,
+    Listen { backlog: u32 },
+    // connected to the socket with this inode, which is in the trees and
+    // connected back to us
+    Pair(u64),
+    // connected to a socket outside of the trees, reconnected by its name
     Connect(PathBuf),
 }
 
 #[derive(Debug, Clone, PartialEq, Encode, Decode, Serialize, Deserialize)]
-pub enum FdSocketIp {
-    Bind(SocketAddr),
-    Connect(SocketAddr),
+pub struct FdSocketIp {
+    pub inode: u64,
+    pub state: SocketIpState,
+}
+
+#[derive(Debug, Clone, PartialEq, Encode, Decode, Serialize, Deserialize)]
+pub enum SocketIpState {
+    Bind(SocketIpBind),
+    // an established tcp connection, see TcpConnection
+    Connect { local: SocketAddr, peer: SocketAddr },
+}
+
+// a tcp or udp socket which is not connected, bound unless its port is zero
+#[derive(Debug, Clone, PartialEq, Encode, Decode, Serialize, Deserialize)]
+pub struct SocketIpBind {
+    pub addr: SocketAddr,
+    // SOCK_STREAM or SOCK_DGRAM
+    pub r#type: c_int,
+    // set for listening tcp sockets
+    pub backlog: Option<u32>,
+    pub reuse_addr: bool,
+    pub reuse_port: bool,
+    pub v6_only: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Encode, Decode, Serialize, Deserialize)]
+pub struct FdEventFd {
+    pub count: u64,
+    pub semaphore: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Encode, Decode, Serialize, Deserialize)]
+pub struct FdTimerFd {
+    pub clock: c_int,
+    // expirations which have not been read yet
+    pub ticks: u64,
+    // time left until the next expiration, zero if disarmed
+    pub value: Duration,
+    pub interval: Duration,
+}
+
+#[derive(Debug, Clone, PartialEq, Encode, Decode, Serialize, Deserialize)]
+pub struct FdSignalFd {
+    pub mask: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Encode, Decode, Serialize, Deserialize)]
+pub struct FdEpoll {
+    pub targets: Vec<EpollTarget>,
+}
+
+// a fd of the same process registered with an epoll instance
+#[derive(Debug, Clone, PartialEq, Encode, Decode, Serialize, Deserialize)]
+pub struct EpollTarget {
+    pub fd: i32,
+    pub events: u32,
+    pub data: u64,
 }
 
 #[derive(Debug, Clone, PartialEq, Encode, Decode, Serialize, Deserialize)]
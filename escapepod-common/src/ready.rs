@@ -0,0 +1,39 @@
+// what a restore stub writes to the destination over the ready fd once its
+// process is restored. the stub writes it from its restore routine so it is
+// plain repr(C) rather than one of the serialized messages. every stub shares
+// the ready fd so the tids are split over as many reports as it takes for each
+// to fit in a single atomic write
+use std::mem::size_of;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct ReadyReport {
+    pub pid: i32,
+    pub threads_len: u32,
+    // the tids of threads first..first + tids_len follow the report
+    pub first: u32,
+    pub tids_len: u32,
+    // length of the reason the stub cannot restore the process, which
+    // follows the report instead of any tids
+    pub error_len: u32,
+    // keeps the report free of padding so it can be written out as it is
+    pub reserved: u32,
+    // the region holding the restore routine and its state, the stub cannot
+    // unmap the code it runs so the destination does
+    pub region: u64,
+    pub region_len: u64,
+}
+
+impl ReadyReport {
+    pub const SIZE: usize = size_of::<Self>();
+    // most tids following a single report
+    pub const MAX_TIDS: usize = (libc::PIPE_BUF - Self::SIZE) / size_of::<i32>();
+
+    pub fn from_bytes(buf: &[u8; Self::SIZE]) -> Self {
+        unsafe { std::ptr::read_unaligned(buf.as_ptr() as *const Self) }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self as *const Self as *const u8, Self::SIZE) }
+    }
+}
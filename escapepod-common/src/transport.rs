@@ -1,96 +1,635 @@
 use std::{
-    io::{BufReader, Write},
-    net::{SocketAddr, TcpListener, TcpStream},
+    env::consts::ARCH,
+    fmt, fs,
+    io::{self, BufReader, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    os::{
+        fd::AsRawFd,
+        unix::net::{UnixListener, UnixStream},
+    },
+    path::PathBuf,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{self, Receiver, Sender},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
 };
 
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
+use nix::sys::{
+    socket::{getsockopt, sockopt::PeerCredentials},
+    utsname::uname,
+};
+use tracing::{info, warn};
+
+use crate::{
+    compression::{Codec, Preference, CODECS},
+    crypto::{self, Cipher, Key, NONCE_LEN, TAG_LEN},
+    proto::{Hello, Payload, PROTOCOL_VERSION},
+};
+
+// a peer has this long to prove it holds the key
+const AUTH_TIMEOUT: Duration = Duration::from_secs(10);
+// how often the server checks for new connections while others authenticate
+const ACCEPT_POLL: Duration = Duration::from_millis(20);
+// connections beyond this many authenticating at once are closed right away
+const MAX_PENDING: usize = 16;
+// larger frames are refused rather than allocated
+const MAX_FRAME: usize = 1 << 30;
+
+// where the origin listens, written tcp://host:port or unix:///path. a bare
+// host:port is taken as tcp
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Address {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl FromStr for Address {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if let Some(path) = s.strip_prefix("unix://") {
+            if !path.starts_with('/') {
+                bail!("unix socket path of {s} is not absolute");
+            }
+            return Ok(Self::Unix(path.into()));
+        }
+        let host = match s.split_once("://") {
+            Some(("tcp", host)) => host,
+            Some((scheme, _)) => bail!("unknown scheme {scheme} of {s}, expected tcp or unix"),
+            None => s,
+        };
+        let addr = host
+            .to_socket_addrs()
+            .with_context(|| format!("failed to resolve {host}"))?
+            .next()
+            .with_context(|| format!("{host} resolves to no address"))?;
+
+        Ok(Self::Tcp(addr))
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tcp(addr) => write!(f, "tcp://{addr}"),
+            Self::Unix(path) => write!(f, "unix://{}", path.display()),
+        }
+    }
+}
+
+enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener, PathBuf),
+}
+
+impl Listener {
+    // the listener does not block, None when nobody is waiting
+    fn accept(&self) -> Result<Option<(Stream, String)>> {
+        let res = match self {
+            Self::Tcp(listener) => listener
+                .accept()
+                .map(|(socket, addr)| (Stream::Tcp(socket), addr.to_string())),
+            Self::Unix(listener, path) => listener.accept().and_then(|(socket, _)| {
+                let pid = getsockopt(socket.as_raw_fd(), PeerCredentials)?.pid();
+                Ok((
+                    Stream::Unix(socket),
+                    format!("pid {pid} on {}", path.display()),
+                ))
+            }),
+        };
+        match res {
+            Ok((socket, peer)) => {
+                socket.set_nonblocking(false)?;
+                Ok(Some((socket, peer)))
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(None),
+            Err(e) => Err(e).context("failed to accept"),
+        }
+    }
+}
 
 pub struct Server {
-    listener: TcpListener,
+    listener: Listener,
+    key: Key,
+    // connections which proved they hold the key
+    authenticated: Receiver<(Channel, String)>,
+    authenticated_tx: Sender<(Channel, String)>,
+    // connections still authenticating
+    pending: Arc<AtomicUsize>,
 }
 
 impl Server {
-    pub fn listen(addr: SocketAddr) -> Result<Self> {
-        let listener = TcpListener::bind(addr).context("failed to bind")?;
+    pub fn listen(addr: &Address, key: Key) -> Result<Self> {
+        let listener = match addr {
+            Address::Tcp(addr) => Listener::Tcp(TcpListener::bind(addr).context("failed to bind")?),
+            Address::Unix(path) => Listener::Unix(
+                UnixListener::bind(path).with_context(|| format!("failed to bind {path:?}"))?,
+                path.clone(),
+            ),
+        };
+        match &listener {
+            Listener::Tcp(listener) => listener.set_nonblocking(true)?,
+            Listener::Unix(listener, _) => listener.set_nonblocking(true)?,
+        }
 
-        Ok(Self { listener })
+        let (authenticated_tx, authenticated) = mpsc::channel();
+
+        Ok(Self {
+            listener,
+            key,
+            authenticated,
+            authenticated_tx,
+            pending: Arc::new(AtomicUsize::new(0)),
+        })
     }
 
+    // anyone may connect, those who do not hold the key are turned away
+    // before they are sent anything. each connection authenticates on a
+    // thread of its own so a peer that never does cannot hold up the others
     pub fn accept(&mut self) -> Result<ServerConnection> {
-        let (socket, addr) = self.listener.accept().context("failed to accept")?;
+        loop {
+            if let Some((socket, peer)) = self.listener.accept()? {
+                if self.pending.fetch_add(1, Ordering::AcqRel) >= MAX_PENDING {
+                    self.pending.fetch_sub(1, Ordering::AcqRel);
+                    warn!("rejected connection from {peer}: too many connections authenticating");
+                    continue;
+                }
+                let (key, authenticated, pending) = (
+                    self.key.clone(),
+                    self.authenticated_tx.clone(),
+                    self.pending.clone(),
+                );
+                thread::spawn(move || {
+                    match Channel::authenticate(socket, &key, Side::Origin) {
+                        Ok(channel) => {
+                            let _ = authenticated.send((channel, peer));
+                        }
+                        Err(e) => warn!("rejected connection from {peer}: {e:#}"),
+                    }
+                    pending.fetch_sub(1, Ordering::AcqRel);
+                });
+            }
+            if let Ok((channel, peer)) = self.authenticated.recv_timeout(ACCEPT_POLL) {
+                return ServerConnection::new(channel, peer);
+            }
+        }
+    }
+
+    // the address we listen on, with the port the kernel picked if any
+    pub fn addr(&self) -> Address {
+        match &self.listener {
+            Listener::Tcp(listener) => Address::Tcp(listener.local_addr().unwrap()),
+            Listener::Unix(_, path) => Address::Unix(path.clone()),
+        }
+    }
 
-        Ok(ServerConnection::new(socket, addr))
+    pub fn key(&self) -> &Key {
+        &self.key
     }
+}
 
-    pub fn port(&self) -> u16 {
-        self.listener.local_addr().unwrap().port()
+impl Drop for Server {
+    fn drop(&mut self) {
+        if let Listener::Unix(_, path) = &self.listener {
+            let _ = fs::remove_file(path);
+        }
     }
 }
 
 pub struct ServerConnection {
-    socket: TcpStream,
-    peer_addr: SocketAddr,
-    bincode_conf: bincode::config::Configuration,
+    channel: Channel,
+    peer: String,
+    features: Vec<String>,
+    codec: Codec,
 }
 
 impl ServerConnection {
-    pub fn new(socket: TcpStream, peer_addr: SocketAddr) -> Self {
-        let bincode_conf = bincode::config::standard();
-        Self {
-            socket,
-            peer_addr,
-            bincode_conf,
-        }
+    fn new(mut channel: Channel, peer: String) -> Result<Self> {
+        let features =
+            handshake(&mut channel).with_context(|| format!("handshake with {peer} failed"))?;
+        channel.trust()?;
+
+        Ok(Self {
+            channel,
+            peer,
+            features,
+            codec: Codec::NONE,
+        })
+    }
+
+    pub fn peer(&self) -> &str {
+        &self.peer
+    }
+
+    pub fn features(&self) -> &[String] {
+        &self.features
+    }
+
+    // picks the codec payloads are compressed with from those the peer
+    // supports
+    pub fn compress(&mut self, preference: Preference) -> Codec {
+        self.codec = Codec::negotiate(&self.features, preference);
+        self.codec
     }
 
-    pub fn peer_addr(&self) -> SocketAddr {
-        self.peer_addr
+    pub fn payload(&self, data: Vec<u8>) -> Result<Payload> {
+        self.codec.payload(data)
     }
 
     pub fn send(&mut self, msg: impl bincode::Encode) -> Result<()> {
-        let buf = bincode::encode_to_vec(msg, self.bincode_conf)?;
-        Ok(self.socket.write_all(buf.as_slice())?)
+        self.channel.send(msg)
+    }
+
+    pub fn recv<R: bincode::Decode>(&mut self) -> Result<R> {
+        self.channel.recv()
     }
 }
 
 pub struct Client {
-    socket: BufReader<TcpStream>,
-    bincode_conf: bincode::config::Configuration,
+    channel: Channel,
+    features: Vec<String>,
 }
 
 impl Client {
-    pub fn new(socket: TcpStream) -> Self {
-        let bincode_conf = bincode::config::standard();
-        Self {
-            socket: BufReader::new(socket),
-            bincode_conf,
-        }
+    fn new(socket: Stream, key: &Key) -> Result<Self> {
+        let mut channel = Channel::authenticate(socket, key, Side::Destination)
+            .context("failed to authenticate with origin")?;
+        let features = handshake(&mut channel).context("handshake with origin failed")?;
+        channel.trust()?;
+
+        Ok(Self { channel, features })
+    }
+
+    pub fn connect(addr: &Address, key: &Key) -> Result<Self> {
+        let socket = match addr {
+            Address::Tcp(addr) => {
+                Stream::Tcp(TcpStream::connect(addr).context("failed to connect")?)
+            }
+            Address::Unix(path) => Stream::Unix(
+                UnixStream::connect(path)
+                    .with_context(|| format!("failed to connect to {path:?}"))?,
+            ),
+        };
+        Self::new(socket, key)
     }
 
-    pub fn connect(addr: SocketAddr) -> Result<Self> {
-        let socket = TcpStream::connect(addr).context("failed to connect")?;
-        Ok(Self::new(socket))
+    pub fn features(&self) -> &[String] {
+        &self.features
     }
 
     pub fn recv<R: bincode::Decode>(&mut self) -> Result<R> {
-        Ok(bincode::decode_from_reader(
-            &mut self.socket,
-            self.bincode_conf,
-        )?)
+        self.channel.recv()
+    }
+
+    pub fn send(&mut self, msg: impl bincode::Encode) -> Result<()> {
+        self.channel.send(msg)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Side {
+    Origin,
+    Destination,
+}
+
+enum Stream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl Stream {
+    fn try_clone(&self) -> io::Result<Self> {
+        Ok(match self {
+            Self::Tcp(s) => Self::Tcp(s.try_clone()?),
+            Self::Unix(s) => Self::Unix(s.try_clone()?),
+        })
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Self::Tcp(s) => s.set_read_timeout(timeout),
+            Self::Unix(s) => s.set_read_timeout(timeout),
+        }
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match self {
+            Self::Tcp(s) => s.set_nonblocking(nonblocking),
+            Self::Unix(s) => s.set_nonblocking(nonblocking),
+        }
     }
 }
 
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Self::Tcp(s) => s.read(buf),
+            Self::Unix(s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Tcp(s) => s.write(buf),
+            Self::Unix(s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::Tcp(s) => s.flush(),
+            Self::Unix(s) => s.flush(),
+        }
+    }
+}
+
+// a connection carrying encrypted messages, each framed by its length
+struct Channel {
+    writer: Stream,
+    reader: BufReader<Stream>,
+    seal: Cipher,
+    open: Cipher,
+    bincode_conf: bincode::config::Configuration,
+}
+
+impl Channel {
+    // both sides send a nonce and then the tag of an empty message under the
+    // keys derived from them, which only a peer holding the key can produce.
+    // nothing of a size the peer picks is read before that. the read timeout
+    // stays in place for the handshake
+    fn authenticate(socket: Stream, key: &Key, side: Side) -> Result<Self> {
+        let start = Instant::now();
+        socket.set_read_timeout(Some(AUTH_TIMEOUT))?;
+        let mut writer = socket.try_clone()?;
+        let mut reader = BufReader::new(socket);
+
+        let nonce = crypto::random::<NONCE_LEN>()?;
+        writer.write_all(&nonce)?;
+        let mut peer_nonce = [0u8; NONCE_LEN];
+        reader
+            .read_exact(&mut peer_nonce)
+            .context("failed to read nonce")?;
+
+        let (seal, open) = match side {
+            Side::Origin => Cipher::pair(key, &nonce, &peer_nonce)?,
+            Side::Destination => {
+                let (origin, destination) = Cipher::pair(key, &peer_nonce, &nonce)?;
+                (destination, origin)
+            }
+        };
+        let mut channel = Self {
+            writer,
+            reader,
+            seal,
+            open,
+            bincode_conf: bincode::config::standard(),
+        };
+        let mut proof = vec![];
+        channel.seal.seal(&mut proof)?;
+        channel.writer.write_all(&proof)?;
+        let mut proof = vec![0u8; TAG_LEN];
+        channel
+            .reader
+            .read_exact(&mut proof)
+            .context("failed to read proof")?;
+        channel
+            .open
+            .open(&mut proof)
+            .context("peer does not hold the key")?;
+        // the timeout is per read, a peer trickling bytes is cut off here
+        if start.elapsed() > AUTH_TIMEOUT {
+            bail!("peer took {:?} to authenticate", start.elapsed());
+        }
+
+        Ok(channel)
+    }
+
+    // once the peer is known to hold the key reads may take as long as they
+    // need
+    fn trust(&mut self) -> Result<()> {
+        Ok(self.writer.set_read_timeout(None)?)
+    }
+
+    fn send(&mut self, msg: impl bincode::Encode) -> Result<()> {
+        let buf = bincode::encode_to_vec(msg, self.bincode_conf)?;
+        self.send_frame(buf)
+    }
+
+    fn recv<R: bincode::Decode>(&mut self) -> Result<R> {
+        let buf = self.recv_frame()?;
+        let (msg, _) = bincode::decode_from_slice(&buf, self.bincode_conf)?;
+
+        Ok(msg)
+    }
+
+    fn send_frame(&mut self, mut buf: Vec<u8>) -> Result<()> {
+        self.seal.seal(&mut buf)?;
+        // written at once so the length is not sent in a segment of its own
+        let mut frame = Vec::with_capacity(size_of::<u32>() + buf.len());
+        frame.extend_from_slice(&(buf.len() as u32).to_le_bytes());
+        frame.extend_from_slice(&buf);
+
+        Ok(self.writer.write_all(&frame)?)
+    }
+
+    fn recv_frame(&mut self) -> Result<Vec<u8>> {
+        let mut len = [0u8; size_of::<u32>()];
+        self.reader.read_exact(&mut len)?;
+        let len = u32::from_le_bytes(len) as usize;
+        if len > MAX_FRAME {
+            bail!("frame of {len} bytes is too large");
+        }
+
+        let mut buf = vec![0u8; len];
+        self.reader.read_exact(&mut buf)?;
+        self.open.open(&mut buf)?;
+
+        Ok(buf)
+    }
+}
+
+fn hello() -> Result<Hello> {
+    Ok(Hello {
+        version: PROTOCOL_VERSION,
+        arch: ARCH.to_string(),
+        page_size: procfs::page_size(),
+        kernel: uname()?.release().to_string_lossy().into_owned(),
+        // optional parts of the protocol, only those both peers support are
+        // used
+        features: CODECS.iter().map(|f| f.to_string()).collect(),
+    })
+}
+
+// both sides send their hello first and then check the one of their peer, so
+// each refuses an incompatible peer on its own. returns the features both
+// support
+fn handshake(channel: &mut Channel) -> Result<Vec<String>> {
+    let local = hello()?;
+    channel.send(&local)?;
+    let peer: Hello = channel.recv().context("failed to read hello")?;
+    check(&local, &peer)?;
+    info!(
+        "peer runs protocol {} on {} linux {}",
+        peer.version, peer.arch, peer.kernel
+    );
+
+    Ok(local
+        .features
+        .into_iter()
+        .filter(|f| peer.features.contains(f))
+        .collect())
+}
+
+fn check(local: &Hello, peer: &Hello) -> Result<()> {
+    if peer.version != local.version {
+        bail!(
+            "peer speaks protocol version {} while we speak {}, both sides need the same escapepod version",
+            peer.version,
+            local.version
+        );
+    }
+    if peer.arch != local.arch {
+        bail!(
+            "peer runs on {} while we run on {}, processes cannot move across architectures",
+            peer.arch,
+            local.arch
+        );
+    }
+    if peer.page_size != local.page_size {
+        bail!(
+            "peer uses {} byte pages while we use {}",
+            peer.page_size,
+            local.page_size
+        );
+    }
+
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
+    use std::thread;
+
     use super::*;
 
     #[test]
     fn test_server_and_client() {
-        let mut server = Server::listen(([0u8; 4], 12345).into()).unwrap();
-        let mut client = Client::connect(([127, 0, 0, 1], 12345).into()).unwrap();
+        let key = Key::generate().unwrap();
+        let mut server =
+            Server::listen(&"tcp://0.0.0.0:12345".parse().unwrap(), key.clone()).unwrap();
+        let client = thread::spawn(move || {
+            Client::connect(&"localhost:12345".parse().unwrap(), &key).unwrap()
+        });
         let mut con = server.accept().unwrap();
+        let mut client = client.join().unwrap();
 
         con.send("test").unwrap();
         assert_eq!(client.recv::<String>().unwrap(), "test".to_string());
+        client.send("reply").unwrap();
+        assert_eq!(con.recv::<String>().unwrap(), "reply".to_string());
+    }
+
+    #[test]
+    fn test_parse_address() {
+        let addr: Address = "tcp://[::1]:0".parse().unwrap();
+        assert_eq!(addr, Address::Tcp("[::1]:0".parse().unwrap()));
+        assert_eq!(addr.to_string(), "tcp://[::1]:0");
+        assert_eq!(
+            "127.0.0.1:80".parse::<Address>().unwrap(),
+            Address::Tcp(([127, 0, 0, 1], 80).into())
+        );
+        assert_eq!(
+            "unix:///run/escapepod.sock".parse::<Address>().unwrap(),
+            Address::Unix("/run/escapepod.sock".into())
+        );
+        assert!("unix://run/escapepod.sock".parse::<Address>().is_err());
+        assert!("udp://127.0.0.1:80".parse::<Address>().is_err());
+    }
+
+    #[test]
+    fn test_client_without_key_is_rejected() {
+        let key = Key::generate().unwrap();
+        let path = std::env::temp_dir().join(format!("escapepod-test-{}.sock", std::process::id()));
+        let mut server = Server::listen(&Address::Unix(path), key.clone()).unwrap();
+        let addr = server.addr();
+        let client = thread::spawn(move || {
+            assert!(Client::connect(&addr, &Key::generate().unwrap()).is_err());
+            Client::connect(&addr, &key).unwrap()
+        });
+        server.accept().unwrap();
+        client.join().unwrap();
+    }
+
+    #[test]
+    fn test_silent_peer_does_not_stall_accept() {
+        let key = Key::generate().unwrap();
+        let mut server =
+            Server::listen(&"tcp://127.0.0.1:0".parse().unwrap(), key.clone()).unwrap();
+        let Address::Tcp(addr) = server.addr() else {
+            unreachable!();
+        };
+        let start = Instant::now();
+        let client = thread::spawn(move || {
+            let silent = TcpStream::connect(addr).unwrap();
+            thread::sleep(Duration::from_millis(100));
+            let client = Client::connect(&Address::Tcp(addr), &key).unwrap();
+            drop(silent);
+            client
+        });
+        server.accept().unwrap();
+        client.join().unwrap();
+        assert!(start.elapsed() < AUTH_TIMEOUT);
+    }
+
+    #[test]
+    fn test_pending_authentications_are_capped() {
+        let key = Key::generate().unwrap();
+        let mut server =
+            Server::listen(&"tcp://127.0.0.1:0".parse().unwrap(), key.clone()).unwrap();
+        let Address::Tcp(addr) = server.addr() else {
+            unreachable!();
+        };
+        let client = thread::spawn(move || {
+            let silent = (0..MAX_PENDING)
+                .map(|_| TcpStream::connect(addr).unwrap())
+                .collect::<Vec<_>>();
+            thread::sleep(ACCEPT_POLL * 10);
+            // closed before it is sent a nonce
+            let mut extra = TcpStream::connect(addr).unwrap();
+            extra.set_read_timeout(Some(AUTH_TIMEOUT / 2)).unwrap();
+            let mut buf = [0u8; NONCE_LEN];
+            assert_eq!(extra.read(&mut buf).unwrap(), 0);
+
+            drop(silent);
+            thread::sleep(ACCEPT_POLL * 10);
+            // a peer without the key is turned away after a fixed size read
+            let mut wrong = TcpStream::connect(addr).unwrap();
+            wrong.set_read_timeout(Some(AUTH_TIMEOUT / 2)).unwrap();
+            wrong.write_all(&[0u8; NONCE_LEN + TAG_LEN]).unwrap();
+            let mut buf = vec![];
+            wrong.read_to_end(&mut buf).unwrap();
+            assert_eq!(buf.len(), NONCE_LEN + TAG_LEN);
+            Client::connect(&Address::Tcp(addr), &key).unwrap()
+        });
+        server.accept().unwrap();
+        client.join().unwrap();
+    }
+
+    #[test]
+    fn test_incompatible_peer_is_refused() {
+        let local = hello().unwrap();
+        assert!(check(&local, &local).is_ok());
+        let peer = Hello {
+            page_size: local.page_size * 4,
+            ..local.clone()
+        };
+        assert!(check(&local, &peer).is_err());
     }
 }
@@ -1,14 +1,18 @@
 use std::ffi::c_void;
 
+use escapepod_common::ready::ReadyReport;
+
 pub mod restore;
 
 #[derive(Debug)]
+#[repr(C)]
 pub struct CurrentMmap {
     pub addr: usize,
     pub len: usize,
 }
 
 #[derive(Debug)]
+#[repr(C)]
 pub struct NewMmap {
     pub addr: usize,
     pub len: usize,
@@ -18,17 +22,116 @@ pub struct NewMmap {
     pub offset: usize,
 }
 
+#[derive(Debug)]
+#[repr(C)]
+pub struct KernelMmap {
+    // current address of our vdso or vvar
+    pub addr: usize,
+    pub len: usize,
+    // moved out of the way here first as it may overlap other kernel mmaps
+    pub scratch: usize,
+    // address in the restored process
+    pub target: usize,
+}
+
+// struct prctl_mm_map, set with prctl(PR_SET_MM, PR_SET_MM_MAP) which unlike
+// the single field variants needs no CAP_SYS_RESOURCE
+#[derive(Debug)]
+#[repr(C)]
+pub struct MmMap {
+    pub start_code: u64,
+    pub end_code: u64,
+    pub start_data: u64,
+    pub end_data: u64,
+    pub start_brk: u64,
+    pub brk: u64,
+    pub start_stack: u64,
+    pub arg_start: u64,
+    pub arg_end: u64,
+    pub env_start: u64,
+    pub env_end: u64,
+    pub auxv: *const u64,
+    pub auxv_size: u32,
+    // u32::MAX keeps the current exe
+    pub exe_fd: u32,
+}
+
+#[derive(Debug)]
+#[repr(C)]
+pub struct NewThread {
+    pub tid: i32,
+    pub clear_child_tid: usize,
+    pub sigmask: u64,
+    pub robust_list: usize,
+    pub robust_list_len: usize,
+    // tid - 1 in decimal, written to ns_last_pid when clone3 is unavailable
+    pub last_pid: [u8; 12],
+    pub last_pid_len: usize,
+    // processes forked by this thread
+    pub children_len: usize,
+    pub children: *const NewChild,
+}
+
+// a signal disposition, followed by struct sigaction as rt_sigaction takes it
+#[derive(Debug)]
+#[repr(C)]
+pub struct NewSigAction {
+    pub signal: usize,
+    pub handler: usize,
+    pub flags: u64,
+    pub restorer: usize,
+    pub mask: u64,
+}
+
+#[derive(Debug)]
+#[repr(C)]
+pub struct NewChild {
+    pub pid: i32,
+    // pid - 1 in decimal, written to ns_last_pid when clone3 is unavailable
+    pub last_pid: [u8; 12],
+    pub last_pid_len: usize,
+    // environment of the restore stub exec'd by the child
+    pub envp: *const *const u8,
+}
+
+// the layout of this struct is read by the restore routine so must stay repr(C)
+#[repr(C)]
 pub struct RestoreState {
     // new location to set stack pointer
     pub stack_pointer: *const c_void,
     // ptr to restore function codes
     pub restore_fn: *const c_void,
     // mmaps to unmap
-    pub current_mmaps: (usize, *const CurrentMmap),
+    pub current_mmaps_len: usize,
+    pub current_mmaps: *const CurrentMmap,
     // mmaps to create
-    pub new_mmaps: (usize, *const NewMmap),
+    pub new_mmaps_len: usize,
+    pub new_mmaps: *const NewMmap,
+    // kernel provided mmaps to move
+    pub kernel_mmaps_len: usize,
+    pub kernel_mmaps: *const KernelMmap,
+    // memory layout of the restored process
+    pub mm_map: MmMap,
+    // signal dispositions to set once nothing else can fail, these replace
+    // every disposition of our own
+    pub sigactions_len: usize,
+    pub sigactions: *const NewSigAction,
+    // threads to create, the first entry is the current thread
+    pub threads_len: usize,
+    pub threads: *const NewThread,
+    // tids of the restored threads, they directly follow the report
+    pub tids: *mut i32,
+    // written to fd along with the tids once restored
+    pub report: *const ReadyReport,
+    // number of created threads which are still setting themselves up
+    pub pending_threads: usize,
     // restore complete signal fd
     pub fd: i32,
     // current pid
     pub pid: i32,
+    // used to pick the tids of created threads, or -1 to use clone3
+    pub ns_last_pid_fd: i32,
+    // restore stub exec'd by forked children
+    pub exec_path: *const u8,
+    pub exec_argv: *const *const u8,
 }
@@ -1,67 +1,170 @@
 use std::{
     arch::asm,
+    collections::HashMap,
     env,
-    mem::{self, size_of},
-    ptr,
+    ffi::{CStr, CString},
+    fs::{self, OpenOptions},
+    io::{IoSliceMut, Write},
+    mem::size_of,
+    os::{
+        fd::{AsRawFd, IntoRawFd, RawFd},
+        unix::{
+            ffi::{OsStrExt, OsStringExt},
+            net::UnixStream,
+        },
+    },
+    time::Duration,
 };
 
 use escapepod_common::{
-    libc::memcpy,
+    libc::{self, memcpy},
     nix::{
-        self,
-        fcntl::OFlag,
+        self, cmsg_space,
+        errno::Errno,
+        fcntl::{fcntl, FcntlArg, FdFlag, OFlag},
         sys::{
             mman::{mmap, MapFlags, ProtFlags},
-            signal::Signal,
+            socket::{recvmsg, ControlMessageOwned, MsgFlags},
             stat::Mode,
         },
-        unistd::{getpid, sysconf, SysconfVar},
+        unistd::{close, getpid, lseek, sysconf, write, SysconfVar, Whence},
     },
-    procfs::{self},
-    proto::{FdType, MemoryMappingData, Process},
+    procfs::{
+        self,
+        process::{MMPermissions, MMapPath},
+    },
+    proto::{FdType, MemoryMappingData, Process, StubState},
+    ready::ReadyReport,
     serde_json,
-    tracing::trace,
+    tracing::{trace, warn},
+};
+use escapepod_restore::{
+    CurrentMmap, KernelMmap, MmMap, NewChild, NewMmap, NewSigAction, NewThread, RestoreState,
 };
-use escapepod_restore::{restore, CurrentMmap, NewMmap, RestoreState};
-use syscalls::Sysno;
-
-const RESTORE_SPACE: usize = 100 * 1024;
 
-// const RESTORE_FN_CODE: &[u8] = include_bytes!("../target/realease/escapepod-restore-inner.s");
+// stack space reserved for the restore routine
+const RESTORE_STACK: usize = 16 * 1024;
+// extra slots for current mmaps which may appear while preparing the restore
+const CURRENT_MMAPS_SLACK: usize = 32;
+// lowest address we will place the restore space at (default vm.mmap_min_addr)
+const MIN_RESTORE_ADDRESS: usize = 0x10000;
+// _IOW('T', 0, u64), not in libc
+const TFD_IOC_SET_TICKS: libc::c_ulong = 0x40085400;
 
 fn main() {
     escapepod_common::tracing::init();
 
-    let mut proc =
-        serde_json::from_str::<Process>(env::var("EP_PROCESS").unwrap().as_str()).unwrap();
+    let state = fs::read(env::var_os("EP_STATE").unwrap()).expect("failed to read state");
+    let StubState {
+        procs,
+        pipes,
+        memfds,
+        files,
+    } = serde_json::from_slice(&state).unwrap();
+    // the destination spawns us with the original pid
+    let proc = procs
+        .iter()
+        .flat_map(|p| p.self_and_descendents())
+        .find(|p| p.pid == getpid().as_raw())
+        .expect("process is not in the state")
+        .clone();
+    drop(procs);
 
     let mut ready_fd = env::var("EP_READY_FD").unwrap().parse::<i32>().unwrap();
 
-    // todo: restore pid, euid, egid, fds, threads, forks ...
-    // close existings file descriptors except ready_fd
-    let max_fd = nix::fcntl::open("/dev/null", OFlag::O_RDONLY, Mode::empty()).unwrap();
-    for i in 0..=max_fd {
-        if i != ready_fd {
-            // let _ = close(i);
+    // the restore routine can only abort once it has torn down our memory, so
+    // make sure the kernel can take the memory layout of the process first
+    match mm_map_size() {
+        Ok(size) if size as usize == size_of::<MmMap>() => {}
+        Ok(size) => report_failure(
+            ready_fd,
+            &proc,
+            &format!(
+                "the kernel expects a prctl_mm_map of {size} bytes rather than {}",
+                size_of::<MmMap>()
+            ),
+        ),
+        Err(e) => report_failure(
+            ready_fd,
+            &proc,
+            &format!("PR_SET_MM_MAP is unsupported, it needs CONFIG_CHECKPOINT_RESTORE: {e}"),
+        ),
+    }
+    // pipes which are not in the state lead outside of the restored processes
+
+    // todo: restore euid, egid ...
+    // close what we inherited from the destination or our restored parent
+    // unless the process had the same fd open, those we cannot restore are
+    // left as they are
+    let inherited = procfs::process::Process::myself()
+        .unwrap()
+        .fd()
+        .unwrap()
+        .filter_map(|f| f.ok().map(|f| f.fd))
+        .collect::<Vec<_>>();
+    for fd in inherited {
+        if fd != ready_fd && !proc.fd_table.iter().any(|f| f.fd == fd) {
+            // includes the fd used to list them which is already closed
+            let _ = close(fd);
         }
     }
 
+    let mut sockets = receive_sockets(&proc);
+
     // restore file descriptors
     for fd in proc.fd_table.iter() {
+        let flags = OFlag::from_bits_truncate(fd.flags);
+        // the kernel keeps no creation flags after the open but make sure we
+        // never truncate or create anything
+        let open_flags = flags & !(OFlag::O_CREAT | OFlag::O_EXCL | OFlag::O_TRUNC);
         let nfd = match &fd.r#type {
             FdType::File(p) => {
-                nix::fcntl::open(
-                    p.file.as_path(),
-                    // todo: oflag
-                    OFlag::O_RDONLY,
-                    Mode::from_bits(fd.mode).unwrap(),
-                )
-                .unwrap()
+                let nfd = nix::fcntl::open(p.file.as_path(), open_flags, Mode::empty())
+                    .unwrap_or_else(|e| panic!("failed to reopen {:?}: {e}", p.file));
+                seek(nfd, p.position).unwrap_or_else(|e| {
+                    panic!("failed to seek {:?} to {}: {e}", p.file, p.position)
+                });
+                nfd
+            }
+            FdType::Pipe(p) => {
+                let Some(path) = pipes.get(&p.pipe_id) else {
+                    continue;
+                };
+                // reopening the pipe of the destination through procfs gives us
+                // an end of our own with the same access mode as the original
+                nix::fcntl::open(path, open_flags, Mode::empty())
+                    .unwrap_or_else(|e| panic!("failed to open pipe {}: {e}", p.pipe_id))
+            }
+            FdType::DeletedFile(f) => {
+                let nfd = nix::fcntl::open(&files[&f.file], open_flags, Mode::empty())
+                    .unwrap_or_else(|e| panic!("failed to open deleted file {}: {e}", f.file));
+                seek(nfd, f.position).unwrap_or_else(|e| {
+                    panic!(
+                        "failed to seek deleted file {} to {}: {e}",
+                        f.file, f.position
+                    )
+                });
+                nfd
+            }
+            FdType::MemFd(m) => {
+                let nfd = nix::fcntl::open(&memfds[&m.inode], open_flags, Mode::empty())
+                    .unwrap_or_else(|e| panic!("failed to open memfd {}: {e}", m.inode));
+                seek(nfd, m.position).unwrap_or_else(|e| {
+                    panic!("failed to seek memfd {} to {}: {e}", m.inode, m.position)
+                });
+                nfd
+            }
+            FdType::SocketUnix(_) | FdType::SocketIp(_) => {
+                let nfd = sockets.remove(&fd.fd).unwrap();
+                // non-blocking mode belongs to the socket rather than the fd
+                fcntl(nfd, FcntlArg::F_SETFL(flags)).unwrap();
+                nfd
+            }
+            FdType::EventFd(_) | FdType::TimerFd(_) | FdType::SignalFd(_) | FdType::Epoll(_) => {
+                let nfd = anon_inode(&fd.r#type);
+                fcntl(nfd, FcntlArg::F_SETFL(flags)).unwrap();
+                nfd
             }
-            _ => continue
-            // FdType::Pipe(_) => todo!(),
-            // FdType::SocketUnix(_) => todo!(),
-            // FdType::SocketIp(_) => todo!(),
         };
 
         // ensure ready fd does not conflict
@@ -69,56 +172,248 @@ fn main() {
             ready_fd = nix::unistd::dup(ready_fd).unwrap();
         }
 
-        nix::unistd::dup2(nfd, fd.fd).unwrap();
+        if nfd != fd.fd {
+            nix::unistd::dup2(nfd, fd.fd).unwrap();
+            close(nfd).unwrap();
+        }
+        // dup2 always clears close-on-exec
+        let fd_flags = match flags.contains(OFlag::O_CLOEXEC) {
+            true => FdFlag::FD_CLOEXEC,
+            false => FdFlag::empty(),
+        };
+        fcntl(fd.fd, FcntlArg::F_SETFD(fd_flags)).unwrap();
     }
 
-    // get memory restore state
-    let current_mmaps = procfs::process::Process::new(getpid().as_raw())
-        .unwrap()
-        .maps()
-        .unwrap()
-        .into_iter()
-        .map(|i| CurrentMmap {
-            addr: i.address.0 as _,
-            len: (i.address.1 - i.address.0) as _,
-        })
-        .collect::<Vec<_>>();
-
-    trace!("current_mmaps: {:?}", current_mmaps);
+    // epoll sets are rebuilt once the fds they watch are in place
+    for fd in proc.fd_table.iter() {
+        let FdType::Epoll(epoll) = &fd.r#type else {
+            continue;
+        };
+        for target in epoll.targets.iter() {
+            let mut event = libc::epoll_event {
+                events: target.events,
+                u64: target.data,
+            };
+            let res = unsafe { libc::epoll_ctl(fd.fd, libc::EPOLL_CTL_ADD, target.fd, &mut event) };
+            match Errno::result(res) {
+                Ok(_) => {}
+                // a pipe leading outside of the restored processes
+                Err(Errno::EBADF) => warn!(
+                    "epoll {} watches {} which was not restored",
+                    fd.fd, target.fd
+                ),
+                Err(e) => panic!("failed to add {} to epoll {}: {e}", target.fd, fd.fd),
+            }
+        }
+    }
 
     let new_mmaps = proc
         .mmaps
         .iter()
-        .map(|m| {
+        .filter_map(|m| {
             let mut mmap = NewMmap {
                 addr: m.address as _,
                 len: m.len as _,
-                prot: m.perm,
-                // TODO
-                flags: 0,
-                fd: 0,
+                prot: m.perm & (libc::PROT_READ | libc::PROT_WRITE | libc::PROT_EXEC),
+                flags: libc::MAP_PRIVATE | libc::MAP_FIXED,
+                fd: -1,
                 offset: 0,
             };
-            if let MemoryMappingData::File(f) = &m.data {
-                mmap.fd = f.fd;
-                mmap.offset = f.offset as _;
+            match &m.data {
+                MemoryMappingData::Buffer(_) => mmap.flags |= libc::MAP_ANONYMOUS,
+                MemoryMappingData::File(f) => {
+                    let shared = m.perm & MMPermissions::SHARED.bits() as i32 != 0;
+                    let file = OpenOptions::new()
+                        .read(true)
+                        .write(shared && mmap.prot & libc::PROT_WRITE != 0)
+                        .open(&f.path)
+                        .unwrap_or_else(|e| panic!("failed to open mapped file {:?}: {e}", f.path));
+                    // the destination has checked its hash against the origin's
+                    if file.metadata().unwrap().len() != f.size {
+                        panic!("mapped file {:?} changed during the restore", f.path);
+                    }
+                    // closed by the restore routine once mapped
+                    mmap.fd = file.into_raw_fd();
+                    if shared {
+                        mmap.flags = libc::MAP_SHARED | libc::MAP_FIXED;
+                    }
+                    mmap.offset = f.offset as _;
+                }
+                MemoryMappingData::MemFd(m) => {
+                    let oflag = match mmap.prot & libc::PROT_WRITE {
+                        0 => OFlag::O_RDONLY,
+                        _ => OFlag::O_RDWR,
+                    };
+                    // closed by the restore routine once mapped
+                    mmap.fd = nix::fcntl::open(
+                        &memfds[&m.inode],
+                        oflag | OFlag::O_CLOEXEC,
+                        Mode::empty(),
+                    )
+                    .unwrap_or_else(|e| panic!("failed to open memfd {}: {e}", m.inode));
+                    mmap.flags = libc::MAP_SHARED | libc::MAP_FIXED;
+                    mmap.offset = m.offset as _;
+                }
+                // moved into place by the restore routine instead
+                MemoryMappingData::KernelVvar | MemoryMappingData::KernelVdso(_) => return None,
             }
-            mmap
+            Some(mmap)
         })
         .collect::<Vec<_>>();
 
     trace!("new_mmaps: {:?}", new_mmaps);
 
+    // the vdso finds vvar at a fixed offset so our kernel mmaps are moved as a
+    // block, which only works if the origin kernel laid them out the same way
+    let origin_kernel_mmaps = proc
+        .mmaps
+        .iter()
+        .filter(|m| {
+            matches!(
+                m.data,
+                MemoryMappingData::KernelVvar | MemoryMappingData::KernelVdso(_)
+            )
+        })
+        .collect::<Vec<_>>();
+    let own_kernel_mmaps = kernel_mmaps();
+    let layout_matches = origin_kernel_mmaps.len() == own_kernel_mmaps.len()
+        && origin_kernel_mmaps
+            .iter()
+            .zip(own_kernel_mmaps.iter())
+            .all(|(o, (path, m))| {
+                o.len as usize == m.len
+                    && matches!(o.data, MemoryMappingData::KernelVdso(_))
+                        == (*path == MMapPath::Vdso)
+                    && (o.address - origin_kernel_mmaps[0].address) as usize
+                        == m.addr - own_kernel_mmaps[0].1.addr
+            });
+    if !layout_matches {
+        panic!(
+            "vdso layout of this kernel differs from the origin: {:?} != {:?}",
+            origin_kernel_mmaps
+                .iter()
+                .map(|m| (m.address, m.len))
+                .collect::<Vec<_>>(),
+            own_kernel_mmaps
+                .iter()
+                .map(|(_, m)| (m.addr, m.len))
+                .collect::<Vec<_>>()
+        );
+    }
+    let kernel_mmaps_span = own_kernel_mmaps
+        .last()
+        .map(|(_, m)| m.addr + m.len - own_kernel_mmaps[0].1.addr)
+        .unwrap_or(0);
+
+    let mut new_threads = proc
+        .threads
+        .iter()
+        .map(|t| {
+            let last_pid = (t.tid - 1).to_string();
+            let mut thread = NewThread {
+                tid: t.tid,
+                clear_child_tid: t.clear_child_tid as _,
+                sigmask: t.sigmask,
+                robust_list: t.robust_list as _,
+                robust_list_len: t.robust_list_len as _,
+                last_pid: [0; 12],
+                last_pid_len: last_pid.len(),
+                children_len: t.children.len(),
+                children: std::ptr::null(),
+            };
+            thread.last_pid[..last_pid.len()].copy_from_slice(last_pid.as_bytes());
+            thread
+        })
+        .collect::<Vec<_>>();
+    assert!(proc.threads.first().map(|t| t.tid) == Some(proc.pid));
+
+    // clone3 can set the tids of the restored threads directly, before
+    // linux 5.5 we can only pick the next tid through ns_last_pid
+    let ns_last_pid_fd = if clone3_supported() {
+        -1
+    } else {
+        nix::fcntl::open(
+            "/proc/sys/kernel/ns_last_pid",
+            OFlag::O_WRONLY | OFlag::O_CLOEXEC,
+            Mode::empty(),
+        )
+        .unwrap()
+    };
+
+    trace!("new_threads: {:?}", new_threads);
+
+    let new_sigactions = proc
+        .sigactions
+        .iter()
+        .map(|a| NewSigAction {
+            signal: a.signal as _,
+            handler: a.handler as _,
+            flags: a.flags,
+            restorer: a.restorer as _,
+            mask: a.mask,
+        })
+        .collect::<Vec<_>>();
+
+    // children are forked by the thread which created them at the origin
+    // and then exec a restore stub of their own
+    let children = proc
+        .threads
+        .iter()
+        .flat_map(|t| t.children.iter())
+        .collect::<Vec<_>>();
+    let mut new_children = children
+        .iter()
+        .map(|c| {
+            let last_pid = (c.pid - 1).to_string();
+            let mut child = NewChild {
+                pid: c.pid,
+                last_pid: [0; 12],
+                last_pid_len: last_pid.len(),
+                envp: std::ptr::null(),
+            };
+            child.last_pid[..last_pid.len()].copy_from_slice(last_pid.as_bytes());
+            child
+        })
+        .collect::<Vec<_>>();
+    let exec_path = CString::new(env::current_exe().unwrap().into_os_string().into_vec()).unwrap();
+    let child_env = stub_env(ready_fd);
+    let exec_blob_len = exec_blob(0, &exec_path, &child_env).0.len();
+
+    trace!("new_children: {:?}", new_children);
+
+    let (restore_fn_start, restore_fn_end) = restore_fn_code();
+    let restore_fn_len = unsafe { restore_fn_end.offset_from(restore_fn_start) as usize };
+
     // allocate space for restore routine
-    let (start, len) = find_safe_address_space(&mut proc);
-    trace!("restore address space: ({}, {})", start, start + len);
+    let page_size = sysconf(SysconfVar::PAGE_SIZE).unwrap().unwrap() as usize;
+    trace!("page_size: {page_size}");
+    let current_mmaps_len = current_mmaps().len() + CURRENT_MMAPS_SLACK;
+    let len = size_of::<RestoreState>().next_multiple_of(16)
+        + (size_of::<CurrentMmap>() * current_mmaps_len).next_multiple_of(16)
+        + (size_of::<NewMmap>() * new_mmaps.len()).next_multiple_of(16)
+        + (size_of::<KernelMmap>() * own_kernel_mmaps.len()).next_multiple_of(16)
+        + (size_of::<NewSigAction>() * new_sigactions.len()).next_multiple_of(16)
+        + (size_of::<NewThread>() * new_threads.len()).next_multiple_of(16)
+        + (ReadyReport::SIZE + size_of::<i32>() * new_threads.len()).next_multiple_of(16)
+        + (size_of::<NewChild>() * new_children.len()).next_multiple_of(16)
+        + exec_blob_len.next_multiple_of(16)
+        + restore_fn_len.next_multiple_of(16)
+        + RESTORE_STACK;
+    let len = len.next_multiple_of(page_size);
+    // the kernel mmaps are moved through the space after the stack
+    let start = find_safe_address_space(&proc, len + kernel_mmaps_span, page_size);
+    trace!(
+        "restore address space: ({}, {})",
+        start,
+        start + len + kernel_mmaps_span
+    );
 
     let restore_addr = unsafe {
         mmap(
             Some(start.try_into().unwrap()),
-            len.try_into().unwrap(),
+            (len + kernel_mmaps_span).try_into().unwrap(),
             ProtFlags::PROT_READ | ProtFlags::PROT_WRITE | ProtFlags::PROT_EXEC,
-            MapFlags::MAP_PRIVATE | MapFlags::MAP_FIXED | MapFlags::MAP_ANONYMOUS,
+            MapFlags::MAP_PRIVATE | MapFlags::MAP_FIXED_NOREPLACE | MapFlags::MAP_ANONYMOUS,
             0,
             0,
         )
@@ -127,21 +422,99 @@ fn main() {
 
     trace!("restore_addr: {:?}", restore_addr);
 
+    // get memory restore state, this must be the last thing we read
+    // so any mappings created above are also torn down
+    let current_mmaps = current_mmaps()
+        .into_iter()
+        .filter(|m| m.addr != start)
+        .collect::<Vec<_>>();
+    assert!(current_mmaps.len() <= current_mmaps_len);
+
+    trace!("current_mmaps: {:?}", current_mmaps);
+
     // first copy the required state to the new mmapped region
     unsafe {
-        let ptr = restore_addr;
-        let current_mmaps_addr = restore_addr.add(size_of::<RestoreState>());
-        let new_mmaps_addr = current_mmaps_addr.add(size_of::<CurrentMmap>() * current_mmaps.len());
-        let restore_fn_addr = new_mmaps_addr.add(size_of::<NewMmap>() * new_mmaps.len());
-        let restore_fn_addr = restore_fn_addr.add(restore_fn_addr.align_offset(8));
-        let stack_pointer_addr = restore_fn_addr.add(size_of::<NewMmap>() * new_mmaps.len());
-        let stack_pointer_addr = stack_pointer_addr.add(stack_pointer_addr.align_offset(8));
+        let current_mmaps_addr = restore_addr.add(size_of::<RestoreState>().next_multiple_of(16));
+        let new_mmaps_addr = current_mmaps_addr
+            .add((size_of::<CurrentMmap>() * current_mmaps_len).next_multiple_of(16));
+        let kernel_mmaps_addr =
+            new_mmaps_addr.add((size_of::<NewMmap>() * new_mmaps.len()).next_multiple_of(16));
+        let sigactions_addr = kernel_mmaps_addr
+            .add((size_of::<KernelMmap>() * own_kernel_mmaps.len()).next_multiple_of(16));
+        let new_threads_addr = sigactions_addr
+            .add((size_of::<NewSigAction>() * new_sigactions.len()).next_multiple_of(16));
+        let report_addr =
+            new_threads_addr.add((size_of::<NewThread>() * new_threads.len()).next_multiple_of(16));
+        let tids_addr = report_addr.add(ReadyReport::SIZE);
+        let children_addr = report_addr
+            .add((ReadyReport::SIZE + size_of::<i32>() * new_threads.len()).next_multiple_of(16));
+        let exec_blob_addr =
+            children_addr.add((size_of::<NewChild>() * new_children.len()).next_multiple_of(16));
+        let restore_fn_addr = exec_blob_addr.add(exec_blob_len.next_multiple_of(16));
+        // stack grows downwards from the end of the region
+        let stack_pointer_addr = restore_addr.add(len);
+
+        let scratch_addr = stack_pointer_addr as usize;
+        let kernel_mmaps = own_kernel_mmaps
+            .iter()
+            .zip(origin_kernel_mmaps.iter())
+            .map(|((_, m), o)| KernelMmap {
+                addr: m.addr,
+                len: m.len,
+                scratch: scratch_addr + m.addr - own_kernel_mmaps[0].1.addr,
+                target: o.address as _,
+            })
+            .collect::<Vec<_>>();
+        trace!("kernel_mmaps: {:?}", kernel_mmaps);
+
+        let (blob, (argv, envp)) = exec_blob(exec_blob_addr as _, &exec_path, &child_env);
+        assert!(blob.len() == exec_blob_len);
+        memcpy(exec_blob_addr, blob.as_ptr() as _, blob.len());
+        for child in new_children.iter_mut() {
+            child.envp = envp as _;
+        }
+        let mut children_ptr = children_addr as *const NewChild;
+        for thread in new_threads.iter_mut() {
+            thread.children = children_ptr;
+            children_ptr = children_ptr.add(thread.children_len);
+        }
 
         let state = RestoreState {
-            pid: proc.pid,
+            pid: getpid().as_raw(),
             fd: ready_fd,
-            current_mmaps: (current_mmaps.len(), current_mmaps_addr as _),
-            new_mmaps: (new_mmaps.len(), new_mmaps_addr as _),
+            current_mmaps_len: current_mmaps.len(),
+            current_mmaps: current_mmaps_addr as _,
+            new_mmaps_len: new_mmaps.len(),
+            new_mmaps: new_mmaps_addr as _,
+            kernel_mmaps_len: kernel_mmaps.len(),
+            kernel_mmaps: kernel_mmaps_addr as _,
+            mm_map: MmMap {
+                start_code: proc.layout.start_code,
+                end_code: proc.layout.end_code,
+                start_data: proc.layout.start_data,
+                end_data: proc.layout.end_data,
+                start_brk: proc.layout.start_brk,
+                brk: proc.layout.brk,
+                start_stack: proc.layout.start_stack,
+                arg_start: proc.layout.arg_start,
+                arg_end: proc.layout.arg_end,
+                env_start: proc.layout.env_start,
+                env_end: proc.layout.env_end,
+                auxv: std::ptr::null(),
+                auxv_size: 0,
+                exe_fd: u32::MAX,
+            },
+            sigactions_len: new_sigactions.len(),
+            sigactions: sigactions_addr as _,
+            threads_len: new_threads.len(),
+            threads: new_threads_addr as _,
+            tids: tids_addr as _,
+            report: report_addr as _,
+            pending_threads: new_threads.len() - 1,
+            ns_last_pid_fd,
+            // argv[0] is the path itself
+            exec_path: *(argv as *const *const u8),
+            exec_argv: argv as _,
             restore_fn: restore_fn_addr,
             stack_pointer: stack_pointer_addr,
         };
@@ -152,71 +525,335 @@ fn main() {
             size_of::<RestoreState>(),
         );
         memcpy(
-            state.current_mmaps.1 as _,
-            &current_mmaps as *const _ as _,
-            size_of::<CurrentMmap>() * state.current_mmaps.0,
+            state.current_mmaps as _,
+            current_mmaps.as_ptr() as _,
+            size_of::<CurrentMmap>() * state.current_mmaps_len,
         );
         memcpy(
-            state.new_mmaps.1 as _,
-            &new_mmaps as *const _ as _,
-            size_of::<NewMmap>() * state.new_mmaps.0,
+            state.new_mmaps as _,
+            new_mmaps.as_ptr() as _,
+            size_of::<NewMmap>() * state.new_mmaps_len,
         );
-
-        let (start, end) = restore_fn_code();
-        trace!(
-            "restore fn addr {start:?}-{end:?} ({})",
-            end.offset_from(start)
+        memcpy(
+            state.kernel_mmaps as _,
+            kernel_mmaps.as_ptr() as _,
+            size_of::<KernelMmap>() * state.kernel_mmaps_len,
         );
         memcpy(
-            state.restore_fn as _,
-            start as _,
-            end.offset_from(start) as _,
+            state.sigactions as _,
+            new_sigactions.as_ptr() as _,
+            size_of::<NewSigAction>() * state.sigactions_len,
         );
+        memcpy(
+            state.threads as _,
+            new_threads.as_ptr() as _,
+            size_of::<NewThread>() * state.threads_len,
+        );
+        *(report_addr as *mut ReadyReport) = ReadyReport {
+            pid: state.pid,
+            threads_len: new_threads.len() as u32,
+            // filled in for each report by the restore routine
+            first: 0,
+            tids_len: 0,
+            region: restore_addr as u64,
+            region_len: (len + kernel_mmaps_span) as u64,
+            ..Default::default()
+        };
+        memcpy(
+            children_addr,
+            new_children.as_ptr() as _,
+            size_of::<NewChild>() * new_children.len(),
+        );
+        // the restore routine fills in the tids of the threads it creates
+        *state.tids = state.pid;
+
+        trace!("restore fn addr {restore_fn_start:?}-{restore_fn_end:?} ({restore_fn_len})");
+        memcpy(state.restore_fn as _, restore_fn_start as _, restore_fn_len);
         trace!("restore fn copied");
 
+        // set the stack pointer to the new address and call the restore function
+        #[cfg(target_arch = "x86_64")]
+        asm!(
+            "mov rsp, {s}",
+            "jmp {f}",
+            s = in(reg) state.stack_pointer,
+            f = in(reg) state.restore_fn,
+            in("rdi") restore_addr,
+            options(noreturn)
+        );
+
         #[cfg(target_arch = "aarch64")]
-        {
-            // set the stack pointer to the new address and call the restore function
-            unsafe {
-                asm!(
-                    "mov x0, {r}",
-                    "msr el_sp0, {s}",
-                    "br {f}",
-                    r = in(reg) restore_addr,
-                    s = in(reg) state.stack_pointer,
-                    f = in(reg) state.restore_fn
-                );
+        asm!(
+            "mov x0, {r}",
+            "msr el_sp0, {s}",
+            "br {f}",
+            r = in(reg) restore_addr,
+            s = in(reg) state.stack_pointer,
+            f = in(reg) state.restore_fn
+        );
+    }
+}
+
+fn mm_map_size() -> nix::Result<u32> {
+    let mut size = 0u32;
+    let res = unsafe {
+        libc::prctl(
+            libc::PR_SET_MM,
+            libc::PR_SET_MM_MAP_SIZE,
+            &mut size as *mut u32,
+            0,
+            0,
+        )
+    };
+    Errno::result(res).map(|_| size)
+}
+
+// tells the destination why we cannot restore the process rather than
+// leaving it to find out we are gone
+fn report_failure(ready_fd: RawFd, proc: &Process, reason: &str) -> ! {
+    let reason = &reason.as_bytes()[..reason.len().min(libc::PIPE_BUF - ReadyReport::SIZE)];
+    let report = ReadyReport {
+        pid: proc.pid,
+        threads_len: proc.threads.len() as u32,
+        error_len: reason.len() as u32,
+        ..Default::default()
+    };
+    // a single write so it stays in one piece
+    let buf = [report.as_bytes(), reason].concat();
+    write(ready_fd, &buf).expect("failed to report restore failure");
+    std::process::exit(1)
+}
+
+// sockets cannot be reopened through procfs so the destination creates them
+// and passes them to us, keyed by the fd each one is restored to. they are
+// kept above every fd we restore so none is overwritten on the way
+fn receive_sockets(proc: &Process) -> HashMap<RawFd, RawFd> {
+    let fds = proc
+        .fd_table
+        .iter()
+        .filter_map(|f| match &f.r#type {
+            FdType::SocketUnix(s) => Some((f.fd, s.inode)),
+            FdType::SocketIp(s) => Some((f.fd, s.inode)),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    if fds.is_empty() {
+        return HashMap::new();
+    }
+    let min_fd = proc.fd_table.iter().map(|f| f.fd).max().unwrap() + 1;
+
+    let mut stream = UnixStream::connect(env::var_os("EP_SOCKETS").unwrap())
+        .expect("failed to connect to destination");
+    let mut req = (fds.len() as u32).to_ne_bytes().to_vec();
+    for (_, inode) in fds.iter() {
+        req.extend_from_slice(&inode.to_ne_bytes());
+    }
+    stream.write_all(&req).unwrap();
+
+    fds.iter()
+        .map(|(fd, inode)| {
+            let mut buf = [0u8; 1];
+            let mut cmsg = cmsg_space!([RawFd; 1]);
+            let msg = recvmsg::<()>(
+                stream.as_raw_fd(),
+                &mut [IoSliceMut::new(&mut buf)],
+                Some(&mut cmsg),
+                MsgFlags::MSG_CMSG_CLOEXEC,
+            )
+            .unwrap();
+            let Some(ControlMessageOwned::ScmRights(received)) = msg.cmsgs().next() else {
+                panic!("failed to receive socket {inode}");
+            };
+
+            let sock = fcntl(received[0], FcntlArg::F_DUPFD_CLOEXEC(min_fd)).unwrap();
+            close(received[0]).unwrap();
+            (*fd, sock)
+        })
+        .collect()
+}
+
+// moves nfd to the offset the origin fd had
+fn seek(nfd: RawFd, position: u64) -> nix::Result<()> {
+    match lseek(nfd, position as _, Whence::SeekSet) {
+        // ttys and other character devices cannot seek
+        Ok(_) | Err(Errno::ESPIPE) => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+// creates the anon inode fd, an epoll set is empty until its targets exist
+fn anon_inode(r#type: &FdType) -> RawFd {
+    let res = match r#type {
+        FdType::EventFd(e) => {
+            let flags = match e.semaphore {
+                true => libc::EFD_CLOEXEC | libc::EFD_SEMAPHORE,
+                false => libc::EFD_CLOEXEC,
+            };
+            // the initial count only takes 32 bits
+            let nfd = unsafe { libc::eventfd(0, flags) };
+            if nfd >= 0 && e.count > 0 {
+                write(nfd, &e.count.to_ne_bytes()).expect("failed to restore eventfd count");
+            }
+            nfd
+        }
+        FdType::TimerFd(t) => {
+            let nfd = unsafe { libc::timerfd_create(t.clock, libc::TFD_CLOEXEC) };
+            if nfd >= 0 && !t.value.is_zero() {
+                let timespec = |d: Duration| libc::timespec {
+                    tv_sec: d.as_secs() as _,
+                    tv_nsec: d.subsec_nanos() as _,
+                };
+                let spec = libc::itimerspec {
+                    it_interval: timespec(t.interval),
+                    it_value: timespec(t.value),
+                };
+                let res = unsafe { libc::timerfd_settime(nfd, 0, &spec, std::ptr::null_mut()) };
+                Errno::result(res).expect("failed to arm timerfd");
+            }
+            if nfd >= 0 && t.ticks > 0 {
+                // only available with CONFIG_CHECKPOINT_RESTORE
+                let res = unsafe { libc::ioctl(nfd, TFD_IOC_SET_TICKS, &t.ticks) };
+                if let Err(e) = Errno::result(res) {
+                    warn!("failed to restore {} timerfd expirations: {e}", t.ticks);
+                }
             }
+            nfd
+        }
+        FdType::SignalFd(s) => unsafe {
+            // the kernel only takes the 64 bits of its own sigset
+            libc::syscall(
+                libc::SYS_signalfd4,
+                -1,
+                &s.mask,
+                size_of::<u64>(),
+                libc::SFD_CLOEXEC,
+            ) as _
+        },
+        FdType::Epoll(_) => unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) },
+        _ => unreachable!(),
+    };
+
+    Errno::result(res).unwrap_or_else(|e| panic!("failed to create {type:?}: {e}"))
+}
+
+// environment of the restore stubs of our children, they read the same state
+// and report to the same ready fd
+fn stub_env(ready_fd: i32) -> Vec<CString> {
+    env::vars_os()
+        .filter(|(k, _)| k != "EP_READY_FD")
+        .map(|(k, v)| {
+            let mut var = k.into_vec();
+            var.push(b'=');
+            var.extend_from_slice(v.as_bytes());
+            CString::new(var).unwrap()
+        })
+        .chain([CString::new(format!("EP_READY_FD={ready_fd}")).unwrap()])
+        .collect()
+}
+
+// lays out argv followed by envp as if the returned bytes were copied to
+// addr, also returns the address of both arrays
+fn exec_blob(addr: usize, path: &CStr, env: &[CString]) -> (Vec<u8>, (usize, usize)) {
+    let mut blob = vec![];
+    let mut ptrs = vec![];
+
+    for strs in [vec![path], env.iter().map(|i| i.as_c_str()).collect()] {
+        blob.resize(blob.len().next_multiple_of(size_of::<usize>()), 0);
+        ptrs.push(addr + blob.len());
+
+        // null terminated array of pointers followed by the strings themselves
+        let mut str_addr = addr + blob.len() + (strs.len() + 1) * size_of::<usize>();
+        for s in strs.iter() {
+            blob.extend_from_slice(&str_addr.to_ne_bytes());
+            str_addr += s.to_bytes_with_nul().len();
+        }
+        blob.extend_from_slice(&0usize.to_ne_bytes());
+        for s in strs.iter() {
+            blob.extend_from_slice(s.to_bytes_with_nul());
         }
     }
 
-    // copy restore routine to mmap, jump there, fix stack?, unmap all, remap new, signal done
+    (blob, (ptrs[0], ptrs[1]))
+}
 
-    unsafe {
-        restore::restore(ptr::null());
+fn clone3_supported() -> bool {
+    // a zero sized clone_args is rejected with EINVAL if clone3 exists
+    let res = unsafe { libc::syscall(libc::SYS_clone3, std::ptr::null::<u8>(), 0) };
+    !(res == -1 && Errno::last() == Errno::ENOSYS)
+}
+
+// vsyscall is fixed by the kernel and cannot be unmapped while the vdso
+// and vvar are moved rather than unmapped
+fn is_kernel_mmap(path: &MMapPath) -> bool {
+    match path {
+        MMapPath::Vsyscall | MMapPath::Vdso | MMapPath::Vvar => true,
+        MMapPath::Other(p) => p == "vvar_vclock",
+        _ => false,
     }
 }
 
-fn find_safe_address_space(proc: &mut Process) -> (usize, usize) {
-    let page_size = sysconf(SysconfVar::PAGE_SIZE).unwrap().unwrap() as usize;
-    trace!("page_size: {page_size}");
-    let restore_space = RESTORE_SPACE - (RESTORE_SPACE % page_size) + page_size;
+fn kernel_mmaps() -> Vec<(MMapPath, CurrentMmap)> {
+    procfs::process::Process::myself()
+        .unwrap()
+        .maps()
+        .unwrap()
+        .into_iter()
+        .filter(|i| is_kernel_mmap(&i.pathname) && i.pathname != MMapPath::Vsyscall)
+        .map(|i| {
+            let mmap = CurrentMmap {
+                addr: i.address.0 as _,
+                len: (i.address.1 - i.address.0) as _,
+            };
+            (i.pathname, mmap)
+        })
+        .collect()
+}
+
+fn current_mmaps() -> Vec<CurrentMmap> {
+    // our pid is namespaced so it cannot be used to look ourselves up in /proc
+    procfs::process::Process::myself()
+        .unwrap()
+        .maps()
+        .unwrap()
+        .into_iter()
+        .filter(|i| !is_kernel_mmap(&i.pathname))
+        .map(|i| CurrentMmap {
+            addr: i.address.0 as _,
+            len: (i.address.1 - i.address.0) as _,
+        })
+        .collect()
+}
 
-    proc.mmaps.sort_by_key(|m| m.address);
-    let prev = proc.mmaps.first().unwrap();
-    for mmap in proc.mmaps.iter().skip(1) {
-        if (mmap.address - prev.address_end()) as usize > restore_space + page_size * 2 {
-            assert!(prev.address_end() as usize % page_size == 0);
-            let start = prev.address_end() as usize + page_size;
+// finds a gap of len bytes in both the restored and the current address space
+fn find_safe_address_space(proc: &Process, len: usize, page_size: usize) -> usize {
+    let mut used = proc
+        .mmaps
+        .iter()
+        .map(|m| (m.address as usize, m.address_end() as usize))
+        .chain(current_mmaps().iter().map(|m| (m.addr, m.addr + m.len)))
+        .chain(kernel_mmaps().iter().map(|(_, m)| (m.addr, m.addr + m.len)))
+        .collect::<Vec<_>>();
+    used.sort();
 
-            return (start as _, restore_space);
+    // leave a guard page on either side of the restore space
+    let mut prev_end = MIN_RESTORE_ADDRESS;
+    for (start, end) in used {
+        if start > prev_end && start - prev_end >= len + page_size * 2 {
+            assert!(prev_end.is_multiple_of(page_size));
+            return prev_end + page_size;
         }
+        prev_end = prev_end.max(end);
     }
 
     panic!("could not find suitable address space")
 }
 
+#[cfg(target_arch = "x86_64")]
+fn restore_fn_code() -> (*const u8, *const u8) {
+    escapepod_restore::restore::x86_64::restore_fn_code()
+}
+
 #[cfg(target_arch = "aarch64")]
-unsafe fn restore_fn_code() -> (*const u8, *const u8) {
+fn restore_fn_code() -> (*const u8, *const u8) {
     todo!()
 }
@@ -1,30 +1,17 @@
-use std::{
-    arch::asm,
-    env,
-    mem::{self, size_of},
-    ptr,
-};
-
-use crate::{CurrentMmap, NewMmap, RestoreState};
-use escapepod_common::{
-    libc::memcpy,
-    nix::{
-        self,
-        fcntl::OFlag,
-        sys::{
-            mman::{mmap, MapFlags, ProtFlags},
-            signal::Signal,
-            stat::Mode,
-        },
-        unistd::{getpid, sysconf, SysconfVar},
-    },
-    procfs::{self},
-    proto::{FdType, MemoryMappingData, Process},
-    serde_json,
-    tracing::trace,
-};
+#[cfg(target_arch = "aarch64")]
+use std::mem;
+
+#[cfg(target_arch = "aarch64")]
+use crate::RestoreState;
+#[cfg(target_arch = "aarch64")]
+use escapepod_common::nix::sys::signal::Signal;
+#[cfg(target_arch = "aarch64")]
 use syscalls::Sysno;
 
+#[cfg(target_arch = "x86_64")]
+pub mod x86_64;
+
+#[cfg(target_arch = "aarch64")]
 macro_rules! assert {
     ($expr:expr) => {
         if !($expr) {
@@ -36,12 +23,13 @@ macro_rules! assert {
 
 // this is our restore function
 // its goals is to restore the memory mappings to the state before the process froze
+#[cfg(target_arch = "aarch64")]
 #[inline(never)]
 pub unsafe extern "C" fn restore(state: *const RestoreState) {
     let state = mem::transmute::<_, &'static RestoreState>(state);
 
     // stage 1: unmap existing memory mappings (except the restore state and code)
-    let (len, mmaps) = state.current_mmaps;
+    let (len, mmaps) = (state.current_mmaps_len, state.current_mmaps);
     for i in 0..len {
         let mmap = mmaps.add(i).read_unaligned();
 
@@ -50,7 +38,7 @@ pub unsafe extern "C" fn restore(state: *const RestoreState) {
     }
 
     // stage 2: recreate process maps
-    let (len, mmaps) = state.new_mmaps;
+    let (len, mmaps) = (state.new_mmaps_len, state.new_mmaps);
     for i in 0..len {
         let mmap = mmaps.add(i).read_unaligned();
 
@@ -63,12 +51,12 @@ pub unsafe extern "C" fn restore(state: *const RestoreState) {
             mmap.fd as _,
             mmap.offset,
         );
-        assert!(res == 0);
+        assert!(res == mmap.addr);
     }
 
     // stage 3: signal main process that the mmaps have been restored
     let res = syscalls::raw::syscall3(Sysno::write, state.fd as _, &state as *const _ as usize, 1);
-    assert!(res == 0);
+    assert!(res == 1);
 
     // stage 4: stop the current process
     syscalls::raw::syscall2(Sysno::kill, state.pid as _, Signal::SIGSTOP as _);
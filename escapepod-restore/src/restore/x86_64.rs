@@ -0,0 +1,472 @@
+use std::{
+    arch::global_asm,
+    mem::{offset_of, size_of},
+};
+
+use escapepod_common::{libc, nix::sys::signal::Signal, ready::ReadyReport};
+use syscalls::Sysno;
+
+use crate::{
+    CurrentMmap, KernelMmap, MmMap, NewChild, NewMmap, NewSigAction, NewThread, RestoreState,
+};
+
+extern "C" {
+    fn escapepod_restore_x86_64();
+    fn escapepod_restore_x86_64_end();
+}
+
+/// Returns the bounds of the position independent restore routine
+/// so it can be copied into the restore address space.
+pub fn restore_fn_code() -> (*const u8, *const u8) {
+    (
+        escapepod_restore_x86_64 as *const u8,
+        escapepod_restore_x86_64_end as *const u8,
+    )
+}
+
+// this is our restore routine, it expects a pointer to the RestoreState in rdi
+// and the stack pointer to be within the restore address space.
+// it must be position independent and must not touch any memory outside
+// of the restore address space as everything else is unmapped.
+//
+// stage 1: unmap existing memory mappings (except the restore state and code)
+// stage 2: move the vdso and vvar to where the origin had them, through a
+//          scratch area as the old and new addresses may overlap
+// stage 3: recreate process maps and hand the kernel the origin's bounds of
+//          the heap, stack, arguments and environment
+// stage 4: create the remaining threads with their original tids one at a
+//          time, each forks its child processes and sets its own
+//          clear_child_tid, robust list and signal mask before parking itself,
+//          the current thread then does the same for itself
+// stage 5: signal main process that the process has been restored by sending
+//          the tids of the restored threads, in reports of at most PIPE_BUF
+//          bytes which also carry the restore region
+// stage 6: set the signal dispositions, which none of the forked children
+//          inherit, and stop the current process. the destination then loads
+//          the saved registers with ptrace so we must never be continued past
+//          this point
+global_asm!(
+    ".pushsection .text.escapepod_restore_x86_64,\"ax\",@progbits",
+    ".globl escapepod_restore_x86_64",
+    ".globl escapepod_restore_x86_64_end",
+    "escapepod_restore_x86_64:",
+    "mov rbx, rdi",
+    // stage 1
+    "mov r12, qword ptr [rbx + {current_mmaps_len}]",
+    "mov r13, qword ptr [rbx + {current_mmaps}]",
+    ".Lepr_unmap:",
+    "test r12, r12",
+    "jz .Lepr_unmap_done",
+    "mov rdi, qword ptr [r13 + {current_addr}]",
+    "mov rsi, qword ptr [r13 + {current_len}]",
+    "mov eax, {sys_munmap}",
+    "syscall",
+    "test rax, rax",
+    "jnz .Lepr_abort",
+    "add r13, {current_size}",
+    "dec r12",
+    "jmp .Lepr_unmap",
+    ".Lepr_unmap_done:",
+    // stage 2
+    "mov r12, qword ptr [rbx + {kernel_mmaps_len}]",
+    "mov r13, qword ptr [rbx + {kernel_mmaps}]",
+    ".Lepr_kernel_scratch:",
+    "test r12, r12",
+    "jz .Lepr_kernel_scratch_done",
+    "mov rdi, qword ptr [r13 + {kernel_addr}]",
+    "mov r8, qword ptr [r13 + {kernel_scratch}]",
+    "call .Lepr_mremap",
+    "add r13, {kernel_size}",
+    "dec r12",
+    "jmp .Lepr_kernel_scratch",
+    ".Lepr_kernel_scratch_done:",
+    "mov r12, qword ptr [rbx + {kernel_mmaps_len}]",
+    "mov r13, qword ptr [rbx + {kernel_mmaps}]",
+    ".Lepr_kernel_move:",
+    "test r12, r12",
+    "jz .Lepr_kernel_move_done",
+    "mov rdi, qword ptr [r13 + {kernel_scratch}]",
+    "mov r8, qword ptr [r13 + {kernel_target}]",
+    "call .Lepr_mremap",
+    "add r13, {kernel_size}",
+    "dec r12",
+    "jmp .Lepr_kernel_move",
+    ".Lepr_kernel_move_done:",
+    // stage 3
+    "mov r12, qword ptr [rbx + {new_mmaps_len}]",
+    "mov r13, qword ptr [rbx + {new_mmaps}]",
+    ".Lepr_map:",
+    "test r12, r12",
+    "jz .Lepr_map_done",
+    "mov rdi, qword ptr [r13 + {new_addr}]",
+    "mov rsi, qword ptr [r13 + {new_len}]",
+    "movsxd rdx, dword ptr [r13 + {new_prot}]",
+    "movsxd r10, dword ptr [r13 + {new_flags}]",
+    "movsxd r8, dword ptr [r13 + {new_fd}]",
+    "mov r9, qword ptr [r13 + {new_offset}]",
+    "mov eax, {sys_mmap}",
+    "syscall",
+    "cmp rax, qword ptr [r13 + {new_addr}]",
+    "jne .Lepr_abort",
+    // file mappings hold their own reference so we can close the fd now
+    "movsxd rdi, dword ptr [r13 + {new_fd}]",
+    "test rdi, rdi",
+    "js .Lepr_mapped",
+    "mov eax, {sys_close}",
+    "syscall",
+    ".Lepr_mapped:",
+    "add r13, {new_size}",
+    "dec r12",
+    "jmp .Lepr_map",
+    ".Lepr_map_done:",
+    "mov edi, {pr_set_mm}",
+    "mov esi, {pr_set_mm_map}",
+    "lea rdx, [rbx + {mm_map}]",
+    "mov r10d, {mm_map_size}",
+    "xor r8, r8",
+    "mov eax, {sys_prctl}",
+    "syscall",
+    "test rax, rax",
+    "jnz .Lepr_abort",
+    // stage 4
+    "mov r12, qword ptr [rbx + {threads_len}]",
+    "mov r13, qword ptr [rbx + {threads}]",
+    "mov r14, qword ptr [rbx + {tids}]",
+    // the first thread is the current one
+    "dec r12",
+    "add r13, {thread_size}",
+    "add r14, 4",
+    ".Lepr_clone:",
+    "test r12, r12",
+    "jz .Lepr_clone_done",
+    "movsxd rdi, dword ptr [rbx + {ns_last_pid_fd}]",
+    "test rdi, rdi",
+    "js .Lepr_clone3",
+    // without clone3 the next tid is whatever follows ns_last_pid
+    "lea rsi, [r13 + {thread_last_pid}]",
+    "mov rdx, qword ptr [r13 + {thread_last_pid_len}]",
+    "xor r10, r10",
+    "mov eax, {sys_pwrite64}",
+    "syscall",
+    "cmp rax, qword ptr [r13 + {thread_last_pid_len}]",
+    "jne .Lepr_abort",
+    "mov edi, {clone_flags}",
+    // the new thread runs on our stack until it parks, which is fine as we
+    // only make syscalls until then, see .Lepr_wait_thread
+    "xor esi, esi",
+    "xor edx, edx",
+    "xor r10, r10",
+    "xor r8, r8",
+    "mov eax, {sys_clone}",
+    "syscall",
+    "jmp .Lepr_cloned",
+    ".Lepr_clone3:",
+    // the clone args live on our stack, the new thread never reads them
+    "sub rsp, {clone_args_size}",
+    "mov rdi, rsp",
+    "xor eax, eax",
+    "mov ecx, {clone_args_size}",
+    "rep stosb",
+    "mov qword ptr [rsp + {clone_args_flags}], {clone_flags}",
+    "lea rax, [r13 + {thread_tid}]",
+    "mov qword ptr [rsp + {clone_args_set_tid}], rax",
+    "mov qword ptr [rsp + {clone_args_set_tid_size}], 1",
+    "mov rdi, rsp",
+    "mov esi, {clone_args_size}",
+    "mov eax, {sys_clone3}",
+    "syscall",
+    "add rsp, {clone_args_size}",
+    ".Lepr_cloned:",
+    "test rax, rax",
+    "jz .Lepr_thread",
+    "js .Lepr_abort",
+    "mov dword ptr [r14], eax",
+    // one thread at a time as the new thread uses our stack to fork
+    ".Lepr_wait_thread:",
+    "cmp qword ptr [rbx + {pending_threads}], r12",
+    "jb .Lepr_thread_ready",
+    "mov eax, {sys_sched_yield}",
+    "syscall",
+    "jmp .Lepr_wait_thread",
+    ".Lepr_thread_ready:",
+    "add r13, {thread_size}",
+    "add r14, 4",
+    "dec r12",
+    "jmp .Lepr_clone",
+    ".Lepr_clone_done:",
+    "mov r13, qword ptr [rbx + {threads}]",
+    "call .Lepr_fork_children",
+    "movsxd rdi, dword ptr [rbx + {ns_last_pid_fd}]",
+    "test rdi, rdi",
+    "js .Lepr_threads_ready",
+    "mov eax, {sys_close}",
+    "syscall",
+    ".Lepr_threads_ready:",
+    "mov rdi, qword ptr [r13 + {thread_clear_child_tid}]",
+    "mov eax, {sys_set_tid_address}",
+    "syscall",
+    "mov rdi, qword ptr [r13 + {thread_robust_list}]",
+    "mov rsi, qword ptr [r13 + {thread_robust_list_len}]",
+    "mov eax, {sys_set_robust_list}",
+    "syscall",
+    "test rax, rax",
+    "jnz .Lepr_abort",
+    "mov edi, {sig_setmask}",
+    "lea rsi, [r13 + {thread_sigmask}]",
+    "xor edx, edx",
+    "mov r10d, 8",
+    "mov eax, {sys_rt_sigprocmask}",
+    "syscall",
+    "test rax, rax",
+    "jnz .Lepr_abort",
+    // stage 5, r14 is the first tid of the next report and r15 the number of
+    // tids following it
+    "xor r14, r14",
+    ".Lepr_report:",
+    "mov r15, qword ptr [rbx + {threads_len}]",
+    "sub r15, r14",
+    "cmp r15, {report_max_tids}",
+    "jbe .Lepr_report_len",
+    "mov r15, {report_max_tids}",
+    ".Lepr_report_len:",
+    "mov rsi, qword ptr [rbx + {report}]",
+    "mov dword ptr [rsi + {report_first}], r14d",
+    "mov dword ptr [rsi + {report_tids_len}], r15d",
+    // each report goes right before its tids, over tids already written
+    "lea rdi, [rsi + r14 * 4]",
+    "mov ecx, {report_size}",
+    "rep movsb",
+    "lea rsi, [rdi - {report_size}]",
+    "lea r12, [r15 * 4 + {report_size}]",
+    "mov rdx, r12",
+    "movsxd rdi, dword ptr [rbx + {fd}]",
+    "mov eax, {sys_write}",
+    "syscall",
+    "cmp rax, r12",
+    "jne .Lepr_abort",
+    "add r14, r15",
+    "cmp r14, qword ptr [rbx + {threads_len}]",
+    "jb .Lepr_report",
+    // the restored process must not inherit it
+    "movsxd rdi, dword ptr [rbx + {fd}]",
+    "mov eax, {sys_close}",
+    "syscall",
+    // stage 6
+    "mov r12, qword ptr [rbx + {sigactions_len}]",
+    "mov r13, qword ptr [rbx + {sigactions}]",
+    ".Lepr_sigaction:",
+    "test r12, r12",
+    "jz .Lepr_sigaction_done",
+    "mov rdi, qword ptr [r13 + {sigaction_signal}]",
+    "lea rsi, [r13 + {sigaction_handler}]",
+    "xor edx, edx",
+    "mov r10d, 8",
+    "mov eax, {sys_rt_sigaction}",
+    "syscall",
+    "test rax, rax",
+    "jnz .Lepr_abort",
+    "add r13, {sigaction_size}",
+    "dec r12",
+    "jmp .Lepr_sigaction",
+    ".Lepr_sigaction_done:",
+    "movsxd rdi, dword ptr [rbx + {pid}]",
+    "mov esi, {sigstop}",
+    "mov eax, {sys_kill}",
+    "syscall",
+    "jmp .Lepr_abort",
+    // entrypoint of the created threads, r13 points to the thread's NewThread
+    ".Lepr_thread:",
+    "call .Lepr_fork_children",
+    "mov rdi, qword ptr [r13 + {thread_clear_child_tid}]",
+    "mov eax, {sys_set_tid_address}",
+    "syscall",
+    "mov rdi, qword ptr [r13 + {thread_robust_list}]",
+    "mov rsi, qword ptr [r13 + {thread_robust_list_len}]",
+    "mov eax, {sys_set_robust_list}",
+    "syscall",
+    "test rax, rax",
+    "jnz .Lepr_abort",
+    "mov edi, {sig_setmask}",
+    "lea rsi, [r13 + {thread_sigmask}]",
+    "xor edx, edx",
+    "mov r10d, 8",
+    "mov eax, {sys_rt_sigprocmask}",
+    "syscall",
+    "test rax, rax",
+    "jnz .Lepr_abort",
+    "lock dec qword ptr [rbx + {pending_threads}]",
+    ".Lepr_park:",
+    "mov eax, {sys_pause}",
+    "syscall",
+    "jmp .Lepr_park",
+    // forks the children of the NewThread in r13 with their original pids,
+    // each child then execs a restore stub for its own process
+    ".Lepr_fork_children:",
+    "mov r15, qword ptr [r13 + {thread_children_len}]",
+    "mov rbp, qword ptr [r13 + {thread_children}]",
+    ".Lepr_fork:",
+    "test r15, r15",
+    "jz .Lepr_fork_done",
+    "movsxd rdi, dword ptr [rbx + {ns_last_pid_fd}]",
+    "test rdi, rdi",
+    "js .Lepr_fork3",
+    "lea rsi, [rbp + {child_last_pid}]",
+    "mov rdx, qword ptr [rbp + {child_last_pid_len}]",
+    "xor r10, r10",
+    "mov eax, {sys_pwrite64}",
+    "syscall",
+    "cmp rax, qword ptr [rbp + {child_last_pid_len}]",
+    "jne .Lepr_abort",
+    "mov edi, {sigchld}",
+    "xor esi, esi",
+    "xor edx, edx",
+    "xor r10, r10",
+    "xor r8, r8",
+    "mov eax, {sys_clone}",
+    "syscall",
+    "jmp .Lepr_forked",
+    ".Lepr_fork3:",
+    "sub rsp, {clone_args_size}",
+    "mov rdi, rsp",
+    "xor eax, eax",
+    "mov ecx, {clone_args_size}",
+    "rep stosb",
+    "mov qword ptr [rsp + {clone_args_exit_signal}], {sigchld}",
+    "lea rax, [rbp + {child_pid}]",
+    "mov qword ptr [rsp + {clone_args_set_tid}], rax",
+    "mov qword ptr [rsp + {clone_args_set_tid_size}], 1",
+    "mov rdi, rsp",
+    "mov esi, {clone_args_size}",
+    "mov eax, {sys_clone3}",
+    "syscall",
+    "add rsp, {clone_args_size}",
+    ".Lepr_forked:",
+    "test rax, rax",
+    "jz .Lepr_exec",
+    "js .Lepr_abort",
+    "add rbp, {child_size}",
+    "dec r15",
+    "jmp .Lepr_fork",
+    ".Lepr_fork_done:",
+    "ret",
+    // moves the kernel mapping of r13 from rdi to r8
+    ".Lepr_mremap:",
+    "mov rsi, qword ptr [r13 + {kernel_len}]",
+    "mov rdx, rsi",
+    "mov r10d, {mremap_flags}",
+    "mov eax, {sys_mremap}",
+    "syscall",
+    "cmp rax, r8",
+    "jne .Lepr_abort",
+    "ret",
+    ".Lepr_exec:",
+    "mov rdi, qword ptr [rbx + {exec_path}]",
+    "mov rsi, qword ptr [rbx + {exec_argv}]",
+    "mov rdx, qword ptr [rbp + {child_envp}]",
+    "mov eax, {sys_execve}",
+    "syscall",
+    "jmp .Lepr_abort",
+    ".Lepr_abort:",
+    "mov eax, {sys_getpid}",
+    "syscall",
+    "mov rdi, rax",
+    "mov esi, {sigabrt}",
+    "mov eax, {sys_kill}",
+    "syscall",
+    "ud2",
+    "escapepod_restore_x86_64_end:",
+    ".popsection",
+    current_mmaps_len = const offset_of!(RestoreState, current_mmaps_len),
+    current_mmaps = const offset_of!(RestoreState, current_mmaps),
+    new_mmaps_len = const offset_of!(RestoreState, new_mmaps_len),
+    new_mmaps = const offset_of!(RestoreState, new_mmaps),
+    kernel_mmaps_len = const offset_of!(RestoreState, kernel_mmaps_len),
+    kernel_mmaps = const offset_of!(RestoreState, kernel_mmaps),
+    sigactions_len = const offset_of!(RestoreState, sigactions_len),
+    sigactions = const offset_of!(RestoreState, sigactions),
+    sigaction_signal = const offset_of!(NewSigAction, signal),
+    sigaction_handler = const offset_of!(NewSigAction, handler),
+    sigaction_size = const size_of::<NewSigAction>(),
+    threads_len = const offset_of!(RestoreState, threads_len),
+    threads = const offset_of!(RestoreState, threads),
+    tids = const offset_of!(RestoreState, tids),
+    report = const offset_of!(RestoreState, report),
+    report_size = const ReadyReport::SIZE,
+    report_max_tids = const ReadyReport::MAX_TIDS,
+    report_first = const offset_of!(ReadyReport, first),
+    report_tids_len = const offset_of!(ReadyReport, tids_len),
+    pending_threads = const offset_of!(RestoreState, pending_threads),
+    fd = const offset_of!(RestoreState, fd),
+    pid = const offset_of!(RestoreState, pid),
+    ns_last_pid_fd = const offset_of!(RestoreState, ns_last_pid_fd),
+    exec_path = const offset_of!(RestoreState, exec_path),
+    exec_argv = const offset_of!(RestoreState, exec_argv),
+    mm_map = const offset_of!(RestoreState, mm_map),
+    mm_map_size = const size_of::<MmMap>(),
+    current_addr = const offset_of!(CurrentMmap, addr),
+    current_len = const offset_of!(CurrentMmap, len),
+    current_size = const size_of::<CurrentMmap>(),
+    new_addr = const offset_of!(NewMmap, addr),
+    new_len = const offset_of!(NewMmap, len),
+    new_prot = const offset_of!(NewMmap, prot),
+    new_flags = const offset_of!(NewMmap, flags),
+    new_fd = const offset_of!(NewMmap, fd),
+    new_offset = const offset_of!(NewMmap, offset),
+    new_size = const size_of::<NewMmap>(),
+    kernel_addr = const offset_of!(KernelMmap, addr),
+    kernel_len = const offset_of!(KernelMmap, len),
+    kernel_scratch = const offset_of!(KernelMmap, scratch),
+    kernel_target = const offset_of!(KernelMmap, target),
+    kernel_size = const size_of::<KernelMmap>(),
+    thread_tid = const offset_of!(NewThread, tid),
+    thread_last_pid = const offset_of!(NewThread, last_pid),
+    thread_last_pid_len = const offset_of!(NewThread, last_pid_len),
+    thread_clear_child_tid = const offset_of!(NewThread, clear_child_tid),
+    thread_sigmask = const offset_of!(NewThread, sigmask),
+    thread_robust_list = const offset_of!(NewThread, robust_list),
+    thread_robust_list_len = const offset_of!(NewThread, robust_list_len),
+    thread_children_len = const offset_of!(NewThread, children_len),
+    thread_children = const offset_of!(NewThread, children),
+    thread_size = const size_of::<NewThread>(),
+    child_pid = const offset_of!(NewChild, pid),
+    child_last_pid = const offset_of!(NewChild, last_pid),
+    child_last_pid_len = const offset_of!(NewChild, last_pid_len),
+    child_envp = const offset_of!(NewChild, envp),
+    child_size = const size_of::<NewChild>(),
+    clone_args_flags = const offset_of!(libc::clone_args, flags),
+    clone_args_exit_signal = const offset_of!(libc::clone_args, exit_signal),
+    clone_args_set_tid = const offset_of!(libc::clone_args, set_tid),
+    clone_args_set_tid_size = const offset_of!(libc::clone_args, set_tid_size),
+    clone_args_size = const size_of::<libc::clone_args>(),
+    sys_munmap = const Sysno::munmap as usize,
+    sys_mmap = const Sysno::mmap as usize,
+    sys_mremap = const Sysno::mremap as usize,
+    sys_write = const Sysno::write as usize,
+    sys_kill = const Sysno::kill as usize,
+    sys_clone = const Sysno::clone as usize,
+    sys_clone3 = const Sysno::clone3 as usize,
+    sys_pwrite64 = const Sysno::pwrite64 as usize,
+    sys_close = const Sysno::close as usize,
+    sys_execve = const Sysno::execve as usize,
+    sys_sched_yield = const Sysno::sched_yield as usize,
+    sys_set_tid_address = const Sysno::set_tid_address as usize,
+    sys_rt_sigprocmask = const Sysno::rt_sigprocmask as usize,
+    sys_rt_sigaction = const Sysno::rt_sigaction as usize,
+    sys_set_robust_list = const Sysno::set_robust_list as usize,
+    sys_pause = const Sysno::pause as usize,
+    sys_getpid = const Sysno::getpid as usize,
+    sys_prctl = const Sysno::prctl as usize,
+    sigstop = const Signal::SIGSTOP as usize,
+    sigabrt = const Signal::SIGABRT as usize,
+    sigchld = const Signal::SIGCHLD as usize,
+    sig_setmask = const libc::SIG_SETMASK,
+    mremap_flags = const libc::MREMAP_MAYMOVE | libc::MREMAP_FIXED,
+    pr_set_mm = const libc::PR_SET_MM,
+    pr_set_mm_map = const libc::PR_SET_MM_MAP,
+    clone_flags = const libc::CLONE_VM
+        | libc::CLONE_FS
+        | libc::CLONE_FILES
+        | libc::CLONE_SIGHAND
+        | libc::CLONE_THREAD
+        | libc::CLONE_SYSVSEM,
+);
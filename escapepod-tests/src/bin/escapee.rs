@@ -0,0 +1,852 @@
+// a process for the tests to escape. it sets up the state of a scenario,
+// waits for the test to create the trigger file once it has been escaped and
+// then checks that the state survived
+use std::{
+    cell::Cell,
+    env,
+    fs::OpenOptions,
+    io::{Read, Seek, SeekFrom, Write},
+    mem::size_of,
+    net::{TcpListener, TcpStream, UdpSocket},
+    os::{
+        fd::{AsRawFd, FromRawFd},
+        unix::{
+            fs::{FileExt, MetadataExt, PermissionsExt},
+            net::{UnixDatagram, UnixListener, UnixStream},
+        },
+    },
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+use escapepod_common::libc;
+
+type Check = Box<dyn FnOnce() -> Result<(), String>>;
+
+fn main() {
+    let args = env::args().collect::<Vec<_>>();
+    let (scenario, trigger) = (args[1].as_str(), Path::new(&args[2]));
+
+    let check: Check = match scenario {
+        "threads" => threads(args.get(3).map_or(3, |n| n.parse().unwrap())),
+        "pids" => pids(),
+        "fork_tree" => fork_tree(trigger),
+        "memory" => memory(),
+        "vdso" => vdso(),
+        "dirty" => dirty(),
+        "dirty_once" => dirty_once(),
+        "files" => files(trigger),
+        "deleted" => deleted(trigger),
+        "synced" => synced(trigger),
+        "pipe" => pipe(trigger),
+        "unix_sockets" => unix_sockets(trigger),
+        "listen" => listen(),
+        "anon" => anon(),
+        "memfd" => memfd(),
+        "signals" => signals(),
+        _ => panic!("unknown scenario {scenario}"),
+    };
+
+    println!("escapee ready");
+    wait_for(trigger);
+
+    match check() {
+        Ok(()) => println!("escapee passed"),
+        Err(e) => println!("escapee failed: {e}"),
+    }
+}
+
+fn wait_for(trigger: &Path) {
+    while !trigger.exists() {
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+// forks a child which exits with 0 if f returns true
+fn fork(f: impl FnOnce() -> bool) -> i32 {
+    match unsafe { libc::fork() } {
+        0 => {
+            let code = if f() { 0 } else { 1 };
+            unsafe { libc::_exit(code) }
+        }
+        pid => pid,
+    }
+}
+
+fn wait_child(pid: i32) -> Result<(), String> {
+    let mut status = 0;
+    if unsafe { libc::waitpid(pid, &mut status, 0) } != pid {
+        return Err(format!("failed to wait for {pid}"));
+    }
+    match libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0 {
+        true => Ok(()),
+        false => Err(format!("child {pid} failed with status {status:#x}")),
+    }
+}
+
+// asks the kernel rather than a cache in libc
+fn getpid() -> i32 {
+    unsafe { libc::syscall(libc::SYS_getpid) as i32 }
+}
+
+fn getppid() -> i32 {
+    unsafe { libc::syscall(libc::SYS_getppid) as i32 }
+}
+
+fn gettid() -> i32 {
+    unsafe { libc::syscall(libc::SYS_gettid) as i32 }
+}
+
+thread_local! {
+    static VALUE: Cell<usize> = const { Cell::new(0) };
+}
+
+// each thread keeps its own tls, and joining relies on the kernel clearing
+// the thread's tid on exit
+fn threads(count: usize) -> Check {
+    let go = Arc::new(AtomicBool::new(false));
+    let threads = (1..=count)
+        .map(|i| {
+            let go = go.clone();
+            thread::spawn(move || {
+                VALUE.with(|v| v.set(i));
+                while !go.load(Ordering::Acquire) {
+                    thread::sleep(Duration::from_millis(10));
+                }
+                VALUE.with(|v| v.get()) == i
+            })
+        })
+        .collect::<Vec<_>>();
+
+    Box::new(move || {
+        go.store(true, Ordering::Release);
+        for (i, thread) in threads.into_iter().enumerate() {
+            if !thread.join().map_err(|_| format!("thread {i} panicked"))? {
+                return Err(format!("thread {i} lost its tls"));
+            }
+        }
+        Ok(())
+    })
+}
+
+fn pids() -> Check {
+    let pid = getpid();
+    let go = Arc::new(AtomicBool::new(false));
+    let thread = thread::spawn({
+        let go = go.clone();
+        move || {
+            let tid = gettid();
+            while !go.load(Ordering::Acquire) {
+                thread::sleep(Duration::from_millis(10));
+            }
+            (tid, gettid())
+        }
+    });
+
+    Box::new(move || {
+        go.store(true, Ordering::Release);
+        if getpid() != pid {
+            return Err(format!("pid changed from {pid} to {}", getpid()));
+        }
+        if gettid() != pid {
+            return Err(format!(
+                "main thread tid changed from {pid} to {}",
+                gettid()
+            ));
+        }
+        let (before, after) = thread.join().unwrap();
+        if before != after {
+            return Err(format!("tid changed from {before} to {after}"));
+        }
+        Ok(())
+    })
+}
+
+// a child with a grandchild, each checks it still has its parent once
+// triggered and the parents reap them
+fn fork_tree(trigger: &Path) -> Check {
+    let pid = getpid();
+    let trigger = trigger.to_path_buf();
+    let child = fork(move || {
+        let child = getpid();
+        let grandchild = fork({
+            let trigger = trigger.clone();
+            move || {
+                wait_for(&trigger);
+                getppid() == child
+            }
+        });
+        wait_for(&trigger);
+        getppid() == pid && wait_child(grandchild).is_ok()
+    });
+
+    Box::new(move || wait_child(child))
+}
+
+fn pattern(len: usize, seed: u64) -> Vec<u8> {
+    (0..len as u64)
+        .map(|i| i.wrapping_mul(seed).wrapping_add(i >> 12) as u8)
+        .collect()
+}
+
+// heap, an anonymous mapping with pages never touched and one without
+// PROT_READ
+fn memory() -> Check {
+    const LEN: usize = 4 << 20;
+
+    let heap = pattern(2 * LEN, 3);
+    let (mapped, hidden) = unsafe {
+        let map = |prot| {
+            let addr = libc::mmap(
+                std::ptr::null_mut(),
+                LEN,
+                prot,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            );
+            assert!(addr != libc::MAP_FAILED);
+            std::slice::from_raw_parts_mut(addr as *mut u8, LEN)
+        };
+        let mapped = map(libc::PROT_READ | libc::PROT_WRITE);
+        mapped[..LEN / 2].copy_from_slice(&pattern(LEN / 2, 5));
+        let hidden = map(libc::PROT_READ | libc::PROT_WRITE);
+        hidden.copy_from_slice(&pattern(LEN, 7));
+        libc::mprotect(hidden.as_mut_ptr() as _, LEN, libc::PROT_NONE);
+        (mapped, hidden)
+    };
+
+    Box::new(move || {
+        if heap != pattern(2 * LEN, 3) {
+            return Err("heap differs".to_string());
+        }
+        if mapped[..LEN / 2] != pattern(LEN / 2, 5) || mapped[LEN / 2..].iter().any(|b| *b != 0) {
+            return Err("anonymous mapping differs".to_string());
+        }
+        unsafe { libc::mprotect(hidden.as_mut_ptr() as _, LEN, libc::PROT_READ) };
+        if hidden[..] != pattern(LEN, 7) {
+            return Err("mapping without PROT_READ differs".to_string());
+        }
+        // nothing of the restore stub is left behind
+        let maps = std::fs::read_to_string("/proc/self/maps").unwrap();
+        if let Some(line) = maps.lines().find(|l| l.contains(" rwx")) {
+            return Err(format!("unexpected writable code mapping {line}"));
+        }
+        Ok(())
+    })
+}
+
+// the address of the vdso mapping in /proc/self/maps
+fn vdso_mapping() -> Option<u64> {
+    let maps = std::fs::read_to_string("/proc/self/maps").unwrap();
+    let line = maps.lines().find(|l| l.ends_with("[vdso]"))?;
+    u64::from_str_radix(line.split('-').next()?, 16).ok()
+}
+
+fn clock_gettime(vdso: bool) -> Duration {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe {
+        match vdso {
+            // libc goes through the vdso
+            true => libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts),
+            false => libc::syscall(libc::SYS_clock_gettime, libc::CLOCK_MONOTONIC, &mut ts) as i32,
+        }
+    };
+    Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32)
+}
+
+// the vdso stays where libc found it and reads the clocks of this kernel
+fn vdso() -> Check {
+    let ehdr = unsafe { libc::getauxval(libc::AT_SYSINFO_EHDR) };
+    let before = clock_gettime(true);
+
+    Box::new(move || {
+        if vdso_mapping() != Some(ehdr) {
+            return Err(format!(
+                "vdso moved from {ehdr:#x} to {:x?}",
+                vdso_mapping()
+            ));
+        }
+        let (vdso, syscall) = (clock_gettime(true), clock_gettime(false));
+        if vdso < before || syscall.abs_diff(vdso) > Duration::from_secs(1) {
+            return Err(format!(
+                "vdso clock reads {vdso:?}, the kernel {syscall:?} and before the escape {before:?}"
+            ));
+        }
+        Ok(())
+    })
+}
+
+// a thread keeps writing generations across pages while memory is copied,
+// whichever generation it was in when stopped must be on every page
+fn dirty() -> Check {
+    const PAGES: usize = 1024;
+    const STRIDE: usize = 4096 / size_of::<AtomicU64>();
+
+    let pages = Arc::new(
+        (0..PAGES * STRIDE)
+            .map(|_| AtomicU64::new(0))
+            .collect::<Vec<_>>(),
+    );
+    let stop = Arc::new(AtomicBool::new(false));
+    let writer = thread::spawn({
+        let (pages, stop) = (pages.clone(), stop.clone());
+        move || {
+            let mut generation = 0;
+            while !stop.load(Ordering::Acquire) {
+                generation += 1;
+                for page in pages.iter().step_by(STRIDE) {
+                    page.store(generation, Ordering::Relaxed);
+                }
+            }
+            generation
+        }
+    });
+
+    Box::new(move || {
+        stop.store(true, Ordering::Release);
+        let generation = writer.join().unwrap();
+        match pages
+            .iter()
+            .step_by(STRIDE)
+            .position(|p| p.load(Ordering::Relaxed) != generation)
+        {
+            Some(page) => Err(format!(
+                "page {page} holds generation {} rather than {generation}",
+                pages[page * STRIDE].load(Ordering::Relaxed)
+            )),
+            None => Ok(()),
+        }
+    })
+}
+
+// a thread writes each page once, one after the other, while memory is
+// copied. a page written just as a round lists what to copy must not be lost
+// by the next
+fn dirty_once() -> Check {
+    const PAGES: usize = 16384;
+    const STRIDE: usize = 4096 / size_of::<AtomicU64>();
+
+    let pages = Arc::new(
+        (0..PAGES * STRIDE)
+            .map(|_| AtomicU64::new(0))
+            .collect::<Vec<_>>(),
+    );
+    let written = Arc::new(AtomicU64::new(0));
+    let stop = Arc::new(AtomicBool::new(false));
+    let writer = thread::spawn({
+        let (pages, written, stop) = (pages.clone(), written.clone(), stop.clone());
+        move || {
+            for (i, page) in pages.iter().step_by(STRIDE).enumerate() {
+                if stop.load(Ordering::Acquire) {
+                    break;
+                }
+                page.store(i as u64 + 1, Ordering::Relaxed);
+                written.store(i as u64 + 1, Ordering::Release);
+                thread::sleep(Duration::from_micros(50));
+            }
+        }
+    });
+
+    Box::new(move || {
+        stop.store(true, Ordering::Release);
+        writer.join().unwrap();
+        let written = written.load(Ordering::Acquire) as usize;
+        match pages.iter().step_by(STRIDE).enumerate().position(|(i, p)| {
+            p.load(Ordering::Relaxed) != if i < written { i as u64 + 1 } else { 0 }
+        }) {
+            Some(page) => Err(format!(
+                "page {page} of {written} written holds {}",
+                pages[page * STRIDE].load(Ordering::Relaxed)
+            )),
+            None => Ok(()),
+        }
+    })
+}
+
+fn fd_flags(fd: i32) -> (i32, i32) {
+    unsafe {
+        (
+            libc::fcntl(fd, libc::F_GETFL),
+            libc::fcntl(fd, libc::F_GETFD),
+        )
+    }
+}
+
+// a file read from the middle and a log appended to without close-on-exec,
+// the log is seeked back to its start which an append ignores
+fn files(trigger: &Path) -> Check {
+    let (data, log) = (
+        trigger.with_extension("data"),
+        trigger.with_extension("log"),
+    );
+    std::fs::write(&data, "hello world").unwrap();
+    let mut reader = std::fs::File::open(&data).unwrap();
+    reader.seek(SeekFrom::Start(6)).unwrap();
+    let mut writer = OpenOptions::new()
+        .append(true)
+        .create(true)
+        .truncate(false)
+        .open(&log)
+        .unwrap();
+    writer.write_all(b"first\n").unwrap();
+    unsafe { libc::fcntl(writer.as_raw_fd(), libc::F_SETFD, 0) };
+    let flags = (fd_flags(reader.as_raw_fd()), fd_flags(writer.as_raw_fd()));
+
+    Box::new(move || {
+        let now = (fd_flags(reader.as_raw_fd()), fd_flags(writer.as_raw_fd()));
+        if now != flags {
+            return Err(format!("flags changed from {flags:?} to {now:?}"));
+        }
+        let mut rest = String::new();
+        reader.read_to_string(&mut rest).unwrap();
+        if rest != "world" {
+            return Err(format!("read {rest:?} from the offset of the data file"));
+        }
+        writer.seek(SeekFrom::Start(0)).unwrap();
+        writer.write_all(b"second\n").unwrap();
+        let contents = std::fs::read_to_string(&log).unwrap();
+        std::fs::remove_file(&data).unwrap();
+        std::fs::remove_file(&log).unwrap();
+        match contents.as_str() {
+            "first\nsecond\n" => Ok(()),
+            _ => Err(format!("log holds {contents:?}")),
+        }
+    })
+}
+
+// a scratch file larger than a chunk of file data, unlinked while open twice
+// at different offsets
+fn deleted(trigger: &Path) -> Check {
+    const LEN: usize = 5 << 20;
+
+    let path = trigger.with_extension("scratch");
+    std::fs::write(&path, pattern(LEN, 13)).unwrap();
+    let mut reader = std::fs::File::open(&path).unwrap();
+    reader.seek(SeekFrom::Start(LEN as u64 - 8)).unwrap();
+    let writer = OpenOptions::new().write(true).open(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    Box::new(move || {
+        let mut rest = vec![];
+        reader.read_to_end(&mut rest).unwrap();
+        if rest != pattern(LEN, 13)[LEN - 8..] {
+            return Err(format!("read {rest:?} from the offset of the scratch file"));
+        }
+        let mut data = vec![0u8; LEN];
+        reader.read_exact_at(&mut data, 0).unwrap();
+        if data != pattern(LEN, 13) {
+            return Err("scratch file differs".to_string());
+        }
+        // both fds still refer to the one file, which has no name
+        writer.write_all_at(b"shared", 0).unwrap();
+        let mut buf = [0u8; 6];
+        reader.read_exact_at(&mut buf, 0).unwrap();
+        if &buf != b"shared" {
+            return Err(format!("read {buf:?} after writing the other fd"));
+        }
+        match reader.metadata().unwrap().nlink() {
+            0 => Ok(()),
+            n => Err(format!("scratch file has {n} links")),
+        }
+    })
+}
+
+// a tree synced with --path holding a directory, a file that is open and
+// mapped and a symlink to it. the data spans several delta blocks so a stale
+// copy at the destination has some of them in common
+fn synced(trigger: &Path) -> Check {
+    let tree = trigger.with_extension("tree");
+    let (dir, data, link) = (tree.join("dir"), tree.join("dir/data"), tree.join("link"));
+    let expected = (0..64 * 1024).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(&data, &expected).unwrap();
+    std::os::unix::fs::symlink("dir/data", &link).unwrap();
+    std::fs::set_permissions(&data, std::fs::Permissions::from_mode(0o640)).unwrap();
+    std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o750)).unwrap();
+    let file = std::fs::File::open(&data).unwrap();
+    file.set_modified(std::time::UNIX_EPOCH + Duration::from_secs(1 << 30))
+        .unwrap();
+    let mapped = unsafe {
+        let addr = libc::mmap(
+            std::ptr::null_mut(),
+            expected.len(),
+            libc::PROT_READ,
+            libc::MAP_SHARED,
+            file.as_raw_fd(),
+            0,
+        );
+        assert!(addr != libc::MAP_FAILED);
+        std::slice::from_raw_parts(addr as *const u8, expected.len())
+    };
+
+    Box::new(move || {
+        if mapped != expected {
+            return Err("mapping differs from the synced data".to_string());
+        }
+        let mut contents = vec![];
+        (&file).read_to_end(&mut contents).unwrap();
+        let target = std::fs::read_link(&link).unwrap();
+        let modes = [&dir, &data].map(|p| std::fs::metadata(p).unwrap().mode() & 0o7777);
+        let mtime = std::fs::metadata(&data).unwrap().mtime();
+        if contents != expected {
+            return Err(format!("read {} bytes of other data", contents.len()));
+        }
+        if target != Path::new("dir/data") {
+            return Err(format!("symlink points to {target:?}"));
+        }
+        if modes != [0o750, 0o640] {
+            return Err(format!(
+                "synced modes are {:o} and {:o}",
+                modes[0], modes[1]
+            ));
+        }
+        match mtime {
+            0x4000_0000 => Ok(()),
+            _ => Err(format!("synced file has mtime {mtime}")),
+        }
+    })
+}
+
+// bytes left in a pipe before the escape are read back after it, followed by
+// what a child writes to its end. the read ends once the child exits, which
+// relies on no stray write end being left open
+fn pipe(trigger: &Path) -> Check {
+    let mut fds = [0; 2];
+    assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+    let (mut read, write) = unsafe {
+        (
+            std::fs::File::from_raw_fd(fds[0]),
+            std::fs::File::from_raw_fd(fds[1]),
+        )
+    };
+    (&write).write_all(b"buffered ").unwrap();
+
+    let trigger = trigger.to_path_buf();
+    // the parent drops this closure and with it its write end
+    let child = fork(move || {
+        unsafe { libc::close(fds[0]) };
+        wait_for(&trigger);
+        (&write).write_all(b"from the child").is_ok()
+    });
+
+    Box::new(move || {
+        let mut data = String::new();
+        read.read_to_string(&mut data).unwrap();
+        wait_child(child)?;
+        match data.as_str() {
+            "buffered from the child" => Ok(()),
+            _ => Err(format!("read {data:?} from the pipe")),
+        }
+    })
+}
+
+// pairs with data queued in either direction, datagrams keep their
+// boundaries, and a listener still accepting under its name
+fn unix_sockets(trigger: &Path) -> Check {
+    let (mut a, mut b) = UnixStream::pair().unwrap();
+    a.write_all(b"queued").unwrap();
+    let (c, d) = UnixDatagram::pair().unwrap();
+    c.send(b"first").unwrap();
+    c.send(b"second").unwrap();
+    let name = trigger.with_extension("sock");
+    let _ = std::fs::remove_file(&name);
+    let listener = UnixListener::bind(&name).unwrap();
+
+    Box::new(move || {
+        let mut buf = [0u8; 16];
+        let n = b.read(&mut buf).unwrap();
+        if &buf[..n] != b"queued" {
+            return Err(format!("read {:?} from the stream pair", &buf[..n]));
+        }
+        b.write_all(b"reply").unwrap();
+        let n = a.read(&mut buf).unwrap();
+        if &buf[..n] != b"reply" {
+            return Err(format!("read {:?} back from the stream pair", &buf[..n]));
+        }
+        c.send(b"third").unwrap();
+        for expected in [&b"first"[..], b"second", b"third"] {
+            let n = d.recv(&mut buf).unwrap();
+            if &buf[..n] != expected {
+                return Err(format!("received {:?} from the datagram pair", &buf[..n]));
+            }
+        }
+
+        let mut client = UnixStream::connect(&name).map_err(|e| format!("connect: {e}"))?;
+        let (mut server, _) = listener.accept().unwrap();
+        client.write_all(b"hello").unwrap();
+        let n = server.read(&mut buf).unwrap();
+        std::fs::remove_file(&name).unwrap();
+        match &buf[..n] {
+            b"hello" => Ok(()),
+            data => Err(format!("listener accepted {data:?}")),
+        }
+    })
+}
+
+fn reuse_addr(fd: i32) -> i32 {
+    let (mut value, mut len) = (0i32, size_of::<i32>() as libc::socklen_t);
+    unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_REUSEADDR,
+            &mut value as *mut i32 as *mut _,
+            &mut len,
+        )
+    };
+    value
+}
+
+// a non-blocking tcp listener and a udp socket, both still reachable on
+// their ports
+fn listen() -> Check {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.set_nonblocking(true).unwrap();
+    let udp = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let (addr, udp_addr) = (listener.local_addr().unwrap(), udp.local_addr().unwrap());
+    let reuse = reuse_addr(listener.as_raw_fd());
+
+    Box::new(move || {
+        if reuse_addr(listener.as_raw_fd()) != reuse {
+            return Err("SO_REUSEADDR changed".to_string());
+        }
+        match listener.accept() {
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {}
+            res => return Err(format!("accept without a client gave {res:?}")),
+        }
+        let mut client = TcpStream::connect(addr).map_err(|e| format!("connect {addr}: {e}"))?;
+        client.write_all(b"hello").unwrap();
+        listener.set_nonblocking(false).unwrap();
+        let (mut server, _) = listener.accept().unwrap();
+        let mut buf = [0u8; 5];
+        server.read_exact(&mut buf).unwrap();
+        if &buf != b"hello" {
+            return Err(format!("listener accepted {buf:?}"));
+        }
+
+        let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+        sender.send_to(b"datagram", udp_addr).unwrap();
+        let mut buf = [0u8; 16];
+        let (n, _) = udp.recv_from(&mut buf).unwrap();
+        match &buf[..n] {
+            b"datagram" => Ok(()),
+            data => Err(format!("received {data:?} on {udp_addr}")),
+        }
+    })
+}
+
+fn read_u64(fd: i32) -> Result<u64, String> {
+    let mut value = 0u64;
+    match unsafe { libc::read(fd, &mut value as *mut u64 as *mut _, size_of::<u64>()) } {
+        8 => Ok(value),
+        n => Err(format!("read {n} bytes from {fd}")),
+    }
+}
+
+fn timer(clock: i32, value: Duration, interval: Duration) -> i32 {
+    let spec = |d: Duration| libc::timespec {
+        tv_sec: d.as_secs() as _,
+        tv_nsec: d.subsec_nanos() as _,
+    };
+    let timer = libc::itimerspec {
+        it_interval: spec(interval),
+        it_value: spec(value),
+    };
+    unsafe {
+        let fd = libc::timerfd_create(clock, libc::TFD_CLOEXEC);
+        assert!(fd >= 0 && libc::timerfd_settime(fd, 0, &timer, std::ptr::null_mut()) == 0);
+        fd
+    }
+}
+
+// an eventfd holding a count watched by epoll, a periodic and a one-shot
+// timer and a signalfd of a blocked signal
+fn anon() -> Check {
+    unsafe {
+        let eventfd = libc::eventfd(0, libc::EFD_CLOEXEC | libc::EFD_NONBLOCK);
+        assert_eq!(libc::write(eventfd, &5u64 as *const u64 as *const _, 8), 8);
+        let epoll = libc::epoll_create1(libc::EPOLL_CLOEXEC);
+        let mut event = libc::epoll_event {
+            events: libc::EPOLLIN as u32,
+            u64: 42,
+        };
+        assert_eq!(
+            libc::epoll_ctl(epoll, libc::EPOLL_CTL_ADD, eventfd, &mut event),
+            0
+        );
+
+        let periodic = timer(
+            libc::CLOCK_MONOTONIC,
+            Duration::from_millis(20),
+            Duration::from_millis(20),
+        );
+        let one_shot = timer(
+            libc::CLOCK_REALTIME,
+            Duration::from_secs(3600),
+            Duration::ZERO,
+        );
+
+        let mut mask = std::mem::zeroed::<libc::sigset_t>();
+        libc::sigemptyset(&mut mask);
+        libc::sigaddset(&mut mask, libc::SIGUSR2);
+        libc::pthread_sigmask(libc::SIG_BLOCK, &mask, std::ptr::null_mut());
+        let signalfd = libc::signalfd(-1, &mask, libc::SFD_CLOEXEC);
+
+        Box::new(move || {
+            let mut events = [libc::epoll_event { events: 0, u64: 0 }; 2];
+            match libc::epoll_wait(epoll, events.as_mut_ptr(), 2, 1000) {
+                1 if events[0].u64 == 42 => {}
+                n => return Err(format!("epoll reported {n} events")),
+            }
+            if read_u64(eventfd)? != 5 {
+                return Err("eventfd lost its count".to_string());
+            }
+            // blocks until the timer fires again
+            if read_u64(periodic)? == 0 {
+                return Err("periodic timer did not fire".to_string());
+            }
+            let mut left = std::mem::zeroed::<libc::itimerspec>();
+            libc::timerfd_gettime(one_shot, &mut left);
+            if left.it_value.tv_sec < 3000 || left.it_interval.tv_sec != 0 {
+                return Err(format!("one-shot timer has {}s left", left.it_value.tv_sec));
+            }
+
+            libc::raise(libc::SIGUSR2);
+            let mut info = std::mem::zeroed::<libc::signalfd_siginfo>();
+            let size = size_of::<libc::signalfd_siginfo>();
+            if libc::read(signalfd, &mut info as *mut _ as *mut _, size) != size as isize {
+                return Err("failed to read signalfd".to_string());
+            }
+            match info.ssi_signo as i32 {
+                libc::SIGUSR2 => Ok(()),
+                signo => Err(format!("signalfd reported signal {signo}")),
+            }
+        })
+    }
+}
+
+static HANDLED: AtomicU64 = AtomicU64::new(0);
+
+extern "C" fn handle(_: libc::c_int) {
+    HANDLED.fetch_add(1, Ordering::Relaxed);
+}
+
+// the head of the robust futex list libc keeps for the current thread
+fn robust_list() -> (usize, usize) {
+    let (mut head, mut len) = (0usize, 0usize);
+    unsafe { libc::syscall(libc::SYS_get_robust_list, 0, &mut head, &mut len) };
+    (head, len)
+}
+
+// the handler and flags of every standard signal
+fn dispositions() -> Vec<(usize, i32)> {
+    (1..32)
+        .map(|signal| unsafe {
+            let mut action: libc::sigaction = std::mem::zeroed();
+            libc::sigaction(signal, std::ptr::null(), &mut action);
+            (action.sa_sigaction, action.sa_flags)
+        })
+        .collect()
+}
+
+// a handled and an ignored signal, and the robust lists of two threads
+fn signals() -> Check {
+    unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = handle as *const () as usize;
+        action.sa_flags = libc::SA_RESTART;
+        libc::sigaction(libc::SIGUSR1, &action, std::ptr::null_mut());
+        libc::signal(libc::SIGUSR2, libc::SIG_IGN);
+    }
+    let actions = dispositions();
+    let before = robust_list();
+    let go = Arc::new(AtomicBool::new(false));
+    let thread = thread::spawn({
+        let go = go.clone();
+        move || {
+            let before = robust_list();
+            while !go.load(Ordering::Acquire) {
+                thread::sleep(Duration::from_millis(10));
+            }
+            (before, robust_list())
+        }
+    });
+
+    Box::new(move || {
+        go.store(true, Ordering::Release);
+        let (thread_before, thread_after) = thread.join().unwrap();
+        if before.0 == 0 || robust_list() != before || thread_after != thread_before {
+            return Err(format!(
+                "robust lists changed from {before:x?} and {thread_before:x?} to {:x?} and {thread_after:x?}",
+                robust_list()
+            ));
+        }
+        if dispositions() != actions {
+            return Err(format!(
+                "signal dispositions changed from {actions:x?} to {:x?}",
+                dispositions()
+            ));
+        }
+        // either kills us unless its disposition survived
+        unsafe {
+            libc::raise(libc::SIGUSR1);
+            libc::raise(libc::SIGUSR2);
+        }
+        if HANDLED.load(Ordering::Relaxed) != 1 {
+            return Err("SIGUSR1 was not handled".to_string());
+        }
+        Ok(())
+    })
+}
+
+// a memfd larger than a chunk of file data, open and mapped shared
+fn memfd() -> Check {
+    const LEN: usize = 9 << 20;
+
+    let mut file = unsafe {
+        let fd = libc::memfd_create(c"escapee".as_ptr(), libc::MFD_CLOEXEC);
+        assert!(fd >= 0);
+        std::fs::File::from_raw_fd(fd)
+    };
+    file.write_all(&pattern(LEN, 11)).unwrap();
+    let mapped = unsafe {
+        let addr = libc::mmap(
+            std::ptr::null_mut(),
+            LEN,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_SHARED,
+            file.as_raw_fd(),
+            0,
+        );
+        assert!(addr != libc::MAP_FAILED);
+        std::slice::from_raw_parts_mut(addr as *mut u8, LEN)
+    };
+
+    Box::new(move || {
+        if mapped[..] != pattern(LEN, 11) {
+            return Err("mapping differs".to_string());
+        }
+        // writes through either are seen by the other
+        mapped[..5].copy_from_slice(b"first");
+        let mut buf = [0u8; 5];
+        file.read_exact_at(&mut buf, 0).map_err(|e| e.to_string())?;
+        if &buf != b"first" {
+            return Err(format!("memfd holds {buf:?} after writing the mapping"));
+        }
+        file.write_all_at(b"second", (LEN - 6) as u64)
+            .map_err(|e| e.to_string())?;
+        match &mapped[LEN - 6..] {
+            b"second" => Ok(()),
+            data => Err(format!("mapping holds {data:?} after writing the memfd")),
+        }
+    })
+}
@@ -1,15 +1,105 @@
-use std::process;
+use std::{
+    env, fs,
+    io::{BufRead, BufReader, Write},
+    net::TcpListener,
+    path::PathBuf,
+    process, thread,
+    time::{Duration, Instant},
+};
 
 use escapepod_common::nix::sys::signal::Signal;
-use escapepod_tests::util::{escapepod_bin, spawn, wait_for_output};
+use escapepod_tests::util::{escapepod_bin, spawn, wait_for_output, ChildWithStreamedOutput};
+
+// a scenario of the escapee binary, escaped to a destination on this host
+struct Escapee {
+    origin: ChildWithStreamedOutput,
+    trigger: PathBuf,
+}
+
+impl Escapee {
+    // returns once the escaped processes have been resumed at the destination
+    fn escape(origin_args: &[&str], scenario: &str, args: &[&str]) -> Self {
+        Self::escape_launched(
+            |destination| format!("{destination} &"),
+            origin_args,
+            scenario,
+            args,
+        )
+    }
+
+    // like escape, with launch turning the command running the destination
+    // into the launch pod command
+    fn escape_launched(
+        launch: impl Fn(&str) -> String,
+        origin_args: &[&str],
+        scenario: &str,
+        args: &[&str],
+    ) -> Self {
+        let trigger = env::temp_dir().join(format!("escapee-{scenario}-{}", process::id()));
+        let _ = fs::remove_file(&trigger);
+
+        let mut origin = spawn(
+            process::Command::new(escapepod_bin())
+                .args(["--signal", "SIGUSR1"])
+                .args(["--launch-pod-command"])
+                .arg(launch(&format!(
+                    "{} --launch-pod-command test -- test",
+                    escapepod_bin()
+                )))
+                .args(origin_args)
+                .args(["--", env!("CARGO_BIN_EXE_escapee"), scenario])
+                .arg(&trigger)
+                .args(args),
+        );
+
+        Self::wait_for(&origin, &["escapee ready"]);
+        origin.signal(Signal::SIGUSR1);
+        Self::wait_for(&origin, &["resumed restored processes"]);
+
+        Self { origin, trigger }
+    }
+
+    // has the escapee check its state and panics unless it survived
+    fn check(mut self) {
+        fs::write(&self.trigger, "").unwrap();
+        let line = Self::wait_for(&self.origin, &["escapee passed", "escapee failed"]);
+        fs::remove_file(&self.trigger).unwrap();
+        if line.starts_with("escapee failed") {
+            panic!("{line}");
+        }
+
+        self.origin.proc.wait().unwrap();
+    }
+
+    // the first line of output containing any of contents, unlike
+    // wait_for_output this fails a broken escape instead of hanging
+    fn wait_for(origin: &ChildWithStreamedOutput, contents: &[&str]) -> String {
+        let deadline = Instant::now() + Duration::from_secs(60);
+        while Instant::now() < deadline {
+            for out in [&origin.stdout, &origin.stderr] {
+                let out = out.lock().unwrap();
+                if let Some(line) = out.lines().find(|l| contents.iter().any(|c| l.contains(c))) {
+                    return line.to_string();
+                }
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        panic!("timed out waiting for {contents:?}");
+    }
+}
 
 #[test]
+#[allow(clippy::needless_borrows_for_generic_args)]
 fn escape_hello_world_binary() {
     let mut origin = spawn(
         process::Command::new(escapepod_bin())
             .args(&["--signal", "SIGUSR1"])
             .args(&["--launch-pod-command"])
-            .arg(format!("ESCAPEE_ADDR=localhost:$ESCAPEE_PORT {} --launch-pod-command test --port 0 -- test &", escapepod_bin()))
+            .arg(format!(
+                "{} --launch-pod-command test -- test &",
+                escapepod_bin()
+            ))
             .args(&["--port", "0"])
             .args(&["--", "sleep", "infinity"]),
     );
@@ -20,3 +110,157 @@ fn escape_hello_world_binary() {
 
     origin.proc.wait().unwrap();
 }
+
+#[test]
+fn escape_tcp_connection() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+
+    let mut origin = spawn(
+        process::Command::new(escapepod_bin())
+            .args(["--signal", "SIGUSR1"])
+            .args(["--launch-pod-command"])
+            .arg(format!(
+                "{} --launch-pod-command test -- test &",
+                escapepod_bin()
+            ))
+            .args(["--port", "0"])
+            .args(["--", "bash", "-c"])
+            .arg(format!(
+                "exec 3<>/dev/tcp/127.0.0.1/{port}; read -r line <&3; echo \"echo $line\" >&3"
+            )),
+    );
+
+    let (mut conn, _) = listener.accept().unwrap();
+    wait_for_output(&origin, "waiting for signal");
+
+    origin.signal(Signal::SIGUSR1);
+
+    // the connection is only written to once it lives at the destination
+    wait_for_output(&origin, "resumed restored processes");
+    conn.write_all(b"hello\n").unwrap();
+    let mut reply = String::new();
+    BufReader::new(conn).read_line(&mut reply).unwrap();
+    assert_eq!(reply, "echo hello\n");
+
+    origin.proc.wait().unwrap();
+}
+
+#[test]
+fn escape_over_unix_socket() {
+    let socket = env::temp_dir().join(format!("escapepod-{}.sock", process::id()));
+    let listen = format!("unix://{}", socket.display());
+    Escapee::escape(&["--listen", &listen], "pids", &[]).check();
+    assert!(!socket.exists());
+}
+
+#[test]
+fn escape_threads() {
+    Escapee::escape(&[], "threads", &[]).check();
+}
+
+#[test]
+fn escape_keeps_pids() {
+    Escapee::escape(&[], "pids", &[]).check();
+}
+
+#[test]
+fn escape_fork_tree() {
+    Escapee::escape(&[], "fork_tree", &[]).check();
+}
+
+// the state of the process trees is far too big for the environment
+#[test]
+fn escape_large_process() {
+    Escapee::escape(&[], "threads", &["200"]).check();
+}
+
+// the tids take more than one ready report
+#[test]
+fn escape_many_threads() {
+    Escapee::escape(&[], "threads", &["1500"]).check();
+}
+
+#[test]
+fn escape_memory() {
+    Escapee::escape(&[], "memory", &[]).check();
+}
+
+#[test]
+fn escape_vdso() {
+    Escapee::escape(&[], "vdso", &[]).check();
+}
+
+#[test]
+fn escape_dirty_memory() {
+    Escapee::escape(&["--pre-copy-rounds", "3"], "dirty", &[]).check();
+}
+
+#[test]
+fn escape_memory_written_once() {
+    Escapee::escape(&["--pre-copy-rounds", "3"], "dirty_once", &[]).check();
+}
+
+#[test]
+fn escape_files() {
+    Escapee::escape(&[], "files", &[]).check();
+}
+
+#[test]
+fn escape_deleted_file() {
+    Escapee::escape(&[], "deleted", &[]).check();
+}
+
+#[test]
+fn escape_synced_files() {
+    let tree = env::temp_dir().join(format!("escapee-synced-{}.tree", process::id()));
+    let _ = fs::remove_dir_all(&tree);
+
+    // the destination gets a tree of its own in a mount namespace, holding a
+    // stale copy of the data with a different tail and a symlink pointing
+    // elsewhere, so the data travels as a delta and the rest is replaced
+    let tree = tree.to_str().unwrap();
+    let launch = |destination: &str| {
+        format!(
+            "unshare -m sh -c 'cp {tree}/dir/data {tree}.basis \
+                && mount -t tmpfs tmpfs {tree} \
+                && mkdir {tree}/dir \
+                && mv {tree}.basis {tree}/dir/data \
+                && echo stale >> {tree}/dir/data \
+                && ln -s dir/stale {tree}/link \
+                && {{ {destination} & }}'"
+        )
+    };
+    Escapee::escape_launched(launch, &["--path", tree], "synced", &[]).check();
+    fs::remove_dir_all(tree).unwrap();
+}
+
+#[test]
+fn escape_pipe() {
+    Escapee::escape(&[], "pipe", &[]).check();
+}
+
+#[test]
+fn escape_unix_sockets() {
+    Escapee::escape(&[], "unix_sockets", &[]).check();
+}
+
+#[test]
+fn escape_listening_sockets() {
+    Escapee::escape(&[], "listen", &[]).check();
+}
+
+#[test]
+fn escape_anon_inodes() {
+    Escapee::escape(&[], "anon", &[]).check();
+}
+
+#[test]
+fn escape_memfd() {
+    Escapee::escape(&[], "memfd", &[]).check();
+}
+
+#[test]
+fn escape_signal_dispositions() {
+    Escapee::escape(&[], "signals", &[]).check();
+}
@@ -15,6 +15,7 @@ fn it_boots() {
 }
 
 #[test]
+#[allow(clippy::needless_borrows_for_generic_args)]
 fn it_exits_with_code_from_child_proc() {
     let code = spawn(
         process::Command::new(escapepod_bin())
@@ -31,6 +32,10 @@ fn it_exits_with_code_from_child_proc() {
 }
 
 #[test]
+#[allow(
+    clippy::needless_borrows_for_generic_args,
+    clippy::unnecessary_mut_passed
+)]
 fn it_forwards_other_signals_to_child_proc() {
     let mut proc = spawn(
         process::Command::new(escapepod_bin())
@@ -1,7 +1,7 @@
 use std::path::PathBuf;
 
 use clap::Parser;
-use escapepod_common::nix::sys::signal::Signal;
+use escapepod_common::{compression::Preference, nix::sys::signal::Signal, transport::Address};
 
 #[derive(Parser, Debug, Clone)]
 #[command(author, version, about, long_about = None)]
@@ -9,15 +9,29 @@ pub struct Args {
     /// signals to escape on
     #[arg(long)]
     pub signal: Vec<Signal>,
-    /// port to listen for pods on
     /// command to run when escape signal is received
     #[arg(long)]
     pub launch_pod_command: String,
-    #[arg(long)]
+    /// port to listen for pods on
+    #[arg(long, default_value_t = 0)]
     pub port: u16,
+    /// address to listen for pods on instead, tcp://host:port or
+    /// unix:///path. the launch pod command finds where to connect in
+    /// ESCAPEE_ADDR
+    #[arg(long, conflicts_with = "port")]
+    pub listen: Option<Address>,
     /// sync files under path
     #[arg(long)]
     pub path: Vec<PathBuf>,
+    /// rounds of copying memory while the processes keep running before they
+    /// are stopped for the final copy, needs soft-dirty tracking in the kernel
+    #[arg(long, default_value_t = 0)]
+    pub pre_copy_rounds: u32,
+    /// compress memory and file data for speed or ratio, or leave it off. on
+    /// by default as heaps compress well and the link is what usually sets
+    /// the downtime, data that does not shrink is sent as it is
+    #[arg(long, default_value_t = Preference::Ratio)]
+    pub compression: Preference,
     /// child command to exec
     pub exec: Vec<String>,
 }
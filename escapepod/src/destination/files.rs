@@ -0,0 +1,525 @@
+use std::{
+    collections::HashMap,
+    env,
+    ffi::{CString, OsString},
+    fs::{self, DirBuilder, OpenOptions},
+    io::{BufReader, Read, Seek, Write},
+    os::{
+        fd::AsRawFd,
+        unix::{
+            ffi::OsStrExt,
+            fs::{
+                lchown, symlink, DirBuilderExt, FileExt, MetadataExt, OpenOptionsExt,
+                PermissionsExt,
+            },
+        },
+    },
+    path::{Path, PathBuf},
+};
+
+use escapepod_common::{
+    anyhow::{bail, Context, Result},
+    delta::{self, Checksum},
+    libc,
+    nix::{
+        errno::Errno,
+        sys::stat::{fchmod, Mode},
+        unistd::{fchown, Gid, Uid},
+    },
+    proto::{
+        BlockSignature, File, FileBlocks, FileData, FileId, FileRequest, FileType, Hash, FILE_CHUNK,
+    },
+    sha2::{Digest, Sha256},
+    tracing::debug,
+};
+
+// blocks the destination already has are copied in pieces of at most this size
+const COPY_CHUNK: usize = 1024 * 1024;
+
+// the files sent by the origin. regular files which differ are written next
+// to where they go and moved in place once complete, which leaves them intact
+// should the origin be reading them on the same host. metadata is applied
+// last as writing a file changes its directory and modes may not let us write
+// at all. the origin syncs once while its processes run and again once they
+// are frozen, each manifest completes the files of the one before
+#[derive(Default)]
+pub(crate) struct Files {
+    synced: Vec<File>,
+    partial: HashMap<FileId, Partial>,
+    deleted: HashMap<FileId, fs::File>,
+    // the memfds receiving their data, which are owned by the caller
+    memfds: HashMap<FileId, fs::File>,
+}
+
+struct Partial {
+    file: fs::File,
+    path: PathBuf,
+    hasher: Sha256,
+    hash: Hash,
+    // the copy we already have and the size of its blocks
+    basis: Option<(fs::File, u64)>,
+}
+
+impl Partial {
+    fn write(&mut self, data: &[u8]) -> Result<()> {
+        self.hasher.update(data);
+        Ok(self.file.write_all(data)?)
+    }
+}
+
+impl Files {
+    // creates the files of the manifest and returns those we need the
+    // contents of
+    pub(crate) fn manifest(&mut self, files: Vec<File>) -> Result<Vec<FileRequest>> {
+        self.complete(false)?;
+        let mut requests = vec![];
+        for file in files {
+            let path = file.path.clone();
+            if let Some(request) = self
+                .create(file)
+                .with_context(|| format!("failed to create {path:?}"))?
+            {
+                requests.push(request);
+            }
+        }
+        debug!(
+            "requesting {} of {} files",
+            requests.len(),
+            self.synced.len()
+        );
+
+        Ok(requests)
+    }
+
+    fn create(&mut self, file: File) -> Result<Option<FileRequest>> {
+        let path = file.path.as_path();
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        // whatever is in the way is replaced, unless it is a directory
+        let existing = fs::symlink_metadata(path).ok().map(|s| s.file_type());
+        match (&file.r#type, existing) {
+            (FileType::Directory, Some(t)) if t.is_dir() => {}
+            (_, Some(t)) if t.is_dir() => bail!("{path:?} is a directory here"),
+            (FileType::Regular { .. }, Some(_)) => {}
+            (_, Some(_)) => fs::remove_file(path)?,
+            (_, None) => {}
+        }
+
+        let mut request = None;
+        match &file.r#type {
+            FileType::Regular { size, hash } => {
+                let basis = match existing {
+                    Some(t) if t.is_file() => Some(fs::File::open(path)?),
+                    _ => None,
+                };
+                // one with the size and mtime of the origin's is taken to be
+                // the same without hashing it, as any synced before would be
+                if let Some(basis) = &basis {
+                    let stat = basis.metadata()?;
+                    let mtime = (stat.mtime(), stat.mtime_nsec());
+                    if stat.len() == *size
+                        && (mtime == (file.mtime, file.mtime_nsec)
+                            || delta::hash_reader(basis)? == *hash)
+                    {
+                        self.synced.push(file);
+                        return Ok(None);
+                    }
+                }
+
+                let mut name = OsString::from(".");
+                name.push(path.file_name().context("file without a name")?);
+                name.push(".escapepod");
+                let partial = path.with_file_name(name);
+                let data = OpenOptions::new()
+                    .write(true)
+                    .create(true)
+                    .truncate(true)
+                    .mode(0o600)
+                    .open(&partial)?;
+
+                let basis = match basis {
+                    Some(basis) => {
+                        let block_size = delta::block_size(basis.metadata()?.len());
+                        Some((basis, block_size))
+                    }
+                    None => None,
+                };
+                request = Some(FileRequest {
+                    id: file.id,
+                    block_size: basis.as_ref().map(|(_, s)| *s).unwrap_or(0),
+                    blocks: match &basis {
+                        Some((basis, block_size)) => signatures(basis, *block_size)?,
+                        None => vec![],
+                    },
+                });
+                self.partial.insert(
+                    file.id,
+                    Partial {
+                        file: data,
+                        path: partial,
+                        hasher: Sha256::new(),
+                        hash: *hash,
+                        basis,
+                    },
+                );
+            }
+            FileType::Directory if existing.is_none() => {
+                DirBuilder::new().mode(0o700).create(path)?
+            }
+            FileType::Directory => {}
+            FileType::Symlink(target) => symlink(target, path)?,
+            FileType::Deleted => bail!("deleted file {} in the manifest", file.id),
+        }
+        self.synced.push(file);
+
+        Ok(request)
+    }
+
+    pub(crate) fn create_deleted(&mut self, file: File) -> Result<()> {
+        self.deleted.insert(file.id, create_deleted_file(&file)?);
+
+        Ok(())
+    }
+
+    // the data of id is written to file like that of a deleted file
+    pub(crate) fn receive_memfd(&mut self, id: FileId, file: &fs::File) -> Result<()> {
+        self.memfds.insert(id, file.try_clone()?);
+
+        Ok(())
+    }
+
+    pub(crate) fn write(&mut self, data: &FileData) -> Result<()> {
+        let id = data.id;
+        let data = data.data.decompress(FILE_CHUNK)?;
+        if let Some(file) = self.deleted.get_mut(&id).or(self.memfds.get_mut(&id)) {
+            return Ok(file.write_all(&data)?);
+        }
+        let Some(partial) = self.partial.get_mut(&id) else {
+            bail!("unexpected data of file {id}");
+        };
+
+        partial.write(&data)
+    }
+
+    pub(crate) fn copy_blocks(&mut self, blocks: &FileBlocks) -> Result<()> {
+        let Some(partial) = self.partial.get_mut(&blocks.id) else {
+            bail!("unexpected blocks of file {}", blocks.id);
+        };
+        let Partial {
+            file,
+            hasher,
+            basis: Some((basis, block_size)),
+            ..
+        } = partial
+        else {
+            bail!("unexpected blocks of file {} which is new", blocks.id);
+        };
+
+        // the last block may be short
+        let len = basis.metadata()?.len();
+        let mut offset = blocks.index * *block_size;
+        let end = ((blocks.index + blocks.count) * *block_size).min(len);
+        let mut buf = vec![0u8; (end.saturating_sub(offset) as usize).min(COPY_CHUNK)];
+        while offset < end {
+            let buf = &mut buf[..((end - offset) as usize).min(COPY_CHUNK)];
+            basis.read_exact_at(buf, offset)?;
+            hasher.update(&buf);
+            file.write_all(buf)?;
+            offset += buf.len() as u64;
+        }
+
+        Ok(())
+    }
+
+    // returns the deleted files, which the restore stubs reopen
+    pub(crate) fn finish(mut self) -> Result<HashMap<FileId, fs::File>> {
+        self.complete(true)?;
+
+        Ok(self.deleted)
+    }
+
+    // moves the transferred files in place and applies the metadata of the
+    // manifest. a file which changed at the origin while it was sent is only
+    // an error once the processes are frozen, before that it is left out and
+    // sent again
+    fn complete(&mut self, frozen: bool) -> Result<()> {
+        let mut synced = vec![];
+        for file in self.synced.drain(..) {
+            if let Some(partial) = self.partial.remove(&file.id) {
+                if <[u8; 32]>::from(partial.hasher.finalize()) != partial.hash {
+                    if frozen {
+                        bail!("{:?} differs from the origin once transferred", file.path);
+                    }
+                    debug!("{:?} changed while it was transferred", file.path);
+                    fs::remove_file(partial.path)?;
+                    continue;
+                }
+                fs::rename(partial.path, &file.path)?;
+            }
+            synced.push(file);
+        }
+        // children first so the times of their directories are not touched
+        for file in synced.iter().rev() {
+            let path = file.path.as_path();
+            lchown(path, Some(file.uid), Some(file.gid))?;
+            if !matches!(file.r#type, FileType::Symlink(_)) {
+                fs::set_permissions(path, fs::Permissions::from_mode(file.mode & 0o7777))?;
+            }
+            set_mtime(path, file.mtime, file.mtime_nsec)
+                .with_context(|| format!("failed to set the mtime of {path:?}"))?;
+        }
+        if !synced.is_empty() {
+            debug!("synced {} files", synced.len());
+        }
+
+        Ok(())
+    }
+}
+
+fn signatures(basis: &fs::File, block_size: u64) -> Result<Vec<BlockSignature>> {
+    // the basis may have been read through when it was hashed
+    let mut reader = BufReader::new(basis);
+    reader.rewind()?;
+    let mut block = vec![0u8; block_size as usize];
+    let mut blocks = vec![];
+    loop {
+        let len = read_block(&mut reader, &mut block)?;
+        if len == 0 {
+            return Ok(blocks);
+        }
+        blocks.push(BlockSignature {
+            weak: Checksum::new(&block[..len]).value(),
+            strong: delta::hash(&block[..len]),
+        });
+    }
+}
+
+// fills block unless the end of the file comes first
+fn read_block(reader: &mut impl Read, block: &mut [u8]) -> Result<usize> {
+    let mut len = 0;
+    while len < block.len() {
+        match reader.read(&mut block[len..])? {
+            0 => break,
+            n => len += n,
+        }
+    }
+
+    Ok(len)
+}
+
+fn set_mtime(path: &Path, sec: i64, nsec: i64) -> Result<()> {
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    let times = [
+        libc::timespec {
+            tv_sec: 0,
+            tv_nsec: libc::UTIME_OMIT,
+        },
+        libc::timespec {
+            tv_sec: sec,
+            tv_nsec: nsec,
+        },
+    ];
+    let res = unsafe {
+        libc::utimensat(
+            libc::AT_FDCWD,
+            path.as_ptr(),
+            times.as_ptr(),
+            libc::AT_SYMLINK_NOFOLLOW,
+        )
+    };
+    Errno::result(res)?;
+
+    Ok(())
+}
+
+// an anonymous file taking the place of a deleted one, next to where it was
+// if that directory exists here
+fn create_deleted_file(file: &File) -> Result<fs::File> {
+    let create = |dir: &Path| {
+        OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_TMPFILE | libc::O_CLOEXEC)
+            .mode(0o600)
+            .open(dir)
+    };
+    let dir = file.path.parent().unwrap_or(Path::new("/"));
+    let tmp = match create(dir) {
+        Ok(tmp) => tmp,
+        Err(e) => {
+            debug!(
+                "failed to create deleted file {:?} in {dir:?}: {e}",
+                file.path
+            );
+            create(&env::temp_dir())
+                .with_context(|| format!("failed to create deleted file {:?}", file.path))?
+        }
+    };
+    fchown(
+        tmp.as_raw_fd(),
+        Some(Uid::from_raw(file.uid)),
+        Some(Gid::from_raw(file.gid)),
+    )?;
+    fchmod(tmp.as_raw_fd(), Mode::from_bits_truncate(file.mode))?;
+    debug!("created deleted file {} ({:?})", file.id, file.path);
+
+    Ok(tmp)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process;
+
+    use escapepod_common::{nix::unistd::getuid, proto::Payload};
+
+    use super::*;
+
+    fn file(id: FileId, path: PathBuf, mode: u32, r#type: FileType) -> File {
+        File {
+            id,
+            uid: getuid().as_raw(),
+            gid: Gid::current().as_raw(),
+            mode,
+            mtime: 1 << 30,
+            mtime_nsec: 0,
+            path,
+            r#type,
+        }
+    }
+
+    #[test]
+    fn test_files_sync() {
+        let dir = env::temp_dir().join(format!("escapepod-files-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let (new, changed, same, link) = (
+            dir.join("sub/new"),
+            dir.join("changed"),
+            dir.join("same"),
+            dir.join("link"),
+        );
+        fs::create_dir_all(&dir).unwrap();
+        let data = (0..64 * 1024u32).map(|i| i as u8).collect::<Vec<_>>();
+        let mut old = data.clone();
+        old[1000] ^= 1;
+        fs::write(&changed, &old).unwrap();
+        fs::write(&same, "same").unwrap();
+
+        let regular = |data: &[u8]| FileType::Regular {
+            size: data.len() as u64,
+            hash: delta::hash(data),
+        };
+        let mut files = Files::default();
+        let requests = files
+            .manifest(vec![
+                file(0, dir.join("sub"), 0o750, FileType::Directory),
+                file(1, new.clone(), 0o640, regular(b"new")),
+                file(2, changed.clone(), 0o600, regular(&data)),
+                file(3, same.clone(), 0o644, regular(b"same")),
+                file(4, link.clone(), 0o777, FileType::Symlink("same".into())),
+            ])
+            .unwrap();
+        // the new file is requested whole, the changed one by its blocks
+        let ids = requests.iter().map(|r| r.id).collect::<Vec<_>>();
+        assert_eq!(ids, [1, 2]);
+        assert!(requests[0].blocks.is_empty());
+        let block_size = requests[1].block_size as usize;
+        assert_eq!(requests[1].blocks.len(), data.len().div_ceil(block_size));
+
+        files
+            .write(&FileData {
+                id: 1,
+                data: Payload::raw(b"new".to_vec()),
+            })
+            .unwrap();
+        // every block but the one holding the change is copied
+        let changed_block = 1000 / block_size;
+        files
+            .copy_blocks(&FileBlocks {
+                id: 2,
+                index: 0,
+                count: changed_block as u64,
+            })
+            .unwrap();
+        let end = ((changed_block + 1) * block_size).min(data.len());
+        files
+            .write(&FileData {
+                id: 2,
+                data: Payload::raw(data[changed_block * block_size..end].to_vec()),
+            })
+            .unwrap();
+        let blocks = requests[1].blocks.len();
+        files
+            .copy_blocks(&FileBlocks {
+                id: 2,
+                index: changed_block as u64 + 1,
+                count: (blocks - changed_block - 1) as u64,
+            })
+            .unwrap();
+        assert!(files.finish().unwrap().is_empty());
+
+        assert_eq!(fs::read(&new).unwrap(), b"new");
+        assert_eq!(fs::read(&changed).unwrap(), data);
+        assert_eq!(fs::read_link(&link).unwrap(), Path::new("same"));
+        for (path, mode) in [(dir.join("sub"), 0o750), (new, 0o640), (same, 0o644)] {
+            let stat = fs::metadata(&path).unwrap();
+            assert_eq!(stat.mode() & 0o7777, mode, "{path:?}");
+            assert_eq!(stat.mtime(), 1 << 30, "{path:?}");
+        }
+        // nothing is left next to the synced files
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 4);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_files_sync_twice() {
+        let dir = env::temp_dir().join(format!("escapepod-files-twice-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let (changing, stale) = (dir.join("changing"), dir.join("stale"));
+        // differs from the manifest but has its size and mtime
+        fs::write(&stale, "old").unwrap();
+        set_mtime(&stale, 1 << 30, 0).unwrap();
+
+        let regular = |data: &[u8]| FileType::Regular {
+            size: data.len() as u64,
+            hash: delta::hash(data),
+        };
+        let mut files = Files::default();
+        let requests = files
+            .manifest(vec![
+                file(0, changing.clone(), 0o600, regular(b"before")),
+                file(1, stale.clone(), 0o600, regular(b"new")),
+            ])
+            .unwrap();
+        assert_eq!(requests.iter().map(|r| r.id).collect::<Vec<_>>(), [0]);
+        // the file changed at the origin while it was sent
+        files
+            .write(&FileData {
+                id: 0,
+                data: Payload::raw(b"after".to_vec()),
+            })
+            .unwrap();
+
+        // which is left out until it is sent again
+        let requests = files
+            .manifest(vec![file(2, changing.clone(), 0o600, regular(b"after"))])
+            .unwrap();
+        assert_eq!(requests.iter().map(|r| r.id).collect::<Vec<_>>(), [2]);
+        assert!(!changing.exists());
+        files
+            .write(&FileData {
+                id: 2,
+                data: Payload::raw(b"after".to_vec()),
+            })
+            .unwrap();
+        files.finish().unwrap();
+
+        assert_eq!(fs::read(&changing).unwrap(), b"after");
+        assert_eq!(fs::read(&stale).unwrap(), b"old");
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,210 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+};
+
+use escapepod_common::{
+    anyhow::{bail, Context, Result},
+    libc::{self, c_int},
+    nix::{
+        errno::Errno,
+        sys::socket::{
+            bind as nix_bind, connect, listen, send, socket, AddressFamily, MsgFlags, SockFlag,
+            SockType, SockaddrStorage,
+        },
+    },
+    proto::{FdType, Process, SocketIpBind, SocketIpState, TcpConnection},
+    tracing::debug,
+};
+
+use crate::sockopt;
+
+const TCP_RECV_QUEUE: c_int = 1;
+const TCP_SEND_QUEUE: c_int = 2;
+const TCPOPT_MSS: u32 = 2;
+const TCPOPT_WINDOW: u32 = 3;
+const TCPOPT_SACK_PERM: u32 = 4;
+const TCPOPT_TIMESTAMP: u32 = 8;
+const TCPI_OPT_TIMESTAMPS: u8 = 1;
+const TCPI_OPT_SACK: u8 = 2;
+const TCPI_OPT_WSCALE: u8 = 4;
+
+// the queues are refilled in chunks the kernel is willing to allocate at once
+const QUEUE_CHUNK: usize = 64 * 1024;
+
+// the ip sockets of the trees. they are only bound once the origin processes
+// are gone, which matters when both ends share a host
+pub(crate) fn create_ip_sockets(
+    procs: &[Process],
+) -> Result<HashMap<u64, (OwnedFd, SocketIpState)>> {
+    let mut sockets = HashMap::new();
+    for proc in procs.iter().flat_map(|i| i.self_and_descendents()) {
+        for fd in proc.fd_table.iter() {
+            let FdType::SocketIp(s) = &fd.r#type else {
+                continue;
+            };
+            if sockets.contains_key(&s.inode) {
+                continue;
+            }
+
+            let (addr, r#type) = match &s.state {
+                SocketIpState::Bind(b) => (b.addr, SockType::try_from(b.r#type)?),
+                SocketIpState::Connect { local, .. } => (*local, SockType::Stream),
+            };
+            let family = match addr {
+                SocketAddr::V4(_) => AddressFamily::Inet,
+                SocketAddr::V6(_) => AddressFamily::Inet6,
+            };
+            let sock = socket(family, r#type, SockFlag::SOCK_CLOEXEC, None)?;
+            let sock = unsafe { OwnedFd::from_raw_fd(sock) };
+
+            sockets.insert(s.inode, (sock, s.state.clone()));
+        }
+    }
+
+    Ok(sockets)
+}
+
+pub(crate) fn bind_socket(sock: &OwnedFd, bind: &SocketIpBind) -> Result<()> {
+    let sock = sock.as_raw_fd();
+    let flag = |b| b as c_int;
+    sockopt::set(
+        sock,
+        libc::SOL_SOCKET,
+        libc::SO_REUSEADDR,
+        &flag(bind.reuse_addr),
+    )?;
+    sockopt::set(
+        sock,
+        libc::SOL_SOCKET,
+        libc::SO_REUSEPORT,
+        &flag(bind.reuse_port),
+    )?;
+    if bind.addr.is_ipv6() {
+        sockopt::set(
+            sock,
+            libc::IPPROTO_IPV6,
+            libc::IPV6_V6ONLY,
+            &flag(bind.v6_only),
+        )?;
+    }
+
+    // the port of a socket which was never bound is left to the kernel
+    if bind.addr.port() != 0 {
+        match nix_bind(sock, &SockaddrStorage::from(bind.addr)) {
+            Ok(()) => {}
+            Err(Errno::EADDRINUSE) => bail!("{} is already in use here", bind.addr),
+            Err(e) => return Err(e).with_context(|| format!("failed to bind {}", bind.addr)),
+        }
+    }
+    if let Some(backlog) = bind.backlog {
+        listen(sock, backlog as _)?;
+    }
+    debug!("restored socket bound to {}", bind.addr);
+
+    Ok(())
+}
+
+// brings the connection back to where it was at the origin, the peer hears
+// from us again once repair mode is left
+pub(crate) fn repair_tcp_connection(
+    sock: &OwnedFd,
+    local: SocketAddr,
+    peer: SocketAddr,
+    conn: &TcpConnection,
+) -> Result<()> {
+    let sock = sock.as_raw_fd();
+    sockopt::set(sock, libc::IPPROTO_TCP, libc::TCP_REPAIR, &1)
+        .context("failed to enter repair mode, this requires CAP_NET_ADMIN")?;
+    let res = repair(sock, local, peer, conn);
+    // a half repaired connection is reset rather than silently dropped
+    if res.is_err() {
+        let _ = sockopt::set(sock, libc::IPPROTO_TCP, libc::TCP_REPAIR, &0);
+    }
+
+    res
+}
+
+fn repair(sock: RawFd, local: SocketAddr, peer: SocketAddr, conn: &TcpConnection) -> Result<()> {
+    nix_bind(sock, &SockaddrStorage::from(local))
+        .with_context(|| format!("failed to bind {local}"))?;
+
+    // the sequence numbers are those of the start of each queue
+    let recv_start = conn.recv_seq.wrapping_sub(conn.recv_queue.len() as u32);
+    let send_start = conn.send_seq.wrapping_sub(conn.send_queue.len() as u32);
+    set_queue_seq(sock, TCP_RECV_QUEUE, recv_start)?;
+    set_queue_seq(sock, TCP_SEND_QUEUE, send_start)?;
+    // no handshake takes place in repair mode
+    connect(sock, &SockaddrStorage::from(peer))
+        .with_context(|| format!("failed to connect tcp connection {} to {peer}", conn.inode))?;
+
+    let mut options = vec![[TCPOPT_MSS, conn.mss]];
+    if conn.options & TCPI_OPT_WSCALE != 0 {
+        options.push([
+            TCPOPT_WINDOW,
+            conn.snd_wscale as u32 | (conn.rcv_wscale as u32) << 16,
+        ]);
+    }
+    if conn.options & TCPI_OPT_SACK != 0 {
+        options.push([TCPOPT_SACK_PERM, 0]);
+    }
+    if conn.options & TCPI_OPT_TIMESTAMPS != 0 {
+        options.push([TCPOPT_TIMESTAMP, 0]);
+    }
+    sockopt::set(
+        sock,
+        libc::IPPROTO_TCP,
+        libc::TCP_REPAIR_OPTIONS,
+        options.as_slice(),
+    )?;
+    if conn.options & TCPI_OPT_TIMESTAMPS != 0 {
+        sockopt::set(
+            sock,
+            libc::IPPROTO_TCP,
+            libc::TCP_TIMESTAMP,
+            &conn.timestamp,
+        )?;
+    }
+
+    // data the peer has already seen is queued in repair mode, the rest is
+    // sent like any other once we are out of it
+    let sent = conn.send_queue.len() - conn.unsent as usize;
+    fill_queue(sock, TCP_RECV_QUEUE, &conn.recv_queue)?;
+    fill_queue(sock, TCP_SEND_QUEUE, &conn.send_queue[..sent])?;
+    sockopt::set(
+        sock,
+        libc::IPPROTO_TCP,
+        libc::TCP_REPAIR_WINDOW,
+        &conn.window,
+    )?;
+
+    sockopt::set(sock, libc::IPPROTO_TCP, libc::TCP_REPAIR, &0)?;
+    send_all(sock, &conn.send_queue[sent..])?;
+    debug!("restored tcp connection {} to {peer}", conn.inode);
+
+    Ok(())
+}
+
+fn set_queue_seq(sock: RawFd, queue: c_int, seq: u32) -> Result<()> {
+    sockopt::set(sock, libc::IPPROTO_TCP, libc::TCP_REPAIR_QUEUE, &queue)?;
+    sockopt::set(sock, libc::IPPROTO_TCP, libc::TCP_QUEUE_SEQ, &seq)
+}
+
+fn fill_queue(sock: RawFd, queue: c_int, data: &[u8]) -> Result<()> {
+    sockopt::set(sock, libc::IPPROTO_TCP, libc::TCP_REPAIR_QUEUE, &queue)?;
+    send_all(sock, data)
+}
+
+fn send_all(sock: RawFd, mut data: &[u8]) -> Result<()> {
+    while !data.is_empty() {
+        let len = send(
+            sock,
+            &data[..data.len().min(QUEUE_CHUNK)],
+            MsgFlags::empty(),
+        )?;
+        data = &data[len..];
+    }
+
+    Ok(())
+}
@@ -1,60 +1,395 @@
 use std::{
+    collections::{HashMap, HashSet},
     ffi::{CStr, CString},
-    net::SocketAddr,
-    os::fd::RawFd,
+    fs::File,
+    io::Read,
+    mem::size_of,
+    os::{
+        fd::{AsRawFd, FromRawFd},
+        unix::ffi::OsStrExt,
+    },
+    path::{Path, PathBuf},
+    process,
 };
 
 use escapepod_common::{
     anyhow::Result,
+    crypto::Key,
     nix::{
-        self,
-        fcntl::OFlag,
-        unistd::{close, execvpe, fork, pipe2, ForkResult, Pid},
+        fcntl::{fcntl, FcntlArg, FdFlag, OFlag},
+        poll::{poll, PollFd, PollFlags},
+        unistd::{close, execvpe, pipe2, ForkResult, Pid},
+    },
+    proto::{
+        Buffer, BufferId, DestinationMessage, EscapeeMessage, MemoryMappingData, Process,
+        SocketIpState, StubState,
     },
-    proto::{EscapeeMessage, Process},
-    serde_json,
+    ready::ReadyReport,
     tracing::{debug, info},
-    transport::Client,
+    transport::{Address, Client},
 };
 
 use crate::args::Args;
 
-pub fn receive(args: Args, addr: SocketAddr) -> i32 {
-    info!("connecting to origin {addr:?}");
-    let mut client = Client::connect(addr).expect("failed to connect to origin server");
+mod files;
+mod inet;
+mod pidns;
+mod precopy;
+mod proc;
+mod sockets;
+
+use files::Files;
+use pidns::PidNamespace;
+use precopy::PreCopied;
+use sockets::SocketServer;
+
+pub fn receive(_args: Args, addr: Address) -> i32 {
+    info!("connecting to origin {addr}");
+    let key = Key::from_env().expect("failed to read key");
+    let mut client = Client::connect(&addr, &key).expect("failed to connect to origin server");
     debug!("connected succesfully");
 
+    // memory copied while the origin processes were still running comes before
+    // the process trees, it is held until we know where it goes
     info!("waiting for process tree");
-    let msg = client
-        .recv::<EscapeeMessage>()
-        .expect("failed to read first message");
-
-    let procs = match msg {
-        EscapeeMessage::ProcessTrees(i) => i,
-        msg => panic!("unexpected server message: {msg:?}"),
+    let mut pre_copied = PreCopied::new().expect("failed to hold pre-copied memory");
+    let mut pipes = vec![];
+    let mut memfds = vec![];
+    let mut files = Files::default();
+    let mut socket_queues = vec![];
+    let mut tcp_connections = HashMap::new();
+    let mut procs = loop {
+        match client
+            .recv::<EscapeeMessage>()
+            .expect("failed to read message")
+        {
+            EscapeeMessage::ProcessTrees(i) => break i,
+            EscapeeMessage::Buffer(buf) | EscapeeMessage::BufferUpdate(buf) => pre_copied
+                .push(&buf)
+                .expect("failed to hold pre-copied memory"),
+            EscapeeMessage::Pipe(pipe) => pipes.push(pipe),
+            // its data follows, which is written to it as it arrives
+            EscapeeMessage::MemFd(memfd) => {
+                let file = proc::create_memfd(&memfd).expect("failed to create memfd");
+                files
+                    .receive_memfd(memfd.file, &file)
+                    .expect("failed to create memfd");
+                memfds.push((memfd, file));
+            }
+            EscapeeMessage::FileManifest(manifest) => {
+                let requests = files.manifest(manifest).expect("failed to sync files");
+                client
+                    .send(DestinationMessage::FileRequest(requests))
+                    .expect("failed to request files");
+            }
+            EscapeeMessage::File(file) => files
+                .create_deleted(file)
+                .expect("failed to create deleted file"),
+            EscapeeMessage::FileData(data) => files.write(&data).expect("failed to write file"),
+            EscapeeMessage::FileBlocks(blocks) => files
+                .copy_blocks(&blocks)
+                .expect("failed to copy file blocks"),
+            EscapeeMessage::SocketQueue(queue) => socket_queues.push(queue),
+            EscapeeMessage::TcpConnection(conn) => {
+                tcp_connections.insert(conn.inode, conn);
+            }
+            msg => panic!("unexpected server message: {msg:?}"),
+        }
     };
 
-    let procs = procs
-        .into_iter()
-        .map(spawn)
+    let mut ns = PidNamespace::new().expect("failed to create pid namespace");
+
+    // the restore stubs open their ends of each pipe through our fd, which we
+    // hold until every process is restored
+    let pipes = pipes
+        .iter()
+        .map(|p| Ok((p.pipe_id, proc::create_pipe(p)?)))
+        .collect::<Result<HashMap<_, _>>>()
+        .expect("failed to create pipes");
+    let pipe_paths = pipes
+        .iter()
+        .map(|(id, pipe)| {
+            (
+                *id,
+                PathBuf::from(format!("/proc/{}/fd/{}", process::id(), pipe.as_raw_fd())),
+            )
+        })
+        .collect::<HashMap<_, _>>();
+    // every file is in place before the restore stubs reopen them
+    let files = files.finish().expect("failed to sync files");
+    // mapped files which are missing or differ here are sent whole instead
+    let mut differing = vec![];
+    for proc in procs.iter_mut() {
+        differing.extend(proc::check_mapped_files(proc).expect("failed to check mapped files"));
+    }
+    client
+        .send(DestinationMessage::MappedFileRequest(differing))
+        .expect("failed to request mapped files");
+    let file_paths = files
+        .iter()
+        .map(|(id, file)| {
+            (
+                *id,
+                PathBuf::from(format!("/proc/{}/fd/{}", process::id(), file.as_raw_fd())),
+            )
+        })
+        .collect::<HashMap<_, _>>();
+    // memfds are reopened the same way, which keeps them shared
+    let memfd_paths = memfds
+        .iter()
+        .map(|(memfd, file)| {
+            (
+                memfd.inode,
+                PathBuf::from(format!("/proc/{}/fd/{}", process::id(), file.as_raw_fd())),
+            )
+        })
+        .collect::<HashMap<_, _>>();
+    // every restore stub, including those forked by restored parents, finds
+    // its own process in here
+    let stub_state = proc::create_stub_state(&StubState {
+        procs: procs.clone(),
+        pipes: pipe_paths,
+        memfds: memfd_paths,
+        files: file_paths,
+    })
+    .expect("failed to create restore stub state");
+    let stub_state_path = PathBuf::from(format!(
+        "/proc/{}/fd/{}",
+        process::id(),
+        stub_state.as_raw_fd()
+    ));
+    let mut sockets = sockets::create_unix_sockets(&procs, &socket_queues)
+        .expect("failed to create unix sockets");
+    let ip_sockets = inet::create_ip_sockets(&procs).expect("failed to create ip sockets");
+    for (inode, (sock, _)) in ip_sockets.iter() {
+        sockets.insert(*inode, sock.try_clone().unwrap());
+    }
+    let socket_server = SocketServer::start(sockets).expect("failed to serve sockets");
+
+    // every restore stub reports its tids over the same pipe, including the
+    // stubs of descendants which are forked by their restored parents
+    let (ready_fd_read, ready_fd_write) = pipe2(OFlag::empty()).unwrap();
+    fcntl(ready_fd_read, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC)).unwrap();
+    let pids = procs
+        .iter()
+        .map(|p| {
+            spawn(
+                &mut ns,
+                p,
+                ready_fd_write,
+                &stub_state_path,
+                &socket_server.path(),
+            )
+        })
         .collect::<Result<Vec<_>>>()
         .unwrap();
+    close(ready_fd_write).unwrap();
 
-    for (pid, ready_fd) in procs {
-        let mut buf = [0];
-        let read = nix::unistd::read(ready_fd, &mut buf[..]).unwrap();
+    let all_procs = procs
+        .iter()
+        .flat_map(|p| p.self_and_descendents())
+        .collect::<Vec<_>>();
+    let mut ready = unsafe { File::from_raw_fd(ready_fd_read) };
+    let mut reporting = HashMap::new();
+    let mut restored = HashMap::new();
+    while restored.len() < all_procs.len() {
+        // stubs fetch their sockets before they report
+        let mut fds = [
+            PollFd::new(ready.as_raw_fd(), PollFlags::POLLIN),
+            PollFd::new(socket_server.as_raw_fd(), PollFlags::POLLIN),
+        ];
+        poll(&mut fds, -1).expect("failed to poll restore stubs");
+        if fds[1].revents().unwrap().contains(PollFlags::POLLIN) {
+            socket_server.accept().expect("failed to hand out sockets");
+            continue;
+        }
+        if fds[0].revents().unwrap().is_empty() {
+            continue;
+        }
 
-        if read == 1 {
-            info!("{} is ready", pid);
+        // a report comes in chunks each written at once, which keeps the chunks
+        // of stubs reporting at the same time apart
+        let mut buf = [0u8; ReadyReport::SIZE];
+        ready
+            .read_exact(&mut buf)
+            .expect("failed to read restore report");
+        let report = ReadyReport::from_bytes(&buf);
+        if report.error_len > 0 {
+            let mut reason = vec![0u8; report.error_len as usize];
+            ready
+                .read_exact(&mut reason)
+                .expect("failed to read restore failure");
+            panic!(
+                "failed to restore {}: {}",
+                report.pid,
+                String::from_utf8_lossy(&reason)
+            );
+        }
+        let mut buf = vec![0u8; size_of::<i32>() * report.tids_len as usize];
+        ready
+            .read_exact(&mut buf)
+            .expect("failed to read restored tids");
+        let ns_pid = report.pid;
+        let proc = all_procs
+            .iter()
+            .find(|p| p.pid == ns_pid)
+            .unwrap_or_else(|| panic!("unexpected restored pid {ns_pid}"));
+        if report.threads_len as usize != proc.threads.len() {
+            panic!(
+                "restored {} of {} threads of {ns_pid}",
+                report.threads_len,
+                proc.threads.len()
+            );
+        }
+
+        // the tids start with the pid as the main thread comes first
+        let ns_tids = reporting.entry(ns_pid).or_insert_with(Vec::new);
+        if ns_tids.len() != report.first as usize {
+            panic!(
+                "unexpected tids {}.. of {ns_pid} after {}",
+                report.first,
+                ns_tids.len()
+            );
+        }
+        ns_tids.extend(
+            buf.chunks_exact(size_of::<i32>())
+                .map(|i| Pid::from_raw(i32::from_ne_bytes(i.try_into().unwrap()))),
+        );
+        if ns_tids.len() < proc.threads.len() {
+            continue;
         }
+        let ns_tids = reporting.remove(&ns_pid).unwrap();
+        for (thread, tid) in proc.threads.iter().zip(ns_tids.iter()) {
+            if thread.tid != tid.as_raw() {
+                panic!("failed to restore tid {}: got {tid}", thread.tid);
+            }
+        }
+
+        let pid = pidns::host_pid(&pids, ns_pid).expect("failed to find restored process");
+        info!("{} is ready", pid);
+        let tids = pidns::host_tids(pid, &ns_tids).expect("failed to find restored threads");
+        proc::wait_for_restore_stop(pid, &tids).expect("failed to restore process");
+        restored.insert(ns_pid, (*proc, pid, tids, report));
     }
+    drop(pipes);
+    drop(stub_state);
+    for (memfd, file) in memfds.iter() {
+        proc::seal_memfd(file, memfd).expect("failed to seal memfd");
+    }
+    drop(memfds);
+    drop(files);
+    socket_server
+        .stop()
+        .expect("failed to stop serving sockets");
 
-    // todo: restore memory
+    // the origin streams the contents of every buffer mapping, each goes into
+    // the mapping the restore stub recreated
+    let mut buffers = HashMap::new();
+    for (proc, pid, _, _) in restored.values() {
+        for mmap in proc.mmaps.iter() {
+            match &mmap.data {
+                MemoryMappingData::Buffer(id) => {
+                    buffers.insert(*id, (*pid, mmap.address, mmap.len, false));
+                }
+                MemoryMappingData::KernelVdso(id) => {
+                    buffers.insert(*id, (*pid, mmap.address, mmap.len, true));
+                }
+                // the rest of the mapping comes from the file itself
+                MemoryMappingData::File(f) => {
+                    for pages in f.modified.iter() {
+                        buffers.insert(
+                            pages.buffer,
+                            (*pid, mmap.address + pages.offset, pages.len, false),
+                        );
+                    }
+                }
+                MemoryMappingData::MemFd(_) | MemoryMappingData::KernelVvar => {}
+            }
+        }
+    }
+    let mut missing = buffers.keys().copied().collect::<HashSet<_>>();
+    // buffers of mappings which were gone by the time the origin froze are dropped
+    for buf in pre_copied
+        .drain()
+        .expect("failed to read pre-copied memory")
+    {
+        let buf = buf.expect("failed to read pre-copied memory");
+        if buffers.contains_key(&buf.buffer) {
+            restore_buffer(&buffers, &buf);
+            missing.remove(&buf.buffer);
+        }
+    }
+    loop {
+        match client
+            .recv::<EscapeeMessage>()
+            .expect("failed to read message")
+        {
+            EscapeeMessage::Buffer(buf) => {
+                restore_buffer(&buffers, &buf);
+                missing.remove(&buf.buffer);
+            }
+            EscapeeMessage::BufferUpdate(buf) => restore_buffer(&buffers, &buf),
+            EscapeeMessage::Done => break,
+            msg => panic!("unexpected server message: {msg:?}"),
+        }
+    }
+    if !missing.is_empty() {
+        panic!("origin did not send {} buffers", missing.len());
+    }
+    info!("restored process memory");
 
-    0
+    // the origin processes are gone along with their sockets by now
+    for (inode, (sock, state)) in ip_sockets.iter() {
+        match state {
+            SocketIpState::Bind(bind) => {
+                inet::bind_socket(sock, bind).expect("failed to restore socket");
+            }
+            SocketIpState::Connect { local, peer } => {
+                let conn = tcp_connections
+                    .get(inode)
+                    .unwrap_or_else(|| panic!("origin did not send tcp connection {inode}"));
+                inet::repair_tcp_connection(sock, *local, *peer, conn)
+                    .expect("failed to restore tcp connection");
+            }
+        }
+    }
+    drop(ip_sockets);
+
+    // no thread is resumed until every thread has its registers in place
+    for (proc, _, tids, report) in restored.values() {
+        proc::restore_threads(proc, tids, report).expect("failed to restore threads");
+    }
+
+    for (_, pid, _, _) in restored.values() {
+        proc::resume(*pid).expect("failed to resume process");
+    }
+    info!("resumed restored processes");
+
+    proc::wait(*pids.first().unwrap()).expect("failed to wait")
+}
+
+// writes a buffer into the mapping it belongs to, the vdso is only compared
+// as the restored one is our kernel's
+fn restore_buffer(buffers: &HashMap<BufferId, (Pid, u64, u64, bool)>, buf: &Buffer) {
+    let (pid, address, len, vdso) = *buffers
+        .get(&buf.buffer)
+        .unwrap_or_else(|| panic!("unexpected buffer {}", buf.buffer));
+    if vdso {
+        if !proc::memory_matches(pid, address, len, buf).expect("failed to read vdso") {
+            panic!("vdso of {pid} differs from the origin, kernels do not match");
+        }
+    } else {
+        proc::write_memory(pid, address, len, buf).expect("failed to restore memory");
+    }
 }
 
-fn spawn(proc: Process) -> Result<(Pid, i32)> {
+fn spawn(
+    ns: &mut PidNamespace,
+    proc: &Process,
+    ready_fd_write: i32,
+    state: &Path,
+    sockets: &Path,
+) -> Result<Pid> {
     let restore_path = std::env::current_exe()
         .unwrap()
         .parent()
@@ -63,37 +398,27 @@ fn spawn(proc: Process) -> Result<(Pid, i32)> {
         .to_string_lossy()
         .to_string();
 
-    let (ready_fd_read, ready_fd_write) = pipe2(OFlag::empty()).unwrap();
-
-    // todo restore pid
+    // prepare everything up front as the child only execs
+    let restore_path = CString::new(restore_path).unwrap();
+    let env = [
+        CString::new(format!("EP_READY_FD={}", ready_fd_write)).unwrap(),
+        CString::new([b"EP_STATE=", state.as_os_str().as_bytes()].concat()).unwrap(),
+        CString::new([b"EP_SOCKETS=", sockets.as_os_str().as_bytes()].concat()).unwrap(),
+        // todo:
+        CString::new("RUST_LOG=trace").unwrap(),
+        // the restore routine unmaps glibc's rseq area which the kernel would
+        // then fault on, so we must not let it register one
+        CString::new("GLIBC_TUNABLES=glibc.pthread.rseq=0").unwrap(),
+    ];
 
-    unsafe {
-        match fork().expect("failed to fork") {
-            ForkResult::Parent { child } => {
-                info!("forked to pid: {:?}", child);
-                return Ok((child, ready_fd_read));
-            }
-            ForkResult::Child => {
-                execvpe(
-                    CString::new(restore_path).unwrap().as_c_str(),
-                    &[] as &[&CStr],
-                    &[
-                        CString::new(format!(
-                            "EP_PROCESS={}",
-                            serde_json::to_string(&proc).unwrap()
-                        ))
-                        .unwrap()
-                        .as_c_str(),
-                        CString::new(format!("EP_READY_FD={}", ready_fd_write))
-                            .unwrap()
-                            .as_c_str(),
-                        // todo:
-                        CString::new("RUST_LOG=trace").unwrap().as_c_str(),
-                    ],
-                )
-                .unwrap();
-                unreachable!()
-            }
+    match ns.fork_with_pid(proc.pid)? {
+        ForkResult::Parent { child } => {
+            info!("forked to pid: {:?}", child);
+            Ok(child)
+        }
+        ForkResult::Child => {
+            let Err(e) = execvpe(restore_path.as_c_str(), &[] as &[&CStr], &env);
+            panic!("failed to exec restore: {e}")
         }
     }
 }
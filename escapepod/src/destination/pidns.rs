@@ -0,0 +1,180 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{Read, Write},
+    mem::{size_of, MaybeUninit},
+    os::fd::FromRawFd,
+};
+
+use escapepod_common::{
+    anyhow::{anyhow, bail, Context, Result},
+    libc,
+    nix::{
+        errno::Errno,
+        fcntl::OFlag,
+        sched::{unshare, CloneFlags},
+        sys::signal::{self, SigHandler, Signal},
+        unistd::{fork, pipe2, ForkResult, Pid},
+    },
+    procfs,
+    tracing::debug,
+};
+
+// a fresh pid namespace for the restored processes so they get back the exact
+// pids and tids they had at the origin
+pub(crate) struct PidNamespace {
+    init: Pid,
+    // requests to the init process, see init()
+    ctl: File,
+    ack: File,
+}
+
+impl PidNamespace {
+    // moves the children we create from now on into a new pid namespace
+    pub(crate) fn new() -> Result<Self> {
+        unshare(CloneFlags::CLONE_NEWPID)
+            .context("failed to create pid namespace, restoring pids requires CAP_SYS_ADMIN")?;
+
+        let (ctl_read, ctl_write) = pipe2(OFlag::O_CLOEXEC)?;
+        let (ack_read, ack_write) = pipe2(OFlag::O_CLOEXEC)?;
+
+        // the first process we create becomes pid 1 of the namespace
+        match unsafe { fork()? } {
+            ForkResult::Parent { child } => unsafe {
+                libc::close(ctl_read);
+                libc::close(ack_write);
+                debug!("pid namespace init is {child}");
+
+                Ok(Self {
+                    init: child,
+                    ctl: File::from_raw_fd(ctl_write),
+                    ack: File::from_raw_fd(ack_read),
+                })
+            },
+            ForkResult::Child => unsafe {
+                libc::close(ctl_write);
+                libc::close(ack_read);
+                init(File::from_raw_fd(ctl_read), File::from_raw_fd(ack_write))
+            },
+        }
+    }
+
+    // forks the current process, the child gets the given pid in the namespace
+    pub(crate) fn fork_with_pid(&mut self, pid: libc::pid_t) -> Result<ForkResult> {
+        let set_tid = [pid];
+        let mut args: libc::clone_args = unsafe { MaybeUninit::zeroed().assume_init() };
+        args.exit_signal = libc::SIGCHLD as _;
+        args.set_tid = set_tid.as_ptr() as _;
+        args.set_tid_size = set_tid.len() as _;
+
+        let res = unsafe {
+            libc::syscall(
+                libc::SYS_clone3,
+                &mut args as *mut libc::clone_args,
+                size_of::<libc::clone_args>(),
+            )
+        };
+        let child = match Errno::result(res) {
+            Ok(0) => return Ok(ForkResult::Child),
+            Ok(child) => Pid::from_raw(child as _),
+            // clone3 needs linux 5.5, before that we can only pick the next pid
+            Err(Errno::ENOSYS) => {
+                self.set_last_pid(pid - 1)?;
+                match unsafe { fork()? } {
+                    ForkResult::Child => return Ok(ForkResult::Child),
+                    ForkResult::Parent { child } => child,
+                }
+            }
+            Err(Errno::EEXIST) => bail!("failed to restore pid {pid}: already in use"),
+            Err(e) => bail!("failed to restore pid {pid}: {e}"),
+        };
+
+        let restored = ns_pid(child)?;
+        if restored != pid {
+            signal::kill(child, Signal::SIGKILL)?;
+            bail!("failed to restore pid {pid}: got {restored}");
+        }
+        debug!("restored pid {pid} as {child}");
+
+        Ok(ForkResult::Parent { child })
+    }
+
+    fn set_last_pid(&mut self, pid: libc::pid_t) -> Result<()> {
+        self.ctl.write_all(&pid.to_ne_bytes())?;
+        let mut ack = [0u8];
+        self.ack.read_exact(&mut ack)?;
+        if ack[0] != 0 {
+            bail!("failed to set ns_last_pid of {} to {pid}", self.init);
+        }
+
+        Ok(())
+    }
+}
+
+// pid 1 of the namespace, it reaps orphaned processes and writes ns_last_pid for
+// the destination as that only applies to the namespace of the writer.
+// the namespace is torn down once the destination exits and closes ctl
+fn init(mut ctl: File, mut ack: File) -> ! {
+    unsafe { signal::signal(Signal::SIGCHLD, SigHandler::SigIgn) }.unwrap();
+
+    let mut buf = [0u8; size_of::<libc::pid_t>()];
+    while ctl.read_exact(&mut buf).is_ok() {
+        let pid = libc::pid_t::from_ne_bytes(buf);
+        let res = OpenOptions::new()
+            .write(true)
+            .open("/proc/sys/kernel/ns_last_pid")
+            .and_then(|mut f| f.write_all(pid.to_string().as_bytes()));
+        if ack.write_all(&[res.is_err() as u8]).is_err() {
+            break;
+        }
+    }
+
+    std::process::exit(0)
+}
+
+// the pid of a process as seen from inside the innermost namespace it belongs to
+pub(crate) fn ns_pid(pid: Pid) -> Result<libc::pid_t> {
+    procfs::process::Process::new(pid.as_raw())?
+        .status()?
+        .nspid
+        .and_then(|i| i.last().copied())
+        .ok_or_else(|| anyhow!("failed to read namespace pid of {pid}"))
+}
+
+// finds the restored process with the given namespaced pid among the
+// descendants of our children
+pub(crate) fn host_pid(children: &[Pid], pid: libc::pid_t) -> Result<Pid> {
+    let mut procs = children.to_vec();
+    while let Some(proc) = procs.pop() {
+        if ns_pid(proc)? == pid {
+            return Ok(proc);
+        }
+
+        for task in procfs::process::Process::new(proc.as_raw())?.tasks()? {
+            for child in task?.children()? {
+                procs.push(Pid::from_raw(child as _));
+            }
+        }
+    }
+
+    bail!("failed to find restored process {pid}")
+}
+
+// maps tids reported from inside the namespace of pid to the tids we can trace
+pub(crate) fn host_tids(pid: Pid, tids: &[Pid]) -> Result<Vec<Pid>> {
+    let mut tasks = vec![];
+    for task in procfs::process::Process::new(pid.as_raw())?.tasks()? {
+        let task = task?;
+        let nspid = task.status()?.nspid.and_then(|i| i.last().copied());
+        tasks.push((nspid, Pid::from_raw(task.tid)));
+    }
+
+    tids.iter()
+        .map(|tid| {
+            tasks
+                .iter()
+                .find(|(nspid, _)| *nspid == Some(tid.as_raw()))
+                .map(|(_, host)| *host)
+                .ok_or_else(|| anyhow!("failed to find thread {tid} of {pid}"))
+        })
+        .collect()
+}
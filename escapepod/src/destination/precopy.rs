@@ -0,0 +1,58 @@
+use std::{
+    env,
+    fs::{File, OpenOptions},
+    io::{BufReader, BufWriter, Seek, SeekFrom},
+    os::unix::fs::OpenOptionsExt,
+};
+
+use escapepod_common::{
+    anyhow::{Context, Result},
+    bincode, libc,
+    proto::Buffer,
+};
+
+// memory copied while the origin processes were still running. where it goes
+// is only known once the process trees arrive, until then it is kept in an
+// anonymous temporary file rather than in memory
+pub(crate) struct PreCopied {
+    file: BufWriter<File>,
+    count: usize,
+}
+
+impl PreCopied {
+    pub(crate) fn new() -> Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_TMPFILE | libc::O_CLOEXEC)
+            .mode(0o600)
+            .open(env::temp_dir())
+            .context("failed to create pre-copy file")?;
+
+        Ok(Self {
+            file: BufWriter::new(file),
+            count: 0,
+        })
+    }
+
+    pub(crate) fn push(&mut self, buf: &Buffer) -> Result<()> {
+        bincode::encode_into_std_write(buf, &mut self.file, bincode::config::standard())?;
+        self.count += 1;
+
+        Ok(())
+    }
+
+    // the buffers in the order they were received
+    pub(crate) fn drain(self) -> Result<impl Iterator<Item = Result<Buffer>>> {
+        let mut file = self.file.into_inner()?;
+        file.seek(SeekFrom::Start(0))?;
+        let mut file = BufReader::new(file);
+
+        Ok((0..self.count).map(move |_| {
+            Ok(bincode::decode_from_std_read(
+                &mut file,
+                bincode::config::standard(),
+            )?)
+        }))
+    }
+}
@@ -0,0 +1,576 @@
+use std::{
+    ffi::{c_void, CString},
+    fs::{File, OpenOptions},
+    io::Write,
+    mem::MaybeUninit,
+    os::{
+        fd::{AsRawFd, FromRawFd},
+        unix::fs::FileExt,
+    },
+};
+
+use escapepod_common::{
+    anyhow::{bail, Context, Result},
+    delta, libc,
+    nix::{
+        errno::Errno,
+        fcntl::{fcntl, FcntlArg, OFlag, SealFlag},
+        sys::{
+            memfd::{memfd_create, MemFdCreateFlag},
+            ptrace,
+            signal::{self, Signal},
+            wait::{waitpid, WaitPidFlag, WaitStatus},
+        },
+        unistd::{pipe2, Pid},
+    },
+    proto::{
+        Buffer, BufferId, MappedFile, MemFd, MemoryMappingData, Pipe, Process, StubState, Thread,
+        MEMORY_CHUNK,
+    },
+    ready::ReadyReport,
+    serde_json,
+    tracing::{debug, trace},
+};
+
+#[cfg(target_arch = "aarch64")]
+const NT_ARM_TLS: i32 = 0x401;
+
+// waits for the restore stub to stop itself once its memory has been rebuilt.
+// the stubs of descendants are not our children so we trace the threads to
+// wait on them, they stay in their group stop after we detach
+pub(crate) fn wait_for_restore_stop(pid: Pid, tids: &[Pid]) -> Result<()> {
+    let mut seized = vec![];
+    let res = tids.iter().try_for_each(|tid| {
+        ptrace::seize(*tid, ptrace::Options::empty())?;
+        seized.push(*tid);
+        wait_for_group_stop(*tid)
+    });
+    for tid in seized {
+        let _ = ptrace::detach(tid, None);
+    }
+
+    res.with_context(|| format!("restore of {pid} failed"))
+}
+
+fn wait_for_group_stop(tid: Pid) -> Result<()> {
+    loop {
+        match waitpid(tid, Some(WaitPidFlag::__WALL))? {
+            WaitStatus::PtraceEvent(_, _, libc::PTRACE_EVENT_STOP) => return Ok(()),
+            // the stop signal is dequeued by whichever thread gets to it first,
+            // passing it on starts the group stop
+            WaitStatus::Stopped(_, signal) => ptrace::cont(tid, signal)?,
+            status => bail!("unexpected status of {tid}: {status:?}"),
+        }
+    }
+}
+
+// loads the saved registers of each thread into its counterpart in the stopped
+// restore stub, which leaves its restore region to us
+pub(crate) fn restore_threads(proc: &Process, tids: &[Pid], report: &ReadyReport) -> Result<()> {
+    if proc.threads.len() != tids.len() {
+        bail!(
+            "restored {} of {} threads of {}",
+            tids.len(),
+            proc.threads.len(),
+            proc.pid
+        );
+    }
+
+    // the main thread stopped itself at the end of the restore routine, the
+    // others are parked in it and never run it again once they have their registers
+    for (i, (thread, tid)) in proc.threads.iter().zip(tids).enumerate() {
+        let region = (i == 0).then_some((report.region, report.region_len));
+        set_thread_regset(thread, *tid, region)?;
+    }
+
+    Ok(())
+}
+
+fn set_thread_regset(thread: &Thread, tid: Pid, unmap: Option<(u64, u64)>) -> Result<()> {
+    if thread.reg.len() != std::mem::size_of::<libc::user_regs_struct>() {
+        bail!(
+            "unexpected regset size {} for {}",
+            thread.reg.len(),
+            thread.tid
+        );
+    }
+
+    ptrace::seize(tid, ptrace::Options::empty())?;
+    ptrace::interrupt(tid)?;
+    match waitpid(tid, Some(WaitPidFlag::__WALL))? {
+        WaitStatus::PtraceEvent(_, _, libc::PTRACE_EVENT_STOP) => {}
+        status => bail!("unexpected status while interrupting {tid}: {status:?}"),
+    }
+
+    if let Some((address, len)) = unmap {
+        unmap_region(tid, address, len)?;
+    }
+    set_regset(tid, libc::NT_PRSTATUS, &mut thread.reg.clone())?;
+    // the tls base is part of the general purpose regset on x86_64
+    #[cfg(target_arch = "aarch64")]
+    set_regset(tid, NT_ARM_TLS, &mut thread.tls.to_ne_bytes())?;
+
+    // the stub stays in its group stop after we detach
+    ptrace::detach(tid, None)?;
+    debug!("restored registers of {} into {}", thread.tid, tid);
+
+    Ok(())
+}
+
+// runs munmap through the syscall instruction the thread stopped right after,
+// its registers are replaced with the saved ones afterwards anyway
+#[cfg(target_arch = "x86_64")]
+fn unmap_region(tid: Pid, address: u64, len: u64) -> Result<()> {
+    // syscall
+    const SYSCALL: u16 = 0x050f;
+
+    let mut regs = ptrace::getregs(tid)?;
+    let ip = regs.rip - 2;
+    if ptrace::read(tid, ip as ptrace::AddressType)? as u16 != SYSCALL {
+        bail!("{tid} did not stop right after a syscall");
+    }
+    regs.rip = ip;
+    regs.rax = libc::SYS_munmap as _;
+    // prevents the kernel from restarting the syscall the thread was stopped in
+    regs.orig_rax = u64::MAX;
+    regs.rdi = address;
+    regs.rsi = len;
+    ptrace::setregs(tid, regs)?;
+
+    loop {
+        ptrace::step(tid, None)?;
+        match waitpid(tid, Some(WaitPidFlag::__WALL))? {
+            WaitStatus::Stopped(_, Signal::SIGTRAP) => break,
+            // the pending group stop may be reported before the syscall runs
+            WaitStatus::PtraceEvent(_, _, libc::PTRACE_EVENT_STOP) => continue,
+            status => bail!("unexpected status while unmapping from {tid}: {status:?}"),
+        }
+    }
+    match ptrace::getregs(tid)?.rax as i64 {
+        0 => {}
+        res => bail!(
+            "failed to unmap the restore region {address:x}-{:x} of {tid}: {}",
+            address + len,
+            Errno::from_i32(-res as i32)
+        ),
+    }
+    trace!(
+        "unmapped the restore region {address:x}-{:x} of {tid}",
+        address + len
+    );
+
+    Ok(())
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+fn unmap_region(tid: Pid, _address: u64, _len: u64) -> Result<()> {
+    bail!("unmapping the restore region of {tid} is not supported on this architecture")
+}
+
+fn set_regset(tid: Pid, nt: i32, buf: &mut [u8]) -> Result<()> {
+    unsafe {
+        let mut io: libc::iovec = MaybeUninit::zeroed().assume_init();
+        io.iov_base = buf.as_mut_ptr() as *mut _;
+        io.iov_len = buf.len();
+        let res = libc::ptrace(
+            libc::PTRACE_SETREGSET,
+            tid,
+            nt as *mut c_void,
+            &mut io as *mut _,
+        );
+        if res != 0 {
+            bail!("PTRACE_SETREGSET failed");
+        }
+    }
+
+    Ok(())
+}
+
+// pages no piece of the buffer covers are left as the zero-filled pages of the
+// new mapping
+pub(crate) fn write_memory(pid: Pid, address: u64, len: u64, buf: &Buffer) -> Result<()> {
+    let data = buf.data.decompress(MEMORY_CHUNK as usize)?;
+    check_buffer(address, len, buf, &data)?;
+
+    // unlike process_vm_writev this also writes mappings without PROT_WRITE
+    let mem = OpenOptions::new()
+        .write(true)
+        .open(format!("/proc/{pid}/mem"))?;
+    mem.write_all_at(&data, address + buf.offset)
+        .with_context(|| format!("failed to write {:x} of {pid}", address + buf.offset))?;
+    trace!(
+        "restored {} bytes at {:x} of {:x}-{:x} of {pid}",
+        data.len(),
+        buf.offset,
+        address,
+        address + len
+    );
+
+    Ok(())
+}
+
+pub(crate) fn memory_matches(pid: Pid, address: u64, len: u64, buf: &Buffer) -> Result<bool> {
+    let data = buf.data.decompress(MEMORY_CHUNK as usize)?;
+    check_buffer(address, len, buf, &data)?;
+
+    let mem = OpenOptions::new()
+        .read(true)
+        .open(format!("/proc/{pid}/mem"))?;
+    let mut current = vec![0u8; data.len()];
+    mem.read_exact_at(&mut current, address + buf.offset)
+        .with_context(|| format!("failed to read {:x} of {pid}", address + buf.offset))?;
+
+    Ok(current == *data)
+}
+
+fn check_buffer(address: u64, len: u64, buf: &Buffer, data: &[u8]) -> Result<()> {
+    if buf.offset + data.len() as u64 > len {
+        bail!(
+            "{:x}-{:x} of buffer {} does not fit {:x} of {len} bytes",
+            buf.offset,
+            buf.offset + data.len() as u64,
+            buf.buffer,
+            address,
+        );
+    }
+
+    Ok(())
+}
+
+// a new pipe holding the data buffered in the original
+// file mappings are remapped from the file here, those whose file is missing
+// or differs from the origin's become buffers of the whole mapping instead.
+// returns the buffers the origin is to send
+pub(crate) fn check_mapped_files(proc: &mut Process) -> Result<Vec<BufferId>> {
+    let mut differing = vec![];
+    for mmap in proc.mmaps.iter_mut() {
+        let MemoryMappingData::File(f) = &mmap.data else {
+            continue;
+        };
+        if mapped_file_matches(f, mmap.len)? {
+            continue;
+        }
+        debug!("mapped file {:?} differs from the origin", f.path);
+        differing.push(f.buffer);
+        // a shared mapping no longer reaches the file
+        mmap.data = MemoryMappingData::Buffer(f.buffer);
+    }
+    for child in proc.threads.iter_mut().flat_map(|t| t.children.iter_mut()) {
+        differing.extend(check_mapped_files(child)?);
+    }
+
+    Ok(differing)
+}
+
+// the file may live on another host here so we cannot compare inodes
+fn mapped_file_matches(f: &MappedFile, len: u64) -> Result<bool> {
+    let Ok(file) = File::open(&f.path) else {
+        return Ok(false);
+    };
+    if file.metadata()?.len() != f.size {
+        return Ok(false);
+    }
+
+    Ok(delta::hash_range(&file, f.offset, len)? == f.hash)
+}
+
+pub(crate) fn create_pipe(pipe: &Pipe) -> Result<File> {
+    let (read, write) = pipe2(OFlag::O_CLOEXEC)?;
+    let (read, mut write) = unsafe { (File::from_raw_fd(read), File::from_raw_fd(write)) };
+    fcntl(write.as_raw_fd(), FcntlArg::F_SETPIPE_SZ(pipe.size))
+        .with_context(|| format!("failed to resize pipe {} to {}", pipe.pipe_id, pipe.size))?;
+    write.write_all(&pipe.buf)?;
+    debug!(
+        "restored pipe {} with {} bytes",
+        pipe.pipe_id,
+        pipe.buf.len()
+    );
+
+    Ok(read)
+}
+
+// a new memfd holding the contents of the original, sealed only once the
+// restore stubs have mapped it
+pub(crate) fn create_memfd(memfd: &MemFd) -> Result<File> {
+    let name = CString::new(memfd.name.as_str())?;
+    let fd = memfd_create(
+        &name,
+        MemFdCreateFlag::MFD_CLOEXEC | MemFdCreateFlag::MFD_ALLOW_SEALING,
+    )?;
+    let file = unsafe { File::from_raw_fd(fd) };
+    debug!("created memfd {} ({})", memfd.inode, memfd.name);
+
+    Ok(file)
+}
+
+// the state is only ever read by the stubs, through procfs like the memfds
+pub(crate) fn create_stub_state(state: &StubState) -> Result<File> {
+    let fd = memfd_create(
+        c"escapepod-stub-state",
+        MemFdCreateFlag::MFD_CLOEXEC | MemFdCreateFlag::MFD_ALLOW_SEALING,
+    )?;
+    let mut file = unsafe { File::from_raw_fd(fd) };
+    file.write_all(&serde_json::to_vec(state)?)?;
+    let seals = SealFlag::F_SEAL_SEAL
+        | SealFlag::F_SEAL_SHRINK
+        | SealFlag::F_SEAL_GROW
+        | SealFlag::F_SEAL_WRITE;
+    fcntl(file.as_raw_fd(), FcntlArg::F_ADD_SEALS(seals))?;
+
+    Ok(file)
+}
+
+pub(crate) fn seal_memfd(file: &File, memfd: &MemFd) -> Result<()> {
+    let seals = SealFlag::from_bits_truncate(memfd.seals);
+    if !seals.is_empty() {
+        fcntl(file.as_raw_fd(), FcntlArg::F_ADD_SEALS(seals))
+            .with_context(|| format!("failed to seal memfd {}", memfd.inode))?;
+    }
+
+    Ok(())
+}
+
+pub(crate) fn resume(pid: Pid) -> Result<()> {
+    signal::kill(pid, Signal::SIGCONT)?;
+    debug!("resumed {pid}");
+
+    Ok(())
+}
+
+pub(crate) fn wait(pid: Pid) -> Result<i32> {
+    loop {
+        match waitpid(pid, None)? {
+            WaitStatus::Exited(_, status) => return Ok(status),
+            WaitStatus::Signaled(_, signal, _) => return Ok(128 + (signal as i32)),
+            _ => continue,
+        }
+    }
+}
+
+#[cfg(all(test, target_arch = "x86_64"))]
+mod tests {
+    use std::arch::global_asm;
+
+    use escapepod_common::proto::{MemoryLayout, MemoryMapping};
+
+    use super::*;
+
+    // landing code for the stopped child, each exits with the code its test expects
+    global_asm!(
+        // reached with the loaded rax and rsp
+        ".globl regset_test_roundtrip",
+        "regset_test_roundtrip:",
+        "cmp rax, 0x1234",
+        "jne 1f",
+        "cmp rsp, r12",
+        "jne 1f",
+        "mov edi, 42",
+        "mov eax, 231",
+        "syscall",
+        "1:",
+        "mov edi, 1",
+        "mov eax, 231",
+        "syscall",
+        // entered right after the syscall instruction, the kernel restarts it
+        ".globl regset_test_restart",
+        "regset_test_restart:",
+        "syscall",
+        "mov edi, 1",
+        "mov eax, 231",
+        "syscall",
+        ".globl regset_test_restart_block",
+        "regset_test_restart_block:",
+        "syscall",
+        "cmp rax, -4",
+        "jne 1f",
+        "mov edi, 44",
+        "mov eax, 231",
+        "syscall",
+        "1:",
+        "mov edi, 1",
+        "mov eax, 231",
+        "syscall",
+    );
+
+    extern "C" {
+        fn regset_test_roundtrip();
+        fn regset_test_restart();
+        fn regset_test_restart_block();
+    }
+
+    // a child group-stopped like the restore stub, with its current registers
+    fn stopped_child() -> (Pid, libc::user_regs_struct) {
+        let pid = match unsafe { libc::fork() } {
+            0 => loop {
+                unsafe { libc::raise(libc::SIGSTOP) };
+            },
+            pid => Pid::from_raw(pid),
+        };
+        match waitpid(pid, Some(WaitPidFlag::WUNTRACED)).unwrap() {
+            WaitStatus::Stopped(_, Signal::SIGSTOP) => {}
+            status => panic!("unexpected status {status:?}"),
+        }
+
+        ptrace::seize(pid, ptrace::Options::empty()).unwrap();
+        ptrace::interrupt(pid).unwrap();
+        waitpid(pid, Some(WaitPidFlag::__WALL)).unwrap();
+        let regs = ptrace::getregs(pid).unwrap();
+        ptrace::detach(pid, None).unwrap();
+
+        (pid, regs)
+    }
+
+    fn saved_thread(pid: Pid, regs: libc::user_regs_struct) -> Thread {
+        let reg = unsafe {
+            std::slice::from_raw_parts(
+                &regs as *const _ as *const u8,
+                std::mem::size_of::<libc::user_regs_struct>(),
+            )
+        };
+        Thread {
+            tid: pid.as_raw(),
+            uid: 0,
+            gid: 0,
+            reg: reg.to_vec(),
+            tls: regs.fs_base,
+            clear_child_tid: 0,
+            sigmask: 0,
+            robust_list: 0,
+            robust_list_len: 0,
+            children: vec![],
+        }
+    }
+
+    fn restore(pid: Pid, regs: libc::user_regs_struct) -> i32 {
+        set_thread_regset(&saved_thread(pid, regs), pid, None).unwrap();
+        resume(pid).unwrap();
+        wait(pid).unwrap()
+    }
+
+    #[test]
+    fn test_check_mapped_files() {
+        let path = std::env::temp_dir().join(format!("escapepod-mapped-{}", std::process::id()));
+        std::fs::write(&path, vec![7u8; 3 * 4096]).unwrap();
+        let mapped = |offset, buffer| MemoryMapping {
+            address: 0x10000 + offset,
+            len: 4096,
+            perm: libc::PROT_READ,
+            data: MemoryMappingData::File(MappedFile {
+                path: path.clone(),
+                offset,
+                size: 3 * 4096,
+                hash: delta::hash(&[7u8; 4096]),
+                modified: vec![],
+                buffer,
+            }),
+        };
+        let mut proc = Process {
+            pid: 1,
+            mmaps: vec![mapped(0, 1), mapped(4096, 2), mapped(8192, 3)],
+            layout: MemoryLayout {
+                start_code: 0,
+                end_code: 0,
+                start_data: 0,
+                end_data: 0,
+                start_brk: 0,
+                brk: 0,
+                start_stack: 0,
+                arg_start: 0,
+                arg_end: 0,
+                env_start: 0,
+                env_end: 0,
+            },
+            fd_table: vec![],
+            sigactions: vec![],
+            threads: vec![],
+        };
+        // only the middle page differs here
+        std::fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .write_all_at(&[8u8], 4096 + 100)
+            .unwrap();
+
+        let differing = check_mapped_files(&mut proc).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(differing, vec![2]);
+        assert!(matches!(proc.mmaps[0].data, MemoryMappingData::File(_)));
+        assert_eq!(proc.mmaps[1].data, MemoryMappingData::Buffer(2));
+        assert!(matches!(proc.mmaps[2].data, MemoryMappingData::File(_)));
+        // a missing file differs as well
+        assert_eq!(check_mapped_files(&mut proc).unwrap(), vec![1, 3]);
+    }
+
+    #[test]
+    fn test_regset_roundtrip() {
+        let (pid, mut regs) = stopped_child();
+        regs.rip = regset_test_roundtrip as *const () as u64;
+        regs.rsp = (regs.rsp - 4096) & !15;
+        regs.r12 = regs.rsp;
+        regs.rax = 0x1234;
+        // not in a syscall, nothing to restart
+        regs.orig_rax = u64::MAX;
+
+        assert_eq!(restore(pid, regs), 42);
+    }
+
+    #[test]
+    fn test_regset_unmaps_region() {
+        let len = 4 * 4096;
+        let address = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE | libc::PROT_EXEC,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        } as u64;
+        let mapped = |pid: Pid| {
+            escapepod_common::procfs::process::Process::new(pid.as_raw())
+                .unwrap()
+                .maps()
+                .unwrap()
+                .iter()
+                .any(|m| m.address.0 <= address && address < m.address.1)
+        };
+
+        let (pid, mut regs) = stopped_child();
+        assert!(mapped(pid));
+        regs.rip = regset_test_roundtrip as *const () as u64;
+        regs.rsp = (regs.rsp - 4096) & !15;
+        regs.r12 = regs.rsp;
+        regs.rax = 0x1234;
+        regs.orig_rax = u64::MAX;
+
+        set_thread_regset(&saved_thread(pid, regs), pid, Some((address, len as u64))).unwrap();
+        assert!(!mapped(pid));
+        resume(pid).unwrap();
+        assert_eq!(wait(pid).unwrap(), 42);
+        unsafe { libc::munmap(address as _, len) };
+    }
+
+    #[test]
+    fn test_regset_restarts_syscall() {
+        let (pid, mut regs) = stopped_child();
+        regs.rip = regset_test_restart as *const () as u64 + 2;
+        regs.orig_rax = libc::SYS_exit_group as u64;
+        regs.rdi = 43;
+        regs.rax = -513i64 as u64; // -ERESTARTNOINTR
+
+        assert_eq!(restore(pid, regs), 43);
+    }
+
+    #[test]
+    fn test_regset_restart_block_interrupts() {
+        let (pid, mut regs) = stopped_child();
+        regs.rip = regset_test_restart_block as *const () as u64 + 2;
+        regs.orig_rax = libc::SYS_nanosleep as u64;
+        regs.rax = -516i64 as u64; // -ERESTART_RESTARTBLOCK
+
+        assert_eq!(restore(pid, regs), 44);
+    }
+}
@@ -0,0 +1,312 @@
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    fs::{self, DirBuilder},
+    io::{IoSlice, Read},
+    mem::size_of,
+    os::{
+        fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+        unix::{
+            ffi::OsStrExt,
+            fs::{DirBuilderExt, FileTypeExt},
+            net::{UnixListener, UnixStream},
+        },
+    },
+    path::{Path, PathBuf},
+    process,
+};
+
+use escapepod_common::{
+    anyhow::{bail, Context, Result},
+    nix::{
+        errno::Errno,
+        sys::socket::{
+            bind, connect, listen, send, sendmsg, socket, socketpair, AddressFamily,
+            ControlMessage, MsgFlags, SockFlag, SockType, UnixAddr,
+        },
+    },
+    proto::{FdSocketUnix, FdType, Process, SocketQueue, SocketUnixState},
+    tracing::{debug, warn},
+};
+
+// recreates the unix sockets of the trees, bound ones first so that those
+// connecting to them by name find them
+pub(crate) fn create_unix_sockets(
+    procs: &[Process],
+    queues: &[SocketQueue],
+) -> Result<HashMap<u64, OwnedFd>> {
+    let mut sockets = HashMap::new();
+    for proc in procs.iter().flat_map(|i| i.self_and_descendents()) {
+        for fd in proc.fd_table.iter() {
+            if let FdType::SocketUnix(s) = &fd.r#type {
+                sockets.insert(s.inode, s);
+            }
+        }
+    }
+
+    let mut created = HashMap::new();
+    for s in sockets.values() {
+        let Some(name) = &s.name else {
+            continue;
+        };
+        let backlog = match s.state {
+            SocketUnixState::Listen { backlog } => backlog,
+            SocketUnixState::Unconnected | SocketUnixState::Connect(_) => 0,
+            // the name belongs to the listening socket it was accepted from
+            SocketUnixState::Pair(_) => continue,
+        };
+
+        let sock = new_socket(s)?;
+        let addr = unix_addr(name)?;
+        if fs::metadata(name).is_ok_and(|stat| stat.file_type().is_socket()) {
+            remove_socket_file(s, name)?;
+        }
+        bind(sock.as_raw_fd(), &addr).with_context(|| format!("failed to bind {name:?}"))?;
+        if matches!(s.state, SocketUnixState::Listen { .. }) {
+            listen(sock.as_raw_fd(), backlog as _)?;
+        }
+        created.insert(s.inode, sock);
+    }
+
+    for s in sockets.values() {
+        match &s.state {
+            SocketUnixState::Pair(peer) => {
+                if created.contains_key(&s.inode) {
+                    continue;
+                }
+                let (a, b) = socketpair(
+                    AddressFamily::Unix,
+                    SockType::try_from(s.r#type)?,
+                    None,
+                    SockFlag::SOCK_CLOEXEC,
+                )?;
+                let (a, b) = unsafe { (OwnedFd::from_raw_fd(a), OwnedFd::from_raw_fd(b)) };
+                created.insert(s.inode, a);
+                created.insert(*peer, b);
+            }
+            SocketUnixState::Connect(name) => {
+                let sock = match created.entry(s.inode) {
+                    Entry::Occupied(e) => e.into_mut(),
+                    Entry::Vacant(e) => e.insert(new_socket(s)?),
+                };
+                let addr = unix_addr(name)?;
+                // the other end went away, which the process sees as a
+                // socket that was never connected
+                if let Err(e) = connect(sock.as_raw_fd(), &addr) {
+                    warn!(
+                        "failed to reconnect unix socket {} to {name:?}: {e}",
+                        s.inode
+                    );
+                }
+            }
+            SocketUnixState::Unconnected | SocketUnixState::Listen { .. } => {
+                if let Entry::Vacant(e) = created.entry(s.inode) {
+                    e.insert(new_socket(s)?);
+                }
+            }
+        }
+    }
+
+    // what was queued for a socket is sent again by its peer
+    for queue in queues {
+        let Some(SocketUnixState::Pair(peer)) = sockets.get(&queue.inode).map(|s| &s.state) else {
+            bail!("unexpected queue of unix socket {}", queue.inode);
+        };
+        for packet in queue.packets.iter() {
+            send(created[peer].as_raw_fd(), packet, MsgFlags::MSG_DONTWAIT)
+                .with_context(|| format!("failed to queue data on unix socket {}", queue.inode))?;
+        }
+        debug!(
+            "restored {} packets of unix socket {}",
+            queue.packets.len(),
+            queue.inode
+        );
+    }
+
+    Ok(created)
+}
+
+// a socket file is only taken over once nothing accepts connections on it
+// any more, or when escaping to the same host and it still belongs to the
+// frozen socket, which goes away with the origin processes
+fn remove_socket_file(s: &FdSocketUnix, name: &Path) -> Result<()> {
+    // the backlog of the frozen socket may be full, which would block us
+    let probe = socket(
+        AddressFamily::Unix,
+        SockType::try_from(s.r#type)?,
+        SockFlag::SOCK_CLOEXEC | SockFlag::SOCK_NONBLOCK,
+        None,
+    )?;
+    let probe = unsafe { OwnedFd::from_raw_fd(probe) };
+    match connect(probe.as_raw_fd(), &unix_addr(name)?) {
+        // left behind by a socket which no longer exists
+        Err(Errno::ECONNREFUSED) => {}
+        // a full backlog still means something is bound to it
+        Ok(()) | Err(Errno::EAGAIN | Errno::EINPROGRESS)
+            if bound_inodes(name)?.contains(&s.inode) => {}
+        Ok(()) | Err(Errno::EAGAIN | Errno::EINPROGRESS) => {
+            bail!("{name:?} is in use by another socket")
+        }
+        Err(e) => return Err(e).with_context(|| format!("failed to probe {name:?}")),
+    }
+    drop(probe);
+
+    fs::remove_file(name).with_context(|| format!("failed to remove {name:?}"))
+}
+
+// inodes of the sockets of our network namespace bound to name, which
+// includes those accepted from a listening socket
+fn bound_inodes(name: &Path) -> Result<Vec<u64>> {
+    let unix = fs::read_to_string("/proc/net/unix")?;
+
+    let mut inodes = vec![];
+    // Num RefCount Protocol Flags Type St Inode Path
+    for line in unix.lines().skip(1) {
+        let fields = line.split_whitespace().collect::<Vec<_>>();
+        if fields.len() > 7 && Path::new(&fields[7..].join(" ")) == name {
+            inodes.push(fields[6].parse()?);
+        }
+    }
+
+    Ok(inodes)
+}
+
+fn new_socket(s: &FdSocketUnix) -> Result<OwnedFd> {
+    let sock = socket(
+        AddressFamily::Unix,
+        SockType::try_from(s.r#type)?,
+        SockFlag::SOCK_CLOEXEC,
+        None,
+    )?;
+
+    Ok(unsafe { OwnedFd::from_raw_fd(sock) })
+}
+
+fn unix_addr(name: &Path) -> Result<UnixAddr> {
+    Ok(match name.as_os_str().as_bytes() {
+        [0, name @ ..] => UnixAddr::new_abstract(name)?,
+        _ => UnixAddr::new(name)?,
+    })
+}
+
+// hands the sockets to the restore stubs, which cannot reopen them
+// through procfs like pipes, each stub sends the inodes it wants and gets the
+// sockets back in that order. we cannot spawn threads once we have created
+// the pid namespace so the stubs are served from the ready loop
+pub(crate) struct SocketServer {
+    dir: PathBuf,
+    listener: UnixListener,
+    sockets: HashMap<u64, OwnedFd>,
+}
+
+impl SocketServer {
+    pub(crate) fn start(sockets: HashMap<u64, OwnedFd>) -> Result<Self> {
+        // only we may connect to it
+        let dir = std::env::temp_dir().join(format!("escapepod-{}", process::id()));
+        DirBuilder::new().mode(0o700).create(&dir)?;
+        let listener = UnixListener::bind(dir.join("sockets"))?;
+
+        Ok(Self {
+            dir,
+            listener,
+            sockets,
+        })
+    }
+
+    pub(crate) fn path(&self) -> PathBuf {
+        self.dir.join("sockets")
+    }
+
+    // serves a stub which has connected to us
+    pub(crate) fn accept(&self) -> Result<()> {
+        let (mut stream, _) = self.listener.accept()?;
+        serve(&mut stream, &self.sockets)
+    }
+
+    // drops our references to the sockets, which the stubs hold by now
+    pub(crate) fn stop(self) -> Result<()> {
+        fs::remove_dir_all(&self.dir)?;
+
+        Ok(())
+    }
+}
+
+impl AsRawFd for SocketServer {
+    fn as_raw_fd(&self) -> RawFd {
+        self.listener.as_raw_fd()
+    }
+}
+
+fn serve(stream: &mut UnixStream, sockets: &HashMap<u64, OwnedFd>) -> Result<()> {
+    let mut len = [0u8; size_of::<u32>()];
+    stream.read_exact(&mut len)?;
+    let mut inodes = vec![0u8; u32::from_ne_bytes(len) as usize * size_of::<u64>()];
+    stream.read_exact(&mut inodes)?;
+
+    for inode in inodes.chunks_exact(size_of::<u64>()) {
+        let inode = u64::from_ne_bytes(inode.try_into()?);
+        let Some(sock) = sockets.get(&inode) else {
+            bail!("unexpected socket {inode}");
+        };
+        sendmsg::<UnixAddr>(
+            stream.as_raw_fd(),
+            &[IoSlice::new(&[0])],
+            &[ControlMessage::ScmRights(&[sock.as_raw_fd()])],
+            MsgFlags::empty(),
+            None,
+        )?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::MetadataExt;
+
+    use escapepod_common::libc;
+
+    use super::*;
+
+    #[test]
+    fn test_remove_socket_file_with_full_backlog() {
+        let name = std::env::temp_dir().join(format!("escapepod-probe-{}.sock", process::id()));
+        let _ = fs::remove_file(&name);
+        let listener = UnixListener::bind(&name).unwrap();
+        listen(listener.as_raw_fd(), 0).unwrap();
+        // nobody accepts, so the backlog fills up
+        let mut clients = vec![];
+        loop {
+            let client = unsafe {
+                OwnedFd::from_raw_fd(
+                    socket(
+                        AddressFamily::Unix,
+                        SockType::Stream,
+                        SockFlag::SOCK_NONBLOCK,
+                        None,
+                    )
+                    .unwrap(),
+                )
+            };
+            match connect(client.as_raw_fd(), &unix_addr(&name).unwrap()) {
+                Ok(()) => clients.push(client),
+                Err(Errno::EAGAIN) => break,
+                Err(e) => panic!("failed to connect: {e}"),
+            }
+        }
+
+        let inode = fs::metadata(format!("/proc/self/fd/{}", listener.as_raw_fd()))
+            .unwrap()
+            .ino();
+        let mut s = FdSocketUnix {
+            inode: inode + 1,
+            r#type: libc::SOCK_STREAM,
+            state: SocketUnixState::Unconnected,
+            name: Some(name.clone()),
+        };
+        assert!(remove_socket_file(&s, &name).is_err());
+        assert!(name.exists());
+        s.inode = inode;
+        remove_socket_file(&s, &name).unwrap();
+        assert!(!name.exists());
+    }
+}
@@ -1,28 +1,24 @@
 pub mod args;
 pub mod destination;
 pub mod origin;
+mod sockopt;
 
-use std::{
-    env,
-    net::{SocketAddr, ToSocketAddrs},
-    process,
-};
+use std::{env, process};
 
 use crate::args::Args;
 use clap::Parser;
+use escapepod_common::transport::Address;
 
+#[allow(clippy::len_zero)]
 pub fn main() {
     escapepod_common::tracing::init();
     let args = Args::parse();
     assert!(args.exec.len() > 0);
 
     let code = if let Ok(addr) = env::var("ESCAPEE_ADDR") {
-        let addr: SocketAddr = addr
-            .to_socket_addrs()
-            .expect(&format!("failed to parse ESCAPE_ADDR={addr} var"))
-            .into_iter()
-            .next()
-            .unwrap();
+        let addr: Address = addr
+            .parse()
+            .unwrap_or_else(|e| panic!("failed to parse ESCAPEE_ADDR={addr} var: {e:#}"));
         crate::destination::receive(args, addr)
     } else {
         crate::origin::begin(args)
@@ -0,0 +1,157 @@
+use std::{fs, os::unix::fs::MetadataExt, time::Duration};
+
+use escapepod_common::{
+    anyhow::{bail, Context, Result},
+    proto::{EpollTarget, FdEpoll, FdEventFd, FdSignalFd, FdTimerFd, FdType},
+};
+
+// the anon inode fd of pid, whose state is read from its fdinfo
+pub(crate) fn anon_inode(pid: i32, fd: i32, name: &str) -> Result<FdType> {
+    let info = fs::read_to_string(format!("/proc/{pid}/fdinfo/{fd}"))?;
+    let mut fields = info
+        .lines()
+        .filter_map(|l| l.split_once(':'))
+        .map(|(k, v)| (k, v.trim()));
+
+    let r#type = match name {
+        "[eventfd]" => {
+            let mut eventfd = FdEventFd {
+                count: 0,
+                semaphore: false,
+            };
+            for (k, v) in fields {
+                match k {
+                    "eventfd-count" => eventfd.count = u64::from_str_radix(v, 16)?,
+                    // older kernels do not tell, the flag is then lost
+                    "eventfd-semaphore" => eventfd.semaphore = v == "1",
+                    _ => {}
+                }
+            }
+            FdType::EventFd(eventfd)
+        }
+        "[timerfd]" => {
+            let mut timerfd = FdTimerFd {
+                clock: 0,
+                ticks: 0,
+                value: Duration::ZERO,
+                interval: Duration::ZERO,
+            };
+            for (k, v) in fields {
+                match k {
+                    "clockid" => timerfd.clock = v.parse()?,
+                    "ticks" => timerfd.ticks = v.parse()?,
+                    "it_value" => timerfd.value = parse_timespec(v)?,
+                    "it_interval" => timerfd.interval = parse_timespec(v)?,
+                    _ => {}
+                }
+            }
+            // a periodic timer which has expired is only rearmed once read,
+            // until then the time left shows as zero
+            if timerfd.value.is_zero() {
+                timerfd.value = timerfd.interval;
+            }
+            FdType::TimerFd(timerfd)
+        }
+        "[signalfd]" => {
+            let Some((_, mask)) = fields.find(|(k, _)| *k == "sigmask") else {
+                bail!("no sigmask in fdinfo of signalfd {fd} of {pid}");
+            };
+            FdType::SignalFd(FdSignalFd {
+                mask: u64::from_str_radix(mask, 16)?,
+            })
+        }
+        "[eventpoll]" => FdType::Epoll(FdEpoll {
+            targets: info
+                .lines()
+                .filter(|l| l.starts_with("tfd:"))
+                .map(|l| parse_epoll_target(pid, l))
+                .collect::<Result<_>>()
+                .with_context(|| format!("failed to parse epoll {fd} of {pid}"))?,
+        }),
+        _ => bail!("fd {fd} of {pid} is an unsupported anon inode {name}"),
+    };
+
+    Ok(r#type)
+}
+
+// (sec, nsec)
+fn parse_timespec(v: &str) -> Result<Duration> {
+    let Some((sec, nsec)) = v
+        .strip_prefix('(')
+        .and_then(|v| v.strip_suffix(')'))
+        .and_then(|v| v.split_once(','))
+    else {
+        bail!("malformed timespec {v}");
+    };
+
+    Ok(Duration::new(sec.trim().parse()?, nsec.trim().parse()?))
+}
+
+// tfd: 3 events: 19 data: 3 pos:0 ino:1a sdev:10, where events, data and
+// ino are hex
+fn parse_epoll_target(pid: i32, line: &str) -> Result<EpollTarget> {
+    let mut words = line.split_whitespace();
+    let mut value = |key: &str| match words.next() {
+        Some(k) if k == key => words.next().context("truncated epoll target"),
+        // pos and ino are not followed by a space
+        Some(k) => k.strip_prefix(key).context("malformed epoll target"),
+        None => bail!("truncated epoll target"),
+    };
+    let fd = value("tfd:")?.parse()?;
+    let events = u32::from_str_radix(value("events:")?, 16)?;
+    let data = u64::from_str_radix(value("data:")?, 16)?;
+    value("pos:")?;
+    let ino = u64::from_str_radix(value("ino:")?, 16)?;
+
+    // the registration outlives the fd it was made through as long as the
+    // file is open elsewhere, which we cannot register again
+    match fs::metadata(format!("/proc/{pid}/fd/{fd}")) {
+        Ok(stat) if stat.ino() == ino => {}
+        _ => bail!("epoll target {fd} of {pid} has been closed or replaced"),
+    }
+
+    Ok(EpollTarget { fd, events, data })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::fd::AsRawFd;
+
+    use super::*;
+
+    #[test]
+    fn test_parse_timespec() {
+        assert_eq!(parse_timespec("(1, 500)").unwrap(), Duration::new(1, 500));
+        assert_eq!(parse_timespec("(0, 0)").unwrap(), Duration::ZERO);
+        assert!(parse_timespec("1, 500").is_err());
+        assert!(parse_timespec("(1)").is_err());
+        assert!(parse_timespec("(x, 1)").is_err());
+    }
+
+    #[test]
+    fn test_parse_epoll_target() {
+        let file = fs::File::open("/proc/self/exe").unwrap();
+        let (fd, ino) = (file.as_raw_fd(), file.metadata().unwrap().ino());
+        let pid = std::process::id() as i32;
+
+        let target = parse_epoll_target(
+            pid,
+            &format!(
+                "tfd: {fd:>8} events:       19 data:               2a  pos:0 ino:{ino:x} sdev:10"
+            ),
+        )
+        .unwrap();
+        assert_eq!(target.fd, fd);
+        assert_eq!(target.events, 0x19);
+        assert_eq!(target.data, 0x2a);
+
+        // the fd now refers to another file
+        let line = format!(
+            "tfd: {fd} events: 19 data: 2a pos:0 ino:{:x} sdev:10",
+            ino + 1
+        );
+        assert!(parse_epoll_target(pid, &line).is_err());
+        assert!(parse_epoll_target(pid, &format!("tfd: {fd} events: 19")).is_err());
+        assert!(parse_epoll_target(pid, &format!("tfd: {fd} data: 2a events: 19")).is_err());
+    }
+}
@@ -0,0 +1,298 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{self, Metadata},
+    io::Read,
+    os::unix::fs::MetadataExt,
+    path::{self, Path, PathBuf},
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+use escapepod_common::{
+    anyhow::{bail, Context, Result},
+    delta::{self, Checksum},
+    proto::{
+        DestinationMessage, EscapeeMessage, FdType, File, FileBlocks, FileData, FileId,
+        FileRequest, FileType, Hash, Process, FILE_CHUNK,
+    },
+    tracing::{debug, warn},
+    transport::ServerConnection,
+};
+
+static FILE_ID: AtomicU32 = AtomicU32::new(0);
+
+pub(crate) fn next_file_id() -> FileId {
+    FILE_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+// the hashes of the synced files along with the size and mtime each had, a
+// file which still has them is not hashed again
+#[derive(Default)]
+pub(crate) struct SyncedHashes(HashMap<PathBuf, (u64, (i64, i64), Hash)>);
+
+impl SyncedHashes {
+    fn hash(&mut self, path: &Path, stat: &Metadata) -> Result<Hash> {
+        let (size, mtime) = (stat.size(), (stat.mtime(), stat.mtime_nsec()));
+        match self.0.get(path) {
+            Some((s, m, hash)) if (*s, *m) == (size, mtime) => Ok(*hash),
+            _ => {
+                let hash = delta::hash_reader(fs::File::open(path)?)?;
+                self.0.insert(path.to_path_buf(), (size, mtime, hash));
+                Ok(hash)
+            }
+        }
+    }
+}
+
+// sends the manifest of every file under paths, parents before their
+// children so the destination can create them in order, followed by what the
+// destination lacks of the files it requests. this runs once while the
+// processes run, which moves most of the hashing, the signatures and the data
+// out of the freeze, and again once they are frozen for what changed since
+pub(crate) fn sync(
+    paths: &[PathBuf],
+    hashes: &mut SyncedHashes,
+    con: &mut ServerConnection,
+) -> Result<()> {
+    let mut files = vec![];
+    for path in paths {
+        let path = path::absolute(path)?;
+        walk(&path, hashes, &mut files).with_context(|| format!("failed to sync {path:?}"))?;
+    }
+    if files.is_empty() {
+        return Ok(());
+    }
+
+    con.send(EscapeeMessage::FileManifest(
+        files.iter().map(|(f, _)| f.clone()).collect(),
+    ))?;
+    let requests = match con.recv::<DestinationMessage>()? {
+        DestinationMessage::FileRequest(requests) => requests,
+        msg => bail!("unexpected destination message: {msg:?}"),
+    };
+    debug!(
+        "{} of {} files differ at the destination",
+        requests.len(),
+        files.len()
+    );
+
+    let paths = files
+        .iter()
+        .map(|(f, path)| (f.id, path.as_path()))
+        .collect::<HashMap<_, _>>();
+    for request in requests {
+        let Some(path) = paths.get(&request.id) else {
+            bail!("destination requested unexpected file {}", request.id);
+        };
+        let file = fs::File::open(path)?;
+        match request.blocks.is_empty() {
+            true => send_data(request.id, file, con),
+            false => send_delta(&request, file, con),
+        }
+        .with_context(|| format!("failed to send {path:?}"))?;
+    }
+
+    Ok(())
+}
+
+fn walk(path: &Path, hashes: &mut SyncedHashes, files: &mut Vec<(File, PathBuf)>) -> Result<()> {
+    let stat = fs::symlink_metadata(path)?;
+    let r#type = match stat.file_type() {
+        t if t.is_file() => FileType::Regular {
+            size: stat.size(),
+            hash: hashes.hash(path, &stat)?,
+        },
+        t if t.is_dir() => FileType::Directory,
+        t if t.is_symlink() => FileType::Symlink(fs::read_link(path)?),
+        _ => {
+            warn!("not syncing {path:?} which is neither a file, a directory nor a symlink");
+            return Ok(());
+        }
+    };
+
+    let is_dir = r#type == FileType::Directory;
+    files.push((file(path.to_path_buf(), &stat, r#type), path.to_path_buf()));
+    if is_dir {
+        let mut entries = fs::read_dir(path)?
+            .map(|e| Ok(e?.path()))
+            .collect::<Result<Vec<_>>>()?;
+        entries.sort();
+        for entry in entries {
+            walk(&entry, hashes, files)?;
+        }
+    }
+
+    Ok(())
+}
+
+// sends the file as a mix of data and blocks the destination already has,
+// found wherever they are in the file by rolling their weak checksum along it.
+// the file is read rather than mapped as the processes may truncate it while
+// they run
+fn send_delta(request: &FileRequest, mut file: fs::File, con: &mut ServerConnection) -> Result<()> {
+    let mut data = vec![];
+    file.read_to_end(&mut data)?;
+
+    Delta::new(request, con).send(&data)
+}
+
+struct Delta<'a> {
+    id: FileId,
+    block_size: usize,
+    // weak checksums to the index and strong hash of every block with it
+    blocks: HashMap<u32, Vec<(u64, &'a Hash)>>,
+    con: &'a mut ServerConnection,
+    run: Option<FileBlocks>,
+}
+
+impl<'a> Delta<'a> {
+    fn new(request: &'a FileRequest, con: &'a mut ServerConnection) -> Self {
+        let mut blocks: HashMap<_, Vec<_>> = HashMap::new();
+        for (i, block) in request.blocks.iter().enumerate() {
+            blocks
+                .entry(block.weak)
+                .or_default()
+                .push((i as u64, &block.strong));
+        }
+
+        Self {
+            id: request.id,
+            block_size: request.block_size as usize,
+            blocks,
+            con,
+            run: None,
+        }
+    }
+
+    fn send(mut self, data: &[u8]) -> Result<()> {
+        let block_size = self.block_size;
+        // start of the data which has not been sent yet
+        let mut pending = 0;
+        let mut pos = 0;
+        let mut sum = None;
+        while pos + block_size <= data.len() {
+            let block = &data[pos..pos + block_size];
+            let s = sum.get_or_insert_with(|| Checksum::new(block));
+            let Some(index) = self.find(s.value(), block) else {
+                if pos + block_size < data.len() {
+                    s.roll(data[pos], data[pos + block_size]);
+                }
+                pos += 1;
+                continue;
+            };
+
+            if pending < pos {
+                self.send_data(&data[pending..pos])?;
+            }
+            match &mut self.run {
+                Some(run) if run.index + run.count == index => run.count += 1,
+                _ => {
+                    self.flush()?;
+                    self.run = Some(FileBlocks {
+                        id: self.id,
+                        index,
+                        count: 1,
+                    });
+                }
+            }
+            pos += block_size;
+            pending = pos;
+            sum = None;
+        }
+        if pending < data.len() {
+            self.send_data(&data[pending..])?;
+        }
+
+        self.flush()
+    }
+
+    fn find(&self, weak: u32, block: &[u8]) -> Option<u64> {
+        let candidates = self.blocks.get(&weak)?;
+        let strong = delta::hash(block);
+        candidates
+            .iter()
+            .find(|(_, h)| **h == strong)
+            .map(|(i, _)| *i)
+    }
+
+    fn send_data(&mut self, data: &[u8]) -> Result<()> {
+        self.flush()?;
+        for data in data.chunks(FILE_CHUNK) {
+            let data = self.con.payload(data.to_vec())?;
+            self.con
+                .send(EscapeeMessage::FileData(FileData { id: self.id, data }))?;
+        }
+
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        if let Some(run) = self.run.take() {
+            self.con.send(EscapeeMessage::FileBlocks(run))?;
+        }
+
+        Ok(())
+    }
+}
+
+pub(crate) fn send_data(id: FileId, mut file: fs::File, con: &mut ServerConnection) -> Result<()> {
+    let mut buf = vec![0u8; FILE_CHUNK];
+    loop {
+        let len = file.read(&mut buf)?;
+        if len == 0 {
+            return Ok(());
+        }
+        let data = con.payload(buf[..len].to_vec())?;
+        con.send(EscapeeMessage::FileData(FileData { id, data }))?;
+    }
+}
+
+// the deleted files open in the trees, ready to be read
+pub(crate) fn deleted_files(procs: &[Process]) -> Result<Vec<(File, fs::File)>> {
+    let mut seen = HashSet::new();
+    let mut files = vec![];
+    for proc in procs.iter().flat_map(|i| i.self_and_descendents()) {
+        for fd in proc.fd_table.iter() {
+            let FdType::DeletedFile(f) = &fd.r#type else {
+                continue;
+            };
+            if !seen.insert(f.file) {
+                continue;
+            }
+
+            // the file can still be opened through the fd
+            let path = format!("/proc/{}/fd/{}", proc.pid, fd.fd);
+            let link = fs::read_link(&path)?;
+            let link = link.to_string_lossy();
+            let data = fs::File::open(&path)?;
+            let stat = data.metadata()?;
+            debug!(
+                "deleted file {link} of {} holds {} bytes",
+                proc.pid,
+                stat.size()
+            );
+
+            let mut file = file(
+                link.trim_end_matches(" (deleted)").into(),
+                &stat,
+                FileType::Deleted,
+            );
+            file.id = f.file;
+            files.push((file, data));
+        }
+    }
+
+    Ok(files)
+}
+
+fn file(path: PathBuf, stat: &Metadata, r#type: FileType) -> File {
+    File {
+        id: next_file_id(),
+        uid: stat.uid(),
+        gid: stat.gid(),
+        mode: stat.mode(),
+        mtime: stat.mtime(),
+        mtime_nsec: stat.mtime_nsec(),
+        path,
+        r#type,
+    }
+}
@@ -0,0 +1,243 @@
+use std::{
+    collections::HashSet,
+    net::{SocketAddr, SocketAddrV4, SocketAddrV6},
+    os::fd::{AsRawFd, OwnedFd, RawFd},
+};
+
+use escapepod_common::{
+    anyhow::{bail, Context, Error, Result},
+    libc::{self, c_int},
+    nix::{
+        errno::Errno,
+        sys::socket::{getpeername, getsockname, recv, MsgFlags, SockaddrStorage},
+    },
+    proto::{FdSocketIp, FdType, Process, SocketIpBind, SocketIpState, TcpConnection, TcpWindow},
+    tracing::{debug, warn},
+};
+
+use super::sockets::pidfd_getfd;
+use crate::sockopt;
+
+const TCP_ESTABLISHED: u8 = 1;
+const TCP_CLOSE: u8 = 7;
+const TCP_LISTEN: u8 = 10;
+const TCP_RECV_QUEUE: c_int = 1;
+const TCP_SEND_QUEUE: c_int = 2;
+
+// the ip socket of fd of pid, tcp sockets have to be connected, listening
+// or not yet in use while udp sockets must not be connected
+pub(crate) fn socket(pid: i32, fd: i32, inode: u64) -> Result<FdSocketIp> {
+    let owned = pidfd_getfd(pid, fd)?;
+    let sock = owned.as_raw_fd();
+
+    let domain: c_int = sockopt::get(sock, libc::SOL_SOCKET, libc::SO_DOMAIN)?;
+    let r#type: c_int = sockopt::get(sock, libc::SOL_SOCKET, libc::SO_TYPE)?;
+    let protocol: c_int = sockopt::get(sock, libc::SOL_SOCKET, libc::SO_PROTOCOL)?;
+    if !matches!(domain, libc::AF_INET | libc::AF_INET6) {
+        bail!("socket {inode} of {pid} is neither a unix nor an ip socket");
+    }
+
+    let state = match (r#type, protocol) {
+        (libc::SOCK_STREAM, libc::IPPROTO_TCP) => {
+            // tcpi_state comes first, tcpi_sacked holds the backlog of a
+            // listening socket
+            let info: [u8; 32] = sockopt::get(sock, libc::IPPROTO_TCP, libc::TCP_INFO)?;
+            match info[0] {
+                TCP_ESTABLISHED => SocketIpState::Connect {
+                    local: socket_addr(getsockname(sock)?)?,
+                    peer: socket_addr(getpeername(sock)?)?,
+                },
+                TCP_LISTEN => {
+                    let backlog = u32::from_ne_bytes(info[28..32].try_into()?);
+                    SocketIpState::Bind(bound_socket(sock, r#type, Some(backlog))?)
+                }
+                TCP_CLOSE => SocketIpState::Bind(bound_socket(sock, r#type, None)?),
+                state => bail!("tcp socket {inode} of {pid} is in unsupported state {state}"),
+            }
+        }
+        (libc::SOCK_DGRAM, libc::IPPROTO_UDP) => {
+            if getpeername::<SockaddrStorage>(sock).is_ok() {
+                bail!("udp socket {inode} of {pid} is connected, which is not supported");
+            }
+            SocketIpState::Bind(bound_socket(sock, r#type, None)?)
+        }
+        _ => bail!("ip socket {inode} of {pid} is neither a tcp nor a udp socket"),
+    };
+
+    Ok(FdSocketIp { inode, state })
+}
+
+fn bound_socket(sock: RawFd, r#type: c_int, backlog: Option<u32>) -> Result<SocketIpBind> {
+    let flag = |level, name| Ok::<_, Error>(sockopt::get::<c_int>(sock, level, name)? != 0);
+    let addr = socket_addr(getsockname(sock)?)?;
+
+    Ok(SocketIpBind {
+        addr,
+        r#type,
+        backlog,
+        reuse_addr: flag(libc::SOL_SOCKET, libc::SO_REUSEADDR)?,
+        reuse_port: flag(libc::SOL_SOCKET, libc::SO_REUSEPORT)?,
+        v6_only: addr.is_ipv6() && flag(libc::IPPROTO_IPV6, libc::IPV6_V6ONLY)?,
+    })
+}
+
+fn socket_addr(addr: SockaddrStorage) -> Result<SocketAddr> {
+    if let Some(addr) = addr.as_sockaddr_in() {
+        return Ok(SocketAddrV4::from(*addr).into());
+    }
+    if let Some(addr) = addr.as_sockaddr_in6() {
+        return Ok(SocketAddrV6::from(*addr).into());
+    }
+
+    bail!("unexpected socket address {addr}")
+}
+
+// the state of every tcp connection of the trees. the connections are left in
+// repair mode so the kernel lets go of them silently once the processes are
+// killed, rather than resetting them
+pub(crate) fn tcp_connections(procs: &[Process]) -> Result<(Vec<TcpConnection>, RepairedSockets)> {
+    let mut seen = HashSet::new();
+    let mut conns = vec![];
+    let mut repaired = RepairedSockets(vec![]);
+    for proc in procs.iter().flat_map(|i| i.self_and_descendents()) {
+        for fd in proc.fd_table.iter() {
+            let FdType::SocketIp(s) = &fd.r#type else {
+                continue;
+            };
+            if !matches!(s.state, SocketIpState::Connect { .. }) || !seen.insert(s.inode) {
+                continue;
+            }
+
+            let sock = repaired.enter(pidfd_getfd(proc.pid, fd.fd)?)?;
+            let conn = dump_connection(sock, s.inode)
+                .with_context(|| format!("failed to dump tcp connection {}", s.inode))?;
+            debug!(
+                "tcp connection {} of {} holds {} bytes to send and {} to read",
+                s.inode,
+                proc.pid,
+                conn.send_queue.len(),
+                conn.recv_queue.len()
+            );
+            conns.push(conn);
+        }
+    }
+
+    Ok((conns, repaired))
+}
+
+// tcp sockets of the trees in repair mode, which they leave again when this
+// is dropped so that the connections keep working if the escape fails
+pub(crate) struct RepairedSockets(Vec<OwnedFd>);
+
+impl RepairedSockets {
+    fn enter(&mut self, sock: OwnedFd) -> Result<RawFd> {
+        sockopt::set(sock.as_raw_fd(), libc::IPPROTO_TCP, libc::TCP_REPAIR, &1)
+            .context("failed to enter repair mode, this requires CAP_NET_ADMIN")?;
+        self.0.push(sock);
+
+        Ok(self.0.last().unwrap().as_raw_fd())
+    }
+
+    // closes the sockets still in repair mode, once the processes holding
+    // them are gone this lets the destination take over the connections
+    pub(crate) fn release(mut self) {
+        self.0.clear();
+    }
+}
+
+impl Drop for RepairedSockets {
+    fn drop(&mut self) {
+        for sock in self.0.iter() {
+            if let Err(e) = sockopt::set(sock.as_raw_fd(), libc::IPPROTO_TCP, libc::TCP_REPAIR, &0)
+            {
+                warn!("failed to take tcp socket out of repair mode: {e}");
+            }
+        }
+    }
+}
+
+fn dump_connection(sock: RawFd, inode: u64) -> Result<TcpConnection> {
+    let (recv_seq, recv_queue) = dump_queue(sock, TCP_RECV_QUEUE, libc::FIONREAD)?;
+    let (send_seq, send_queue) = dump_queue(sock, TCP_SEND_QUEUE, libc::TIOCOUTQ)?;
+    let unsent = ioctl(sock, libc::SIOCOUTQNSD)?;
+
+    // tcpi_options and the window scales that follow it in struct tcp_info
+    let info: [u8; 7] = sockopt::get(sock, libc::IPPROTO_TCP, libc::TCP_INFO)?;
+    // in repair mode this is the mss clamp negotiated with the peer
+    let mss: u32 = sockopt::get(sock, libc::IPPROTO_TCP, libc::TCP_MAXSEG)?;
+    let timestamp: u32 = sockopt::get(sock, libc::IPPROTO_TCP, libc::TCP_TIMESTAMP)?;
+    let window: TcpWindow = sockopt::get(sock, libc::IPPROTO_TCP, libc::TCP_REPAIR_WINDOW)?;
+
+    Ok(TcpConnection {
+        inode,
+        send_seq,
+        recv_seq,
+        send_queue,
+        unsent,
+        recv_queue,
+        mss,
+        options: info[5],
+        snd_wscale: info[6] & 0xf,
+        rcv_wscale: info[6] >> 4,
+        timestamp,
+        window,
+    })
+}
+
+// returns the sequence number following the queue and its contents
+fn dump_queue(sock: RawFd, queue: c_int, len_request: libc::c_ulong) -> Result<(u32, Vec<u8>)> {
+    sockopt::set(sock, libc::IPPROTO_TCP, libc::TCP_REPAIR_QUEUE, &queue)?;
+    let seq: u32 = sockopt::get(sock, libc::IPPROTO_TCP, libc::TCP_QUEUE_SEQ)?;
+
+    let mut buf = vec![0u8; ioctl(sock, len_request)? as usize];
+    if !buf.is_empty() {
+        let len = recv(sock, &mut buf, MsgFlags::MSG_PEEK | MsgFlags::MSG_DONTWAIT)?;
+        if len != buf.len() {
+            bail!("read {len} of {} bytes of tcp queue {queue}", buf.len());
+        }
+    }
+
+    Ok((seq, buf))
+}
+
+fn ioctl(sock: RawFd, request: libc::c_ulong) -> Result<u32> {
+    let mut value: c_int = 0;
+    if unsafe { libc::ioctl(sock, request, &mut value) } != 0 {
+        return Err(Errno::last().into());
+    }
+
+    Ok(value as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        net::{TcpListener, TcpStream},
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_repaired_sockets_leave_repair_mode_when_dropped() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut server, _) = listener.accept().unwrap();
+
+        let mut repaired = RepairedSockets(vec![]);
+        let sock = repaired
+            .enter(OwnedFd::from(client.try_clone().unwrap()))
+            .unwrap();
+        let repair: c_int = sockopt::get(sock, libc::IPPROTO_TCP, libc::TCP_REPAIR).unwrap();
+        assert_eq!(repair, 1);
+        drop(repaired);
+
+        let repair: c_int =
+            sockopt::get(client.as_raw_fd(), libc::IPPROTO_TCP, libc::TCP_REPAIR).unwrap();
+        assert_eq!(repair, 0);
+        client.write_all(b"still connected").unwrap();
+        let mut buf = [0u8; 15];
+        server.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"still connected");
+    }
+}
@@ -1,12 +1,20 @@
 use std::{
+    collections::HashSet,
     ffi::CString,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
     process::{self, Stdio},
     sync::mpsc,
     thread,
 };
 
 use escapepod_common::{
+    anyhow::Result,
+    crypto::{Key, KEY_ENV},
+    libc,
     nix::{
+        errno::Errno,
+        poll::{poll, PollFd, PollFlags},
         sys::{
             signal::{self, Signal},
             signalfd::SigSet,
@@ -14,19 +22,63 @@ use escapepod_common::{
         },
         unistd::{execvp, fork, ForkResult, Pid},
     },
-    proto::{Buffer, EscapeeMessage, MemoryMappingData},
+    procfs,
+    proto::{
+        Buffer, BufferId, DestinationMessage, EscapeeMessage, MemoryMappingData, PageRun, Payload,
+    },
     tracing::{debug, error, info},
-    transport::Server,
+    transport::{Address, Server, ServerConnection},
 };
 
 use crate::args::Args;
 
+mod anon;
+mod files;
+mod inet;
+mod precopy;
 mod proc;
+mod sockets;
+
+use precopy::PreCopy;
+
+// the address the destination is told to connect to. listening on every
+// interface says nothing about where we are, so the address of the interface
+// of the default route is given instead, or loopback on a host without one
+fn routable(addr: Address) -> Address {
+    let Address::Tcp(mut addr) = addr else {
+        return addr;
+    };
+    if addr.ip().is_unspecified() {
+        // connecting a udp socket only picks a route, nothing is sent
+        let (probe, loopback): (SocketAddr, IpAddr) = match addr {
+            SocketAddr::V4(_) => (([192, 0, 2, 1], 9).into(), Ipv4Addr::LOCALHOST.into()),
+            SocketAddr::V6(_) => (
+                (Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1), 9).into(),
+                Ipv6Addr::LOCALHOST.into(),
+            ),
+        };
+        let ip = UdpSocket::bind((addr.ip(), 0))
+            .and_then(|socket| {
+                socket.connect(probe)?;
+                socket.local_addr()
+            })
+            .map(|local| local.ip())
+            .unwrap_or(loopback);
+        addr.set_ip(ip);
+    }
+
+    Address::Tcp(addr)
+}
 
 pub fn begin(args: Args) -> i32 {
     debug!("starting from fresh");
 
-    let server = Server::listen(([0u8; 4], args.port).into()).expect("failed to bind");
+    let key = Key::generate().expect("failed to generate key");
+    let addr = args
+        .listen
+        .clone()
+        .unwrap_or(Address::Tcp(([0u8; 4], args.port).into()));
+    let server = Server::listen(&addr, key).expect("failed to bind");
 
     unsafe {
         match fork().expect("failed to fork") {
@@ -39,6 +91,7 @@ pub fn begin(args: Args) -> i32 {
     }
 }
 
+#[allow(clippy::needless_borrows_for_generic_args)]
 fn origin_server(args: Args, mut server: Server, child: Pid) -> i32 {
     info!("entrypoint process ({child:?}) started");
 
@@ -83,12 +136,16 @@ fn origin_server(args: Args, mut server: Server, child: Pid) -> i32 {
         move || {
             SigSet::all().thread_block().unwrap();
             debug!("waiting on child pid: {:?}", child);
-            let status = waitpid(child, None).expect("failed to wait");
-            let status = match status {
-                WaitStatus::Exited(_, status) => status,
-                WaitStatus::Signaled(_, signal, _) => 128 + (signal as i32),
-                WaitStatus::Stopped(_, _) => return,
-                _ => panic!("unexpected: {status:?}"),
+            // the child is traced while it is being frozen, so rather than blocking in
+            // waitpid and stealing the tracer's stop notifications we wait for it to exit
+            wait_for_exit(child).expect("failed to wait");
+            let status = loop {
+                match waitpid(child, None).expect("failed to wait") {
+                    WaitStatus::Exited(_, status) => break status,
+                    WaitStatus::Signaled(_, signal, _) => break 128 + (signal as i32),
+                    WaitStatus::Stopped(_, _) | WaitStatus::PtraceEvent(_, _, _) => continue,
+                    status => panic!("unexpected: {status:?}"),
+                }
             };
             let _ = tx.send(Event::ChildExited(status));
         }
@@ -103,9 +160,19 @@ fn origin_server(args: Args, mut server: Server, child: Pid) -> i32 {
     }
 
     debug!("running '{}' command", args.launch_pod_command);
-    let mut proc = process::Command::new("sh")
+    let addr = routable(server.addr());
+    debug!("destination is to connect to {addr}");
+    let mut command = process::Command::new("sh");
+    command
         .args(&["-c", &args.launch_pod_command])
-        .env("ESCAPEE_PORT", server.port().to_string())
+        // as the destination takes it, the bare port is only there for tcp
+        .env("ESCAPEE_ADDR", addr.to_string())
+        // the destination proves it was launched by us with the key
+        .env(KEY_ENV, server.key().to_hex());
+    if let Address::Tcp(addr) = addr {
+        command.env("ESCAPEE_PORT", addr.port().to_string());
+    }
+    let mut proc = command
         .stdin(Stdio::inherit())
         .stdout(Stdio::inherit())
         .stderr(Stdio::inherit())
@@ -122,30 +189,111 @@ fn origin_server(args: Args, mut server: Server, child: Pid) -> i32 {
     debug!("launch pod command executed successfully");
 
     info!("waiting for connection from destination");
-    let mut con = server.accept().unwrap();
-    info!("received connection from {}", con.peer_addr());
+    let mut con = server
+        .accept()
+        .expect("failed to accept connection from destination");
+    info!("received connection from {}", con.peer());
+    let codec = con.compress(args.compression);
+    debug!("compressing payloads with {:?}", codec.compression());
+
+    // whatever has not changed by the freeze need not be hashed again then
+    let hashes = proc::FileHashes::collect(child);
+    let mut synced = files::SyncedHashes::default();
+    files::sync(&args.path, &mut synced, &mut con).expect("failed to sync files");
+    let pre_copy = PreCopy::run(&args, child, &mut con).expect("failed to pre-copy process memory");
 
-    let procs = proc::freeze(&args, child).expect("failed to freeze processes");
+    let procs = proc::freeze(&args, child, &pre_copy, &hashes).expect("failed to freeze processes");
+    for pipe in proc::pipes(&procs).expect("failed to read pipes") {
+        con.send(EscapeeMessage::Pipe(pipe)).unwrap();
+    }
+    files::sync(&args.path, &mut synced, &mut con).expect("failed to sync files");
+    for (file, data) in files::deleted_files(&procs).expect("failed to read deleted files") {
+        let id = file.id;
+        con.send(EscapeeMessage::File(file)).unwrap();
+        files::send_data(id, data, &mut con).expect("failed to send deleted file");
+    }
+    for (memfd, data) in proc::memfds(&procs).expect("failed to read memfds") {
+        let id = memfd.file;
+        con.send(EscapeeMessage::MemFd(memfd)).unwrap();
+        files::send_data(id, data, &mut con).expect("failed to send memfd");
+    }
+    for queue in sockets::queues(&procs).expect("failed to read unix socket queues") {
+        con.send(EscapeeMessage::SocketQueue(queue)).unwrap();
+    }
+    let (conns, repaired) = inet::tcp_connections(&procs).expect("failed to dump tcp connections");
+    for conn in conns {
+        con.send(EscapeeMessage::TcpConnection(conn)).unwrap();
+    }
     con.send(EscapeeMessage::ProcessTrees(procs.clone()))
         .unwrap();
     info!("froze child processes");
+    let differing = match con.recv::<DestinationMessage>().unwrap() {
+        DestinationMessage::MappedFileRequest(ids) => ids.into_iter().collect::<HashSet<_>>(),
+        msg => panic!("unexpected destination message: {msg:?}"),
+    };
+    debug!("{} mapped files differ at the destination", differing.len());
 
     for proc in procs.iter().flat_map(|i| i.self_and_descendents()) {
         for mmap in &proc.mmaps {
-            if let MemoryMappingData::Buffer(id) = &mmap.data {
-                let data = proc::read_mmap(proc, mmap).expect("failed to read proc mmap");
-                con.send(EscapeeMessage::Buffer(Buffer::new(*id, data)))
-                    .unwrap();
+            let (start, end) = (mmap.address, mmap.address_end());
+            let buffers = match &mmap.data {
+                // only the pages dirtied since the last pre-copy round are left
+                MemoryMappingData::Buffer(id)
+                    if pre_copy.buffer(proc.pid, start, end) == Some(*id) =>
+                {
+                    let pages = proc::dirty_pages(proc.pid, start, end)
+                        .expect("failed to read proc pagemap");
+                    vec![(*id, start, pages, true)]
+                }
+                MemoryMappingData::Buffer(id) => {
+                    let pages = proc::resident_pages(proc.pid, start, end, mmap.perm)
+                        .expect("failed to read proc pagemap");
+                    vec![(*id, start, pages, false)]
+                }
+                MemoryMappingData::KernelVdso(id) => {
+                    vec![(*id, start, vec![PageRun::new(0, mmap.len)], false)]
+                }
+                // the pages past the end of the file cannot be read
+                MemoryMappingData::File(f) if differing.contains(&f.buffer) => {
+                    let len = f
+                        .size
+                        .saturating_sub(f.offset)
+                        .next_multiple_of(procfs::page_size())
+                        .min(mmap.len);
+                    vec![(f.buffer, start, vec![PageRun::new(0, len)], false)]
+                }
+                MemoryMappingData::File(f) => f
+                    .modified
+                    .iter()
+                    .map(|p| {
+                        (
+                            p.buffer,
+                            start + p.offset,
+                            vec![PageRun::new(0, p.len)],
+                            false,
+                        )
+                    })
+                    .collect(),
+                // sent along with the memfd
+                MemoryMappingData::MemFd(_) | MemoryMappingData::KernelVvar => vec![],
+            };
+            for (id, address, pages, update) in buffers {
+                send_buffer(&mut con, proc.pid, id, address, &pages, update)
+                    .expect("failed to send process memory");
             }
         }
     }
     info!("sent process memory");
 
-    // todo: files
-
     for proc in procs.iter().flat_map(|i| i.self_and_descendents()) {
         let _ = proc::kill(proc);
     }
+    // the destination takes over the tcp connections once the processes
+    // holding them are gone, those already reaped have nothing to wait for
+    for proc in procs.iter().flat_map(|i| i.self_and_descendents()) {
+        let _ = wait_for_exit(Pid::from_raw(proc.pid));
+    }
+    repaired.release();
 
     con.send(EscapeeMessage::Done).unwrap();
     drop(con);
@@ -153,6 +301,55 @@ fn origin_server(args: Args, mut server: Server, child: Pid) -> i32 {
     0
 }
 
+// sends the pages of the mapping at address as buffer id, piece by piece, and
+// returns the number of bytes sent
+fn send_buffer(
+    con: &mut ServerConnection,
+    pid: i32,
+    id: BufferId,
+    address: u64,
+    pages: &[PageRun],
+    update: bool,
+) -> Result<usize> {
+    let message = |buf| match update {
+        true => EscapeeMessage::BufferUpdate(buf),
+        false => EscapeeMessage::Buffer(buf),
+    };
+    if pages.is_empty() {
+        // the destination waits for at least one piece of every buffer
+        if !update {
+            con.send(message(Buffer::new(id, 0, Payload::raw(vec![]))))?;
+        }
+        return Ok(0);
+    }
+
+    let mut sent = 0;
+    proc::read_memory(pid, address, pages, |offset, data| {
+        sent += data.len();
+        let data = con.payload(data)?;
+        con.send(message(Buffer::new(id, offset, data)))
+    })?;
+
+    Ok(sent)
+}
+
+fn wait_for_exit(pid: Pid) -> Result<()> {
+    let pidfd = unsafe { libc::syscall(libc::SYS_pidfd_open, pid.as_raw(), 0) };
+    if pidfd < 0 {
+        return Err(Errno::last().into());
+    }
+    let pidfd = unsafe { OwnedFd::from_raw_fd(pidfd as _) };
+
+    // a pidfd becomes readable once the process has exited
+    loop {
+        match poll(&mut [PollFd::new(pidfd.as_raw_fd(), PollFlags::POLLIN)], -1) {
+            Ok(_) => return Ok(()),
+            Err(Errno::EINTR) => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
 unsafe fn origin_entrypoint_exec(args: Args) {
     // todo: new pgrp here?
     // only safe to exec here
@@ -161,6 +358,6 @@ unsafe fn origin_entrypoint_exec(args: Args) {
         .iter()
         .map(|i| CString::new(i.as_bytes().to_vec()).unwrap())
         .collect::<Vec<_>>();
-    execvp(exec[0].as_c_str(), &exec[..]).expect("failed to exec");
-    unreachable!()
+    let Err(e) = execvp(exec[0].as_c_str(), &exec[..]);
+    panic!("failed to exec: {e}")
 }
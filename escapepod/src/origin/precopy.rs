@@ -0,0 +1,139 @@
+use std::collections::HashMap;
+
+use escapepod_common::{
+    anyhow::Result,
+    nix::unistd::Pid,
+    procfs::process::Process,
+    proto::{BufferId, PageRun},
+    tracing::{debug, info, warn},
+    transport::ServerConnection,
+};
+
+use super::{proc, send_buffer};
+use crate::args::Args;
+
+// a round which dirtied less than this is followed by the final copy
+const DIRTY_THRESHOLD: usize = 4 << 20;
+
+// anonymous mappings copied while the processes were running, once frozen
+// only the pages dirtied since the last round are sent as updates
+#[derive(Default)]
+pub(crate) struct PreCopy {
+    buffers: HashMap<(i32, u64, u64), BufferId>,
+}
+
+// the pages of a mapping to send into its buffer, an update unless the
+// mapping is new
+struct PageCopy {
+    key: (i32, u64, u64),
+    id: BufferId,
+    pages: Vec<PageRun>,
+    update: bool,
+}
+
+impl PreCopy {
+    pub(crate) fn run(args: &Args, child: Pid, con: &mut ServerConnection) -> Result<Self> {
+        let mut pre_copy = Self::default();
+        if args.pre_copy_rounds == 0 {
+            return Ok(pre_copy);
+        }
+        if !proc::soft_dirty_supported()? {
+            warn!("kernel does not track soft-dirty pages, skipping pre-copy");
+            return Ok(pre_copy);
+        }
+
+        for round in 0..args.pre_copy_rounds {
+            let sent = pre_copy.copy_round(child, con)?;
+            info!("pre-copy round {round} sent {sent} bytes");
+            // the first round sends everything
+            if round > 0 && sent < DIRTY_THRESHOLD {
+                break;
+            }
+        }
+
+        Ok(pre_copy)
+    }
+
+    // the buffer a mapping was pre-copied into, if it still has the same bounds
+    pub(crate) fn buffer(&self, pid: i32, start: u64, end: u64) -> Option<BufferId> {
+        self.buffers.get(&(pid, start, end)).copied()
+    }
+
+    fn copy_round(&mut self, child: Pid, con: &mut ServerConnection) -> Result<usize> {
+        let mut sent = 0;
+        for p in proc::process_tree(child) {
+            let pid = p.pid();
+            // the process may exit or change its mappings while we copy it,
+            // whatever we miss here is sent once it is frozen.
+            // a page first written between listing the pages and clearing
+            // the soft-dirty bits would be in neither this round nor the
+            // next, so the process is paused for both. it runs again while
+            // the pages are read, which only makes us send newer data that
+            // the next round or the final copy sends again anyway
+            let Ok(copies) = self.list_pages(&p) else {
+                continue;
+            };
+
+            for PageCopy {
+                key,
+                id,
+                pages,
+                update,
+            } in copies
+            {
+                // the pieces sent before a failure are dropped by the
+                // destination along with the buffer, the mapping then gets
+                // a new one once frozen
+                match send_buffer(con, pid, id, key.1, &pages, update) {
+                    Ok(n) => sent += n,
+                    Err(e) => {
+                        debug!("dropping pre-copied {:x}-{:x} of {pid}: {e}", key.1, key.2);
+                        self.buffers.remove(&key);
+                        continue;
+                    }
+                }
+                self.buffers.insert(key, id);
+            }
+        }
+
+        Ok(sent)
+    }
+
+    // the pages of each anonymous mapping of p to copy this round, the
+    // soft-dirty bits are cleared once they are known
+    fn list_pages(&mut self, p: &Process) -> Result<Vec<PageCopy>> {
+        let pid = p.pid();
+        let _paused = proc::pause(pid)?;
+        let mut copies = vec![];
+        for m in p.maps()? {
+            let (start, end) = m.address;
+            let perm = m.perms.bits() as _;
+            if !proc::is_private_anon(pid, start, end, perm) {
+                continue;
+            }
+
+            let key = (pid, start, end);
+            let copy = match self.buffers.get(&key) {
+                Some(id) => proc::dirty_pages(pid, start, end).map(|p| (*id, p, true)),
+                None => proc::resident_pages(pid, start, end, perm)
+                    .map(|p| (proc::next_buffer_id(), p, false)),
+            };
+            match copy {
+                Ok((id, pages, update)) => copies.push(PageCopy {
+                    key,
+                    id,
+                    pages,
+                    update,
+                }),
+                // its dirty pages are about to be forgotten, once frozen it
+                // is sent whole instead
+                Err(_) => {
+                    self.buffers.remove(&key);
+                }
+            }
+        }
+        proc::clear_soft_dirty(pid)?;
+
+        Ok(copies)
+    }
+}
@@ -1,47 +1,75 @@
 use std::{
+    cell::RefCell,
+    collections::HashMap,
     ffi::c_void,
-    io::IoSliceMut,
+    fs::{self, File},
+    io::Read,
     mem::{size_of, MaybeUninit},
-    slice,
+    os::{
+        fd::{AsRawFd, FromRawFd},
+        unix::{
+            ffi::OsStrExt,
+            fs::{FileExt, MetadataExt, OpenOptionsExt},
+        },
+    },
+    path::Path,
     sync::atomic::{AtomicU32, Ordering},
+    thread,
+    time::Duration,
 };
 
 use escapepod_common::{
     anyhow::{bail, Context, Result},
-    libc,
+    delta,
+    libc::{self, c_int},
     nix::{
+        errno::Errno,
+        fcntl::{fcntl, tee, FcntlArg, OFlag, SpliceFFlags},
         sys::{
             ptrace,
             signal::{self, Signal},
-            uio::{process_vm_readv, RemoteIoVec},
+            wait::{waitpid, WaitPidFlag, WaitStatus},
         },
-        unistd::Pid,
+        unistd::{pipe2, Pid},
     },
     procfs::{
         self,
-        process::{FDTarget, MMapPath},
+        process::{FDTarget, MMPermissions, MMapPath},
     },
     proto::{
-        Fd, FdFile, FdPipe, FdType, MappedFile, MemoryMapping, MemoryMappingData, Process, Thread,
+        BufferId, Fd, FdDeletedFile, FdFile, FdMemFd, FdPipe, FdType, FileId, Hash, MappedFile,
+        MappedMemFd, MemFd, MemoryLayout, MemoryMapping, MemoryMappingData, ModifiedPages, PageRun,
+        Pipe, Process, SigAction, Thread, MEMORY_CHUNK,
     },
     tracing::{debug, warn},
 };
 
+use super::{anon, files, inet, precopy::PreCopy, sockets::UnixSockets};
 use crate::args::Args;
 
 static BUFFER_ID: AtomicU32 = AtomicU32::new(0);
 
-pub(crate) fn freeze(_args: &Args, child: Pid) -> Result<Vec<Process>> {
+pub(crate) fn freeze(
+    _args: &Args,
+    child: Pid,
+    pre_copy: &PreCopy,
+    hashes: &FileHashes,
+) -> Result<Vec<Process>> {
     let mut procs = vec![];
     freeze_proc_recursive(child, &mut procs)?;
+    let sockets = UnixSockets::new(&procs)?;
+    let files = DeletedFiles::default();
 
-    Ok(vec![parse_proc_recursive(child)?])
+    Ok(vec![parse_proc_recursive(
+        child, pre_copy, hashes, &sockets, &files,
+    )?])
 }
 
 // todo: get active processes from preload over socket
 fn freeze_proc_recursive(pid: Pid, procs: &mut Vec<procfs::process::Process>) -> Result<()> {
     signal::kill(pid, Signal::SIGSTOP)?;
     let proc = procfs::process::Process::new(pid.as_raw())?;
+    wait_for_group_stop(&proc)?;
 
     for thread in proc.tasks()? {
         let thread = thread?;
@@ -54,122 +82,892 @@ fn freeze_proc_recursive(pid: Pid, procs: &mut Vec<procfs::process::Process>) ->
     Ok(())
 }
 
-fn parse_proc_recursive(pid: Pid) -> Result<Process> {
+// the stop must have been dequeued by every thread before we start tracing
+// them, otherwise a seized thread reports it as a signal and never stops
+fn wait_for_group_stop(proc: &procfs::process::Process) -> Result<()> {
+    for _ in 0..1000 {
+        let mut stopped = true;
+        for thread in proc.tasks()? {
+            stopped &= thread?.stat()?.state == 'T';
+        }
+        if stopped {
+            return Ok(());
+        }
+        thread::sleep(Duration::from_millis(1));
+    }
+
+    bail!("timed out waiting for {} to stop", proc.pid())
+}
+
+// ids of the deleted files open in the trees, each is sent once however
+// many times it is open
+#[derive(Default)]
+struct DeletedFiles(RefCell<HashMap<(u64, u64), FileId>>);
+
+impl DeletedFiles {
+    fn id(&self, dev: u64, inode: u64) -> FileId {
+        let mut ids = self.0.borrow_mut();
+        *ids.entry((dev, inode)).or_insert_with(files::next_file_id)
+    }
+}
+
+fn parse_proc_recursive(
+    pid: Pid,
+    pre_copy: &PreCopy,
+    hashes: &FileHashes,
+    sockets: &UnixSockets,
+    files: &DeletedFiles,
+) -> Result<Process> {
     let proc = procfs::process::Process::new(pid.as_raw())?;
 
     let fd_table = proc
         .fd()?
-        .into_iter()
         .map(|f| {
-            f.map(|f| Fd {
+            let f = f.context("fd")?;
+            let (position, flags) = parse_fdinfo(proc.pid(), f.fd)?;
+
+            Ok(Fd {
                 fd: f.fd,
                 mode: f.mode as _,
+                flags,
                 r#type: match f.target {
-                    FDTarget::Path(f) => FdType::File(FdFile {
-                        file: f,
-                        position: 0, // todo
-                    }),
-                    FDTarget::Socket(_) => todo!(),
-                    FDTarget::Net(_) => todo!(),
+                    FDTarget::Path(file) => {
+                        let stat = fs::metadata(format!("/proc/{}/fd/{}", proc.pid(), f.fd))?;
+                        // the path now leads nowhere or to another file
+                        if stat.is_file() && stat.nlink() == 0 {
+                            FdType::DeletedFile(FdDeletedFile {
+                                file: files.id(stat.dev(), stat.ino()),
+                                position,
+                            })
+                        } else {
+                            FdType::File(FdFile { file, position })
+                        }
+                    }
+                    FDTarget::Socket(inode) => match sockets.fd(inode) {
+                        Some(s) => FdType::SocketUnix(s),
+                        None => FdType::SocketIp(inet::socket(proc.pid(), f.fd, inode)?),
+                    },
+                    FDTarget::Net(inode) => {
+                        bail!(
+                            "fd {} of {} is an unsupported net inode {inode}",
+                            f.fd,
+                            proc.pid()
+                        )
+                    }
                     FDTarget::Pipe(id) => FdType::Pipe(FdPipe { pipe_id: id }),
-                    FDTarget::AnonInode(_) => todo!(),
-                    FDTarget::MemFD(_) => todo!(),
-                    FDTarget::Other(_, _) => todo!(),
+                    FDTarget::AnonInode(name) => anon::anon_inode(proc.pid(), f.fd, &name)?,
+                    FDTarget::MemFD(_) => FdType::MemFd(FdMemFd {
+                        inode: fs::metadata(format!("/proc/{}/fd/{}", proc.pid(), f.fd))?.ino(),
+                        position,
+                    }),
+                    FDTarget::Other(kind, inode) => {
+                        bail!(
+                            "fd {} of {} is an unsupported {kind} inode {inode}",
+                            f.fd,
+                            proc.pid()
+                        )
+                    }
                 },
             })
-            .context("fd")
         })
         .collect::<Result<Vec<_>>>()?;
 
-    let mmaps = proc
-        .maps()?
+    let maps = proc.maps()?;
+    let layout = parse_layout(&proc)?;
+    let mmaps = maps
         .into_iter()
-        .map(|m| MemoryMapping {
-            address: m.address.0,
-            len: m.address.1 - m.address.0,
-            perm: m.perms.bits() as _,
-            data: match m.pathname {
-                MMapPath::Vvar => MemoryMappingData::KernelVvar,
-                _ => MemoryMappingData::Buffer(BUFFER_ID.fetch_add(1, Ordering::Relaxed)),
-            },
+        // vsyscall is at a fixed address provided by every kernel
+        .filter(|m| m.pathname != MMapPath::Vsyscall)
+        .map(|m| parse_mmap(&proc, m, pre_copy, hashes))
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut threads = proc
+        .tasks()?
+        .map(|t| {
+            t.context("task")
+                .and_then(|t| parse_thread(&t, pre_copy, hashes, sockets, files))
         })
-        .collect();
+        .collect::<Result<Vec<_>>>()?;
+    // the main thread is always the first to be restored
+    threads.sort_by_key(|t| t.tid != proc.pid());
 
     let proc = Process {
         pid: proc.pid(),
         mmaps,
+        layout,
         fd_table,
-        threads: proc
-            .tasks()?
-            .into_iter()
-            .map(|t| {
-                t.context("task").and_then(|t| {
-                    let status = t.status()?;
-                    Ok(Thread {
-                        tid: t.tid,
-                        uid: status.euid,
-                        gid: status.egid,
-                        reg: get_thread_regset(&t)?,
-                        children: t
-                            .children()?
-                            .into_iter()
-                            .map(|i| parse_proc_recursive(Pid::from_raw(i as _)))
-                            .collect::<Result<_>>()?,
-                    })
-                })
-            })
-            .collect::<Result<Vec<_>>>()?,
+        sigactions: get_sigactions(Pid::from_raw(proc.pid()))?,
+        threads,
     };
 
     Ok(proc)
 }
 
-fn get_thread_regset(t: &procfs::process::Task) -> Result<Vec<u8>> {
+fn parse_layout(proc: &procfs::process::Process) -> Result<MemoryLayout> {
+    let stat = proc.stat()?;
+    let field = |v: Option<u64>| v.with_context(|| format!("memory layout of {}", proc.pid()));
+
+    Ok(MemoryLayout {
+        start_code: stat.startcode,
+        end_code: stat.endcode,
+        start_data: field(stat.start_data)?,
+        end_data: field(stat.end_data)?,
+        start_brk: field(stat.start_brk)?,
+        brk: get_brk(Pid::from_raw(proc.pid()))?,
+        start_stack: stat.startstack,
+        arg_start: field(stat.arg_start)?,
+        arg_end: field(stat.arg_end)?,
+        env_start: field(stat.env_start)?,
+        env_end: field(stat.env_end)?,
+    })
+}
+
+// returns the offset and open flags of fd, these include O_CLOEXEC
+fn parse_fdinfo(pid: i32, fd: i32) -> Result<(u64, c_int)> {
+    let info = fs::read_to_string(format!("/proc/{pid}/fdinfo/{fd}"))?;
+
+    let (mut pos, mut flags) = (None, None);
+    for line in info.lines() {
+        match line.split_once(':') {
+            Some(("pos", v)) => pos = Some(v.trim().parse()?),
+            Some(("flags", v)) => flags = Some(c_int::from_str_radix(v.trim(), 8)?),
+            _ => {}
+        }
+    }
+
+    match (pos, flags) {
+        (Some(pos), Some(flags)) => Ok((pos, flags)),
+        _ => bail!("failed to parse fdinfo of {fd} of {pid}"),
+    }
+}
+
+fn parse_mmap(
+    proc: &procfs::process::Process,
+    m: procfs::process::MemoryMap,
+    pre_copy: &PreCopy,
+    hashes: &FileHashes,
+) -> Result<MemoryMapping> {
+    let data = match m.pathname {
+        MMapPath::Vvar => MemoryMappingData::KernelVvar,
+        MMapPath::Vdso => MemoryMappingData::KernelVdso(next_buffer_id()),
+        // newer kernels split the clock pages out of [vvar]
+        MMapPath::Other(ref p) if p == "vvar_vclock" => MemoryMappingData::KernelVvar,
+        MMapPath::Path(ref p)
+            if p.as_os_str().as_bytes().starts_with(b"/memfd:")
+                && m.perms.contains(MMPermissions::SHARED) =>
+        {
+            MemoryMappingData::MemFd(MappedMemFd {
+                inode: m.inode,
+                offset: m.offset,
+            })
+        }
+        MMapPath::Path(ref p) => match parse_mapped_file(proc, &m, p, hashes)? {
+            Some(f) => MemoryMappingData::File(f),
+            None => MemoryMappingData::Buffer(next_buffer_id()),
+        },
+        // the pages copied while the process was running are kept
+        _ => MemoryMappingData::Buffer(
+            pre_copy
+                .buffer(proc.pid(), m.address.0, m.address.1)
+                .unwrap_or_else(next_buffer_id),
+        ),
+    };
+
+    Ok(MemoryMapping {
+        address: m.address.0,
+        len: m.address.1 - m.address.0,
+        perm: m.perms.bits() as _,
+        data,
+    })
+}
+
+// file mappings are remapped from the file at the destination so only the
+// pages the process has modified are sent, this is not possible if the file
+// was deleted or replaced after being mapped
+fn parse_mapped_file(
+    proc: &procfs::process::Process,
+    m: &procfs::process::MemoryMap,
+    path: &Path,
+    hashes: &FileHashes,
+) -> Result<Option<MappedFile>> {
+    let Some((file, stat)) = open_mapped_file(proc, m, path)? else {
+        return Ok(None);
+    };
+    let hash = hashes
+        .hash(&file, &stat, m.offset, m.address.1 - m.address.0)
+        .with_context(|| format!("failed to hash mapped file {path:?}"))?;
+
+    let mut modified = vec![];
+    if m.perms.contains(MMPermissions::PRIVATE) {
+        for run in modified_pages(proc.pid(), m.address.0, m.address.1)? {
+            modified.push(ModifiedPages {
+                offset: run.offset,
+                len: run.len,
+                buffer: next_buffer_id(),
+            });
+        }
+    }
+
+    Ok(Some(MappedFile {
+        path: path.to_path_buf(),
+        offset: m.offset,
+        size: stat.size(),
+        hash,
+        modified,
+        buffer: next_buffer_id(),
+    }))
+}
+
+// opens the file at path if it is still the one mapped at m
+fn open_mapped_file(
+    proc: &procfs::process::Process,
+    m: &procfs::process::MemoryMap,
+    path: &Path,
+) -> Result<Option<(File, fs::Metadata)>> {
+    let mapped = fs::metadata(format!(
+        "/proc/{}/map_files/{:x}-{:x}",
+        proc.pid(),
+        m.address.0,
+        m.address.1
+    ))?;
+    let file = match File::open(path) {
+        Ok(f) => f,
+        Err(_) => return Ok(None),
+    };
+    let stat = file.metadata()?;
+    if (stat.dev(), stat.ino()) != (mapped.dev(), mapped.ino()) {
+        return Ok(None);
+    }
+
+    Ok(Some((file, stat)))
+}
+
+// hashes of the mapped ranges of files. they are taken before the freeze so
+// that while the processes are stopped only the files modified since are
+// hashed, and a range mapped by many processes is hashed once
+#[derive(Default)]
+pub(crate) struct FileHashes(RefCell<HashMap<(u64, u64, u64, u64), RangeHash>>);
+
+// the mtime and size of the file when the range was hashed
+struct RangeHash {
+    hash: Hash,
+    mtime: (i64, i64),
+    size: u64,
+}
+
+impl FileHashes {
+    // hashes what the tree of pid maps, mappings which cannot be hashed now
+    // are left for after the freeze
+    pub(crate) fn collect(pid: Pid) -> Self {
+        let hashes = Self::default();
+        if let Err(e) = hashes.collect_recursive(pid) {
+            debug!("failed to hash the mapped files of {pid} before the freeze: {e:#}");
+        }
+        hashes
+    }
+
+    fn collect_recursive(&self, pid: Pid) -> Result<()> {
+        let proc = procfs::process::Process::new(pid.as_raw())?;
+        for m in proc.maps()? {
+            let MMapPath::Path(ref path) = m.pathname else {
+                continue;
+            };
+            if let Ok(Some((file, stat))) = open_mapped_file(&proc, &m, path) {
+                self.hash(&file, &stat, m.offset, m.address.1 - m.address.0)?;
+            }
+        }
+        for task in proc.tasks()? {
+            for child in task?.children()? {
+                self.collect_recursive(Pid::from_raw(child as _))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // a file whose size and mtime are unchanged is taken to be unchanged
+    fn hash(&self, file: &File, stat: &fs::Metadata, offset: u64, len: u64) -> Result<Hash> {
+        let key = (stat.dev(), stat.ino(), offset, len);
+        let mtime = (stat.mtime(), stat.mtime_nsec());
+        if let Some(h) = self.0.borrow().get(&key) {
+            if h.mtime == mtime && h.size == stat.size() {
+                return Ok(h.hash);
+            }
+        }
+
+        let hash = delta::hash_range(file, offset, len)?;
+        self.0.borrow_mut().insert(
+            key,
+            RangeHash {
+                hash,
+                mtime,
+                size: stat.size(),
+            },
+        );
+        Ok(hash)
+    }
+}
+
+pub(crate) fn next_buffer_id() -> BufferId {
+    BUFFER_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+const PM_PRESENT: u64 = 1 << 63;
+const PM_SWAP: u64 = 1 << 62;
+const PM_FILE: u64 = 1 << 61;
+const PM_SOFT_DIRTY: u64 = 1 << 55;
+// pagemap entries read at a time
+const PAGEMAP_WINDOW: u64 = 512;
+
+// returns runs of pages in a private file mapping which no longer match the
+// file, these have been copied into anonymous memory on write
+fn modified_pages(pid: i32, start: u64, end: u64) -> Result<Vec<PageRun>> {
+    page_runs(pid, start, end, |entry| {
+        entry & PM_SWAP != 0 || (entry & PM_PRESENT != 0 && entry & PM_FILE == 0)
+    })
+}
+
+// only pages of a private anonymous mapping that were never touched are known
+// to be zero, any other page may come from a file or a shared mapping of
+// another process
+pub(crate) fn is_private_anon(pid: i32, start: u64, end: u64, perm: c_int) -> bool {
+    perm & MMPermissions::SHARED.bits() as c_int == 0
+        && !Path::new(&format!("/proc/{pid}/map_files/{start:x}-{end:x}")).exists()
+}
+
+// returns runs of pages in a mapping which may hold data
+pub(crate) fn resident_pages(pid: i32, start: u64, end: u64, perm: c_int) -> Result<Vec<PageRun>> {
+    if !is_private_anon(pid, start, end, perm) {
+        return Ok(vec![PageRun::new(0, end - start)]);
+    }
+
+    page_runs(pid, start, end, |entry| entry & (PM_PRESENT | PM_SWAP) != 0)
+}
+
+// returns runs of pages written since the soft-dirty bits of pid were last
+// cleared, every page of a mapping created since then counts as written
+pub(crate) fn dirty_pages(pid: i32, start: u64, end: u64) -> Result<Vec<PageRun>> {
+    page_runs(pid, start, end, |entry| entry & PM_SOFT_DIRTY != 0)
+}
+
+// the threads of a process held in a ptrace stop, which unlike SIGSTOP goes
+// unnoticed by its parent. they run again once this is dropped
+pub(crate) struct Paused(Vec<Pid>);
+
+impl Drop for Paused {
+    fn drop(&mut self) {
+        for tid in self.0.iter() {
+            let _ = ptrace::detach(*tid, None);
+        }
+    }
+}
+
+pub(crate) fn pause(pid: i32) -> Result<Paused> {
+    let proc = procfs::process::Process::new(pid)?;
+    let mut paused = Paused(vec![]);
+    // threads started while we seize the others are caught by the next pass
+    loop {
+        let mut seized = false;
+        for task in proc.tasks()? {
+            let tid = Pid::from_raw(task?.tid);
+            if paused.0.contains(&tid) {
+                continue;
+            }
+            ptrace::seize(tid, ptrace::Options::empty())?;
+            paused.0.push(tid);
+            ptrace::interrupt(tid)?;
+            wait_for_trace_stop(tid)?;
+            seized = true;
+        }
+        if !seized {
+            return Ok(paused);
+        }
+    }
+}
+
+pub(crate) fn clear_soft_dirty(pid: i32) -> Result<()> {
+    fs::write(format!("/proc/{pid}/clear_refs"), "4")
+        .with_context(|| format!("failed to clear soft-dirty bits of {pid}"))
+}
+
+// soft-dirty bits are only tracked by kernels built with CONFIG_MEM_SOFT_DIRTY,
+// without it no page is ever reported as written so we try it on ourselves
+pub(crate) fn soft_dirty_supported() -> Result<bool> {
+    let page_size = procfs::page_size() as usize;
+    let mut buf = vec![0u8; page_size * 2];
+    let offset = buf.as_ptr().align_offset(page_size);
+    let page = buf[offset..].as_mut_ptr();
+
+    let pid = std::process::id() as i32;
+    clear_soft_dirty(pid)?;
+    unsafe { page.write_volatile(1) };
+    let dirty = dirty_pages(pid, page as u64, page as u64 + page_size as u64)?;
+
+    Ok(!dirty.is_empty())
+}
+
+// runs of pages in start..end, relative to start, whose pagemap entry matches
+fn page_runs(
+    pid: i32,
+    start: u64,
+    end: u64,
+    matches: impl Fn(u64) -> bool,
+) -> Result<Vec<PageRun>> {
+    let page_size = procfs::page_size();
+    let pagemap = fs::File::open(format!("/proc/{pid}/pagemap"))?;
+    let pages = (end - start) / page_size;
+    // huge reserved mappings are read a window at a time
+    let mut buf = vec![0u8; PAGEMAP_WINDOW as usize * size_of::<u64>()];
+
+    let mut runs: Vec<PageRun> = vec![];
+    let mut page = 0;
+    while page < pages {
+        let len = (pages - page).min(PAGEMAP_WINDOW) as usize * size_of::<u64>();
+        pagemap
+            .read_exact_at(
+                &mut buf[..len],
+                (start / page_size + page) * size_of::<u64>() as u64,
+            )
+            .with_context(|| format!("failed to read pagemap of {pid} at {start:x}"))?;
+
+        for entry in buf[..len].chunks_exact(size_of::<u64>()) {
+            let entry = u64::from_ne_bytes(entry.try_into().unwrap());
+            let offset = page * page_size;
+            page += 1;
+            if !matches(entry) {
+                continue;
+            }
+
+            match runs.last_mut() {
+                Some(run) if run.offset + run.len == offset => run.len += page_size,
+                _ => runs.push(PageRun::new(offset, page_size)),
+            }
+        }
+    }
+
+    Ok(runs)
+}
+
+fn parse_thread(
+    t: &procfs::process::Task,
+    pre_copy: &PreCopy,
+    hashes: &FileHashes,
+    sockets: &UnixSockets,
+    files: &DeletedFiles,
+) -> Result<Thread> {
+    let status = t.status()?;
+    let tid = Pid::from_raw(t.tid);
+
     // todo: avoid using ptrace
-    let pid = Pid::from_raw(t.pid);
+    ptrace::seize(tid, ptrace::Options::empty())?;
+    ptrace::interrupt(tid)?;
+    wait_for_trace_stop(tid)?;
+
+    let reg = get_thread_regset(tid)?;
+    let tls = get_thread_tls(tid, &reg)?;
+    let clear_child_tid = get_thread_clear_child_tid(tid)?;
+    let (robust_list, robust_list_len) = get_thread_robust_list(tid)?;
+
+    ptrace::detach(tid, None)?;
+
+    Ok(Thread {
+        tid: t.tid,
+        uid: status.euid,
+        gid: status.egid,
+        reg,
+        tls,
+        clear_child_tid,
+        sigmask: status.sigblk,
+        robust_list,
+        robust_list_len,
+        children: t
+            .children()?
+            .into_iter()
+            .map(|i| parse_proc_recursive(Pid::from_raw(i as _), pre_copy, hashes, sockets, files))
+            .collect::<Result<_>>()?,
+    })
+}
 
-    ptrace::attach(pid)?;
+fn wait_for_trace_stop(tid: Pid) -> Result<()> {
+    match waitpid(tid, Some(WaitPidFlag::__WALL))? {
+        WaitStatus::PtraceEvent(_, _, libc::PTRACE_EVENT_STOP) => Ok(()),
+        status => bail!("unexpected status while interrupting {tid}: {status:?}"),
+    }
+}
 
-    let reg = unsafe {
-        let mut regset: libc::user_regs_struct = MaybeUninit::zeroed().assume_init();
+fn get_regset(tid: Pid, nt: i32, buf: &mut [u8]) -> Result<()> {
+    unsafe {
         let mut io: libc::iovec = MaybeUninit::zeroed().assume_init();
-        io.iov_base = &mut regset as *mut _ as *mut _;
-        io.iov_len = size_of::<libc::user_regs_struct>();
+        io.iov_base = buf.as_mut_ptr() as *mut _;
+        io.iov_len = buf.len();
         let res = libc::ptrace(
             libc::PTRACE_GETREGSET,
-            pid,
-            libc::NT_PRSTATUS as *mut c_void,
+            tid,
+            nt as *mut c_void,
             &mut io as *mut _,
         );
-        if res != 0 {
+        if res != 0 || io.iov_len != buf.len() {
             bail!("PTRACE_GETREGSET failed");
         }
+    }
 
-        let mut reg = vec![];
-        reg.extend_from_slice(slice::from_raw_parts(io.iov_base as *const u8, io.iov_len));
-        reg
-    };
+    Ok(())
+}
 
-    ptrace::detach(pid, None)?;
+fn get_thread_regset(tid: Pid) -> Result<Vec<u8>> {
+    let mut reg = vec![0u8; size_of::<libc::user_regs_struct>()];
+    get_regset(tid, libc::NT_PRSTATUS, reg.as_mut_slice())?;
 
     Ok(reg)
 }
 
-pub(crate) fn read_mmap(proc: &Process, mmap: &MemoryMapping) -> Result<Vec<u8>> {
-    let mut buf = vec![0u8; mmap.len as _];
-    let mut remote_iov = vec![RemoteIoVec {
-        base: mmap.address as _,
-        len: mmap.len as _,
-    }];
+// the tls base is part of the general purpose regset on x86_64
+#[cfg(target_arch = "x86_64")]
+fn get_thread_tls(_tid: Pid, reg: &[u8]) -> Result<u64> {
+    let regs = unsafe { (reg.as_ptr() as *const libc::user_regs_struct).read_unaligned() };
+
+    Ok(regs.fs_base)
+}
+
+#[cfg(target_arch = "aarch64")]
+fn get_thread_tls(tid: Pid, _reg: &[u8]) -> Result<u64> {
+    const NT_ARM_TLS: i32 = 0x401;
+    let mut tls = [0u8; size_of::<u64>()];
+    get_regset(tid, NT_ARM_TLS, &mut tls)?;
+
+    Ok(u64::from_ne_bytes(tls))
+}
+
+// the clear_child_tid pointer is only visible to the thread itself
+// so we have the thread call prctl(PR_GET_TID_ADDRESS) on our behalf
+#[cfg(target_arch = "x86_64")]
+fn get_thread_clear_child_tid(tid: Pid) -> Result<u64> {
+    const PR_GET_TID_ADDRESS: u64 = 40;
+
+    let regs = ptrace::getregs(tid)?;
+    // scratch space below the red zone of the thread's stack
+    let addr = regs.rsp - 128 - size_of::<u64>() as u64;
+    let res = inject_syscall(tid, libc::SYS_prctl, &[PR_GET_TID_ADDRESS, addr])?;
+    if res != 0 {
+        bail!("PR_GET_TID_ADDRESS failed for {tid}: {res}");
+    }
+
+    Ok(ptrace::read(tid, addr as _)? as u64)
+}
+
+// runs f with the thread held in a ptrace stop
+fn traced<T>(tid: Pid, f: impl FnOnce() -> Result<T>) -> Result<T> {
+    ptrace::seize(tid, ptrace::Options::empty())?;
+    ptrace::interrupt(tid)?;
+    wait_for_trace_stop(tid)?;
+
+    let res = f();
+    ptrace::detach(tid, None)?;
+
+    res
+}
+
+// stat only has where the heap starts, the break itself is only visible
+// through brk which leaves it as it is when asked to move it to 0
+#[cfg(target_arch = "x86_64")]
+fn get_brk(pid: Pid) -> Result<u64> {
+    traced(pid, || Ok(inject_syscall(pid, libc::SYS_brk, &[0])? as u64))
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+fn get_brk(pid: Pid) -> Result<u64> {
+    bail!("reading the break of {pid} is not supported on this architecture")
+}
+
+// signal dispositions are only visible to the process itself so we have its
+// main thread read each of them with rt_sigaction. those left at SIG_DFL are
+// kept as well since the restore stub has dispositions of its own
+#[cfg(target_arch = "x86_64")]
+fn get_sigactions(pid: Pid) -> Result<Vec<SigAction>> {
+    // the highest signal number, SIGRTMAX
+    const SIGNALS: i32 = 64;
+    // struct sigaction of the kernel
+    const SIGACTION_SIZE: u64 = 4 * size_of::<u64>() as u64;
+
+    traced(pid, || {
+        let regs = ptrace::getregs(pid)?;
+        // scratch space below the red zone of the thread's stack
+        let addr = regs.rsp - 128 - SIGACTION_SIZE;
+        let read = |field: u64| -> Result<u64> {
+            Ok(ptrace::read(pid, (addr + field * size_of::<u64>() as u64) as _)? as u64)
+        };
+
+        let mut sigactions = vec![];
+        for signal in 1..=SIGNALS {
+            if signal == libc::SIGKILL || signal == libc::SIGSTOP {
+                continue;
+            }
+            let res = inject_syscall(
+                pid,
+                libc::SYS_rt_sigaction,
+                &[signal as u64, 0, addr, size_of::<u64>() as u64],
+            )?;
+            if res != 0 {
+                bail!("rt_sigaction of {signal} failed for {pid}: {res}");
+            }
+            sigactions.push(SigAction {
+                signal,
+                handler: read(0)?,
+                flags: read(1)?,
+                restorer: read(2)?,
+                mask: read(3)?,
+            });
+        }
+
+        Ok(sigactions)
+    })
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+fn get_sigactions(pid: Pid) -> Result<Vec<SigAction>> {
+    bail!("reading the signal dispositions of {pid} is not supported on this architecture")
+}
+
+// unlike the clear_child_tid pointer the kernel hands the robust list of a
+// thread to anyone who may trace it
+fn get_thread_robust_list(tid: Pid) -> Result<(u64, u64)> {
+    let (mut head, mut len) = (0u64, 0usize);
+    let res = unsafe {
+        libc::syscall(
+            libc::SYS_get_robust_list,
+            tid.as_raw(),
+            &mut head as *mut u64,
+            &mut len as *mut usize,
+        )
+    };
+    Errno::result(res).with_context(|| format!("failed to get the robust list of {tid}"))?;
+
+    Ok((head, len as u64))
+}
+
+// injecting the syscall is only implemented for x86_64
+#[cfg(not(target_arch = "x86_64"))]
+fn get_thread_clear_child_tid(tid: Pid) -> Result<u64> {
+    bail!("reading the clear_child_tid of {tid} is not supported on this architecture")
+}
+
+#[cfg(target_arch = "x86_64")]
+fn inject_syscall(tid: Pid, nr: i64, args: &[u64]) -> Result<i64> {
+    // syscall; int3
+    const SYSCALL_INT3: i64 = 0xcc050f;
+
+    let saved = ptrace::getregs(tid)?;
+    let ip = saved.rip as ptrace::AddressType;
+    let code = ptrace::read(tid, ip)?;
+
+    let mut regs = saved;
+    regs.rax = nr as _;
+    // prevents the kernel from restarting the syscall the thread was stopped in
+    regs.orig_rax = u64::MAX;
+    for (reg, arg) in [
+        &mut regs.rdi,
+        &mut regs.rsi,
+        &mut regs.rdx,
+        &mut regs.r10,
+        &mut regs.r8,
+        &mut regs.r9,
+    ]
+    .into_iter()
+    .zip(args)
+    {
+        *reg = *arg;
+    }
+
+    unsafe { ptrace::write(tid, ip, ((code & !0xffffff) | SYSCALL_INT3) as *mut c_void)? };
+    ptrace::setregs(tid, regs)?;
+
+    let res = (|| loop {
+        ptrace::cont(tid, None)?;
+        match waitpid(tid, Some(WaitPidFlag::__WALL))? {
+            WaitStatus::Stopped(_, Signal::SIGTRAP) => return Ok(ptrace::getregs(tid)?.rax as i64),
+            // the pending group stop may be reported before the injected code runs
+            WaitStatus::PtraceEvent(_, _, libc::PTRACE_EVENT_STOP) => continue,
+            status => bail!("unexpected status while injecting syscall into {tid}: {status:?}"),
+        }
+    })();
+
+    unsafe { ptrace::write(tid, ip, code as *mut c_void)? };
+    ptrace::setregs(tid, saved)?;
+
+    res
+}
+
+// reads the pages of the mapping at address in pieces of at most
+// MEMORY_CHUNK bytes and hands each to f along with its offset
+pub(crate) fn read_memory(
+    pid: i32,
+    address: u64,
+    pages: &[PageRun],
+    mut f: impl FnMut(u64, Vec<u8>) -> Result<()>,
+) -> Result<()> {
+    // unlike process_vm_readv this also reads mappings without PROT_READ
+    let mem = fs::File::open(format!("/proc/{pid}/mem"))?;
+    for run in pages {
+        let mut offset = run.offset;
+        while offset < run.offset + run.len {
+            let mut buf = vec![0u8; (run.offset + run.len - offset).min(MEMORY_CHUNK) as usize];
+            mem.read_exact_at(&mut buf, address + offset)
+                .with_context(|| format!("failed to read {:x} of {pid}", address + offset))?;
+            let len = buf.len() as u64;
+            f(offset, buf)?;
+            offset += len;
+        }
+    }
+
+    Ok(())
+}
+
+// pipes with a read and a write end in the trees, a pipe missing either is
+// connected to a process outside of them and left to the destination
+pub(crate) fn pipes(procs: &[Process]) -> Result<Vec<Pipe>> {
+    let mut ends: HashMap<u64, (i32, i32, bool, bool)> = HashMap::new();
+    for proc in procs.iter().flat_map(|i| i.self_and_descendents()) {
+        for fd in proc.fd_table.iter() {
+            let FdType::Pipe(p) = &fd.r#type else {
+                continue;
+            };
+            let (_, _, read, write) = ends
+                .entry(p.pipe_id)
+                .or_insert((proc.pid, fd.fd, false, false));
+            match fd.flags & libc::O_ACCMODE {
+                libc::O_RDONLY => *read = true,
+                libc::O_WRONLY => *write = true,
+                _ => (*read, *write) = (true, true),
+            }
+        }
+    }
+
+    let mut pipes = vec![];
+    for (pipe_id, (pid, fd, read, write)) in ends {
+        if !(read && write) {
+            warn!("pipe {pipe_id} of {pid} leads outside of the process trees, not restoring it");
+            continue;
+        }
+
+        let (size, buf) = peek_pipe(pid, fd)?;
+        debug!("pipe {pipe_id} of {pid} holds {} bytes", buf.len());
+        pipes.push(Pipe { pipe_id, size, buf });
+    }
+
+    Ok(pipes)
+}
+
+// the memfds of the trees, which may only be left mapped once their fd is closed
+// returns the memfds along with a file to read their data from
+pub(crate) fn memfds(procs: &[Process]) -> Result<Vec<(MemFd, fs::File)>> {
+    let mut paths = HashMap::new();
+    for proc in procs.iter().flat_map(|i| i.self_and_descendents()) {
+        for fd in proc.fd_table.iter() {
+            if let FdType::MemFd(m) = &fd.r#type {
+                paths
+                    .entry(m.inode)
+                    .or_insert_with(|| format!("/proc/{}/fd/{}", proc.pid, fd.fd));
+            }
+        }
+        for mmap in proc.mmaps.iter() {
+            if let MemoryMappingData::MemFd(m) = &mmap.data {
+                paths.entry(m.inode).or_insert_with(|| {
+                    format!(
+                        "/proc/{}/map_files/{:x}-{:x}",
+                        proc.pid,
+                        mmap.address,
+                        mmap.address_end()
+                    )
+                });
+            }
+        }
+    }
+
+    let mut memfds = vec![];
+    for (inode, path) in paths {
+        let link = fs::read_link(&path)?;
+        let name = link
+            .to_string_lossy()
+            .strip_prefix("/memfd:")
+            .map(|n| n.trim_end_matches(" (deleted)").to_string())
+            .with_context(|| format!("{path} is not a memfd: {link:?}"))?;
 
-    // since we only have one iovec element this cannot result in a partial read
-    process_vm_readv(
-        Pid::from_raw(proc.pid),
-        &mut [IoSliceMut::new(buf.as_mut_slice())],
-        remote_iov.as_mut_slice(),
-    )?;
+        let data = fs::File::open(&path)?;
+        let seals = fcntl(data.as_raw_fd(), FcntlArg::F_GET_SEALS)?;
+        debug!(
+            "memfd {inode} ({name}) holds {} bytes",
+            data.metadata()?.len()
+        );
+        memfds.push((
+            MemFd {
+                inode,
+                name,
+                seals,
+                file: files::next_file_id(),
+            },
+            data,
+        ));
+    }
+
+    Ok(memfds)
+}
+
+// returns the capacity of the pipe of fd and the data buffered in it, which
+// is duplicated with tee so the frozen processes never notice
+fn peek_pipe(pid: i32, fd: i32) -> Result<(c_int, Vec<u8>)> {
+    // reopening an end of a pipe through procfs gives us a new end of our choice
+    let pipe = fs::OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NONBLOCK)
+        .open(format!("/proc/{pid}/fd/{fd}"))?;
+    let size = fcntl(pipe.as_raw_fd(), FcntlArg::F_GETPIPE_SZ)?;
+
+    let (peek_read, peek_write) = pipe2(OFlag::O_CLOEXEC | OFlag::O_NONBLOCK)?;
+    let (mut peek_read, peek_write) = unsafe {
+        (
+            fs::File::from_raw_fd(peek_read),
+            fs::File::from_raw_fd(peek_write),
+        )
+    };
+    fcntl(peek_write.as_raw_fd(), FcntlArg::F_SETPIPE_SZ(size))?;
+
+    let len = match tee(
+        pipe.as_raw_fd(),
+        peek_write.as_raw_fd(),
+        size as _,
+        SpliceFFlags::SPLICE_F_NONBLOCK,
+    ) {
+        Ok(len) => len,
+        // nothing buffered
+        Err(Errno::EAGAIN) => 0,
+        Err(e) => return Err(e.into()),
+    };
+    let mut buf = vec![0u8; len];
+    peek_read.read_exact(&mut buf)?;
 
-    Ok(buf)
+    Ok((size, buf))
+}
+
+// the processes under pid, which may come and go while they are running
+pub(crate) fn process_tree(pid: Pid) -> Vec<procfs::process::Process> {
+    let mut procs = vec![];
+    let mut pending = vec![pid.as_raw()];
+    while let Some(pid) = pending.pop() {
+        let Ok(proc) = procfs::process::Process::new(pid) else {
+            continue;
+        };
+        for task in proc.tasks().into_iter().flatten().flatten() {
+            pending.extend(
+                task.children()
+                    .unwrap_or_default()
+                    .iter()
+                    .map(|i| *i as i32),
+            );
+        }
+        procs.push(proc);
+    }
+
+    procs
 }
 
 pub(crate) fn kill(proc: &Process) -> Result<()> {
@@ -180,3 +978,239 @@ pub(crate) fn kill(proc: &Process) -> Result<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Seek, SeekFrom},
+        sync::atomic::AtomicU64,
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_parse_fdinfo() {
+        let path = std::env::temp_dir().join(format!("escapepod-fdinfo-{}", std::process::id()));
+        fs::write(&path, "some data").unwrap();
+        let mut file = fs::OpenOptions::new()
+            .append(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open(&path)
+            .unwrap();
+        file.seek(SeekFrom::Start(5)).unwrap();
+
+        let (position, flags) = parse_fdinfo(std::process::id() as i32, file.as_raw_fd()).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(position, 5);
+        assert_eq!(flags & libc::O_ACCMODE, libc::O_WRONLY);
+        for flag in [libc::O_APPEND, libc::O_NONBLOCK, libc::O_CLOEXEC] {
+            assert_ne!(flags & flag, 0, "missing flag {flag:o} in {flags:o}");
+        }
+    }
+
+    #[test]
+    fn test_page_runs_span_windows() {
+        let page_size = procfs::page_size();
+        let len = (PAGEMAP_WINDOW * 3) * page_size;
+        let addr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len as usize,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        assert_ne!(addr, libc::MAP_FAILED);
+        // a huge page would make all its pages resident at once
+        unsafe { libc::madvise(addr, len as usize, libc::MADV_NOHUGEPAGE) };
+        // a run across the first window boundary and a single page in the last
+        for page in [PAGEMAP_WINDOW - 1, PAGEMAP_WINDOW, PAGEMAP_WINDOW * 2 + 7] {
+            unsafe {
+                (addr as *mut u8)
+                    .add((page * page_size) as usize)
+                    .write_volatile(1)
+            };
+        }
+
+        let start = addr as u64;
+        let runs = resident_pages(std::process::id() as i32, start, start + len, 0).unwrap();
+        unsafe { libc::munmap(addr, len as usize) };
+
+        assert_eq!(
+            runs,
+            [
+                PageRun::new((PAGEMAP_WINDOW - 1) * page_size, 2 * page_size),
+                PageRun::new((PAGEMAP_WINDOW * 2 + 7) * page_size, page_size),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_read_memory_in_chunks() {
+        let len = 4 * MEMORY_CHUNK;
+        let addr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len as usize,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        assert_ne!(addr, libc::MAP_FAILED);
+        let from = unsafe { std::slice::from_raw_parts_mut(addr as *mut u8, len as usize) };
+        for (i, b) in from.iter_mut().enumerate() {
+            *b = (i / 4096 + i) as u8;
+        }
+
+        // a run of two and a half chunks and a short one after a gap
+        let chunk = MEMORY_CHUNK;
+        let runs = [
+            PageRun::new(0, chunk * 5 / 2),
+            PageRun::new(chunk * 3, chunk / 2),
+        ];
+        let mut to = vec![0u8; len as usize];
+        let mut chunks = vec![];
+        read_memory(
+            std::process::id() as i32,
+            addr as u64,
+            &runs,
+            |offset, data| {
+                chunks.push((offset, data.len() as u64));
+                to[offset as usize..][..data.len()].copy_from_slice(&data);
+                Ok(())
+            },
+        )
+        .unwrap();
+        let expected = from
+            .iter()
+            .enumerate()
+            .map(|(i, b)| {
+                match runs
+                    .iter()
+                    .any(|r| (r.offset..r.offset + r.len).contains(&(i as u64)))
+                {
+                    true => *b,
+                    false => 0,
+                }
+            })
+            .collect::<Vec<_>>();
+        unsafe { libc::munmap(addr, len as usize) };
+
+        assert_eq!(
+            chunks,
+            [
+                (0, chunk),
+                (chunk, chunk),
+                (2 * chunk, chunk / 2),
+                (3 * chunk, chunk / 2)
+            ]
+        );
+        assert!(to == expected);
+    }
+
+    #[test]
+    fn test_file_hashes_skip_unchanged_files() {
+        let path = std::env::temp_dir().join(format!("escapepod-hashes-{}", std::process::id()));
+        fs::write(&path, "some data").unwrap();
+        let hashes = FileHashes::default();
+        let hash = || {
+            let file = File::open(&path).unwrap();
+            hashes.hash(&file, &file.metadata().unwrap(), 0, 9).unwrap()
+        };
+        let before = hash();
+
+        // taken to be unchanged as its size and mtime are
+        let mtime = fs::metadata(&path).unwrap().modified().unwrap();
+        fs::write(&path, "same size").unwrap();
+        File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(mtime)
+            .unwrap();
+        let unchanged = hash();
+        fs::write(&path, "other data").unwrap();
+        let changed = hash();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(unchanged, before);
+        assert_eq!(changed, delta::hash(b"other dat"));
+    }
+
+    #[test]
+    fn test_pause() {
+        let counter = unsafe {
+            let addr = libc::mmap(
+                std::ptr::null_mut(),
+                size_of::<AtomicU32>(),
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            );
+            assert_ne!(addr, libc::MAP_FAILED);
+            &*(addr as *const AtomicU32)
+        };
+        let child = match unsafe { libc::fork() } {
+            0 => loop {
+                counter.fetch_add(1, Ordering::Relaxed);
+            },
+            pid => pid,
+        };
+        let count = || {
+            let before = counter.load(Ordering::Relaxed);
+            thread::sleep(Duration::from_millis(20));
+            counter.load(Ordering::Relaxed) - before
+        };
+
+        let paused = pause(child).unwrap();
+        let while_paused = count();
+        drop(paused);
+        let after = count();
+        signal::kill(Pid::from_raw(child), Signal::SIGKILL).unwrap();
+        waitpid(Pid::from_raw(child), None).unwrap();
+
+        assert_eq!(while_paused, 0);
+        assert_ne!(after, 0);
+    }
+
+    #[test]
+    fn test_get_brk() {
+        let brk = unsafe {
+            let addr = libc::mmap(
+                std::ptr::null_mut(),
+                size_of::<AtomicU64>(),
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            );
+            assert_ne!(addr, libc::MAP_FAILED);
+            &*(addr as *const AtomicU64)
+        };
+        let child = match unsafe { libc::fork() } {
+            0 => unsafe {
+                // leaves the break in the middle of a page
+                libc::sbrk(4097);
+                brk.store(libc::sbrk(0) as u64, Ordering::Release);
+                loop {
+                    libc::pause();
+                }
+            },
+            pid => Pid::from_raw(pid),
+        };
+        while brk.load(Ordering::Acquire) == 0 {
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        let res = get_brk(child);
+        signal::kill(child, Signal::SIGKILL).unwrap();
+        waitpid(child, None).unwrap();
+
+        assert_eq!(res.unwrap(), brk.load(Ordering::Acquire));
+    }
+}
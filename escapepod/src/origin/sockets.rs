@@ -0,0 +1,464 @@
+use std::{
+    collections::{HashMap, HashSet},
+    ffi::OsStr,
+    mem::size_of,
+    os::{
+        fd::{AsRawFd, FromRawFd, OwnedFd},
+        unix::ffi::OsStrExt,
+    },
+    path::PathBuf,
+    slice,
+};
+
+use escapepod_common::{
+    anyhow::{bail, Context, Result},
+    libc::{self, c_int},
+    nix::{
+        errno::Errno,
+        sys::socket::{
+            recv, send, socket, AddressFamily, MsgFlags, SockFlag, SockProtocol, SockType,
+        },
+    },
+    procfs::{self, process::FDTarget},
+    proto::{FdSocketUnix, FdType, Process, SocketQueue, SocketUnixState},
+    tracing::{debug, warn},
+};
+
+use crate::sockopt;
+
+const SOCK_DIAG_BY_FAMILY: u16 = 20;
+const UDIAG_SHOW_NAME: u32 = 0x01;
+const UDIAG_SHOW_PEER: u32 = 0x04;
+const UDIAG_SHOW_RQLEN: u32 = 0x10;
+const UNIX_DIAG_NAME: u16 = 0;
+const UNIX_DIAG_PEER: u16 = 2;
+const UNIX_DIAG_RQLEN: u16 = 4;
+// unix sockets report their state with the tcp states
+const TCP_LISTEN: u8 = 10;
+
+// largest datagram we can peek, larger ones fail the freeze
+const MAX_PACKET: usize = 1024 * 1024;
+
+#[repr(C)]
+struct UnixDiagReq {
+    sdiag_family: u8,
+    sdiag_protocol: u8,
+    pad: u16,
+    udiag_states: u32,
+    udiag_ino: u32,
+    udiag_show: u32,
+    udiag_cookie: [u32; 2],
+}
+
+#[repr(C)]
+struct UnixDiagMsg {
+    udiag_family: u8,
+    udiag_type: u8,
+    udiag_state: u8,
+    pad: u8,
+    udiag_ino: u32,
+    udiag_cookie: [u32; 2],
+}
+
+// a unix socket as reported by sock_diag
+struct UnixSocket {
+    r#type: c_int,
+    listening: bool,
+    name: Option<PathBuf>,
+    peer: Option<u64>,
+    // the backlog of a listening socket
+    wqueue: u32,
+}
+
+// the unix sockets of our network namespace along with those open in the
+// frozen process trees
+pub(crate) struct UnixSockets {
+    sockets: HashMap<u64, UnixSocket>,
+    frozen: HashSet<u64>,
+}
+
+impl UnixSockets {
+    pub(crate) fn new(procs: &[procfs::process::Process]) -> Result<Self> {
+        let mut frozen = HashSet::new();
+        for proc in procs {
+            for fd in proc.fd()? {
+                if let FDTarget::Socket(inode) = fd?.target {
+                    frozen.insert(inode);
+                }
+            }
+        }
+
+        Ok(Self {
+            sockets: unix_diag()?,
+            frozen,
+        })
+    }
+
+    // None if inode is not a unix socket
+    pub(crate) fn fd(&self, inode: u64) -> Option<FdSocketUnix> {
+        let sock = self.sockets.get(&inode)?;
+        let state = match sock.peer.map(|p| (p, self.sockets.get(&p))) {
+            _ if sock.listening => SocketUnixState::Listen {
+                backlog: sock.wqueue,
+            },
+            Some((p, Some(peer))) if self.frozen.contains(&p) && peer.peer == Some(inode) => {
+                SocketUnixState::Pair(p)
+            }
+            Some((
+                _,
+                Some(UnixSocket {
+                    name: Some(name), ..
+                }),
+            )) => SocketUnixState::Connect(name.clone()),
+            Some((p, _)) => {
+                warn!("unix socket {inode} is connected to {p} which cannot be reached by name, not reconnecting it");
+                SocketUnixState::Unconnected
+            }
+            None => SocketUnixState::Unconnected,
+        };
+
+        Some(FdSocketUnix {
+            inode,
+            r#type: sock.r#type,
+            name: sock.name.clone(),
+            state,
+        })
+    }
+}
+
+// dumps every unix socket of our network namespace
+fn unix_diag() -> Result<HashMap<u64, UnixSocket>> {
+    let sock = socket(
+        AddressFamily::Netlink,
+        SockType::Datagram,
+        SockFlag::SOCK_CLOEXEC,
+        SockProtocol::NetlinkSockDiag,
+    )?;
+    let sock = unsafe { OwnedFd::from_raw_fd(sock) };
+
+    let hdr = libc::nlmsghdr {
+        nlmsg_len: (size_of::<libc::nlmsghdr>() + size_of::<UnixDiagReq>()) as _,
+        nlmsg_type: SOCK_DIAG_BY_FAMILY,
+        nlmsg_flags: (libc::NLM_F_REQUEST | libc::NLM_F_DUMP) as _,
+        nlmsg_seq: 1,
+        nlmsg_pid: 0,
+    };
+    let req = UnixDiagReq {
+        sdiag_family: libc::AF_UNIX as _,
+        sdiag_protocol: 0,
+        pad: 0,
+        udiag_states: u32::MAX,
+        udiag_ino: 0,
+        udiag_show: UDIAG_SHOW_NAME | UDIAG_SHOW_PEER | UDIAG_SHOW_RQLEN,
+        udiag_cookie: [u32::MAX; 2],
+    };
+    let mut msg = as_bytes(&hdr).to_vec();
+    msg.extend_from_slice(as_bytes(&req));
+    send(sock.as_raw_fd(), &msg, MsgFlags::empty())?;
+
+    let mut sockets = HashMap::new();
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let len = recv(sock.as_raw_fd(), &mut buf, MsgFlags::empty())?;
+        let mut msgs = &buf[..len];
+        while msgs.len() >= size_of::<libc::nlmsghdr>() {
+            let hdr = unsafe { (msgs.as_ptr() as *const libc::nlmsghdr).read_unaligned() };
+            let len = hdr.nlmsg_len as usize;
+            if len < size_of::<libc::nlmsghdr>() || len > msgs.len() {
+                bail!("malformed sock_diag message");
+            }
+            let payload = &msgs[size_of::<libc::nlmsghdr>()..len];
+            match hdr.nlmsg_type as c_int {
+                libc::NLMSG_DONE => return Ok(sockets),
+                libc::NLMSG_ERROR => {
+                    let err = i32::from_ne_bytes(payload[..size_of::<i32>()].try_into()?);
+                    bail!("sock_diag failed: {}", Errno::from_i32(-err));
+                }
+                _ => {
+                    let (inode, sock) = parse_unix_diag(payload)?;
+                    sockets.insert(inode, sock);
+                }
+            }
+            msgs = &msgs[len.next_multiple_of(4).min(msgs.len())..];
+        }
+    }
+}
+
+fn parse_unix_diag(msg: &[u8]) -> Result<(u64, UnixSocket)> {
+    if msg.len() < size_of::<UnixDiagMsg>() {
+        bail!("truncated unix_diag message");
+    }
+    let diag = unsafe { (msg.as_ptr() as *const UnixDiagMsg).read_unaligned() };
+    let mut sock = UnixSocket {
+        r#type: diag.udiag_type as _,
+        listening: diag.udiag_state == TCP_LISTEN,
+        name: None,
+        peer: None,
+        wqueue: 0,
+    };
+
+    // attributes are a length and a type followed by the payload
+    let mut attrs = &msg[size_of::<UnixDiagMsg>()..];
+    while attrs.len() >= 4 {
+        let len = u16::from_ne_bytes([attrs[0], attrs[1]]) as usize;
+        let r#type = u16::from_ne_bytes([attrs[2], attrs[3]]);
+        if len < 4 || len > attrs.len() {
+            bail!("malformed unix_diag attribute");
+        }
+        let data = &attrs[4..len];
+        match r#type {
+            UNIX_DIAG_NAME => {
+                // path names come with their nul terminator, abstract ones
+                // start with a nul byte instead
+                let name = match data.split_last() {
+                    Some((0, name)) if data[0] != 0 => name,
+                    _ => data,
+                };
+                sock.name = Some(PathBuf::from(OsStr::from_bytes(name)));
+            }
+            UNIX_DIAG_PEER => {
+                let peer = data.get(..4).context("short unix_diag peer")?;
+                sock.peer = Some(u32::from_ne_bytes(peer.try_into()?) as u64);
+            }
+            UNIX_DIAG_RQLEN => {
+                let wqueue = data.get(4..8).context("short unix_diag queue lengths")?;
+                sock.wqueue = u32::from_ne_bytes(wqueue.try_into()?);
+            }
+            _ => {}
+        }
+        attrs = &attrs[len.next_multiple_of(4).min(attrs.len())..];
+    }
+
+    Ok((diag.udiag_ino as u64, sock))
+}
+
+fn as_bytes<T>(v: &T) -> &[u8] {
+    unsafe { slice::from_raw_parts(v as *const T as *const u8, size_of::<T>()) }
+}
+
+// data queued on the unix sockets of the trees, which only pairs get back as
+// there is no way to queue data on a socket from outside of the trees
+pub(crate) fn queues(procs: &[Process]) -> Result<Vec<SocketQueue>> {
+    let mut seen = HashSet::new();
+    let mut queues = vec![];
+    for proc in procs.iter().flat_map(|i| i.self_and_descendents()) {
+        for fd in proc.fd_table.iter() {
+            let FdType::SocketUnix(s) = &fd.r#type else {
+                continue;
+            };
+            if matches!(s.state, SocketUnixState::Listen { .. }) || !seen.insert(s.inode) {
+                continue;
+            }
+
+            let packets = peek_socket(proc.pid, fd.fd, s.r#type)
+                .with_context(|| format!("failed to peek unix socket {}", s.inode))?;
+            if packets.is_empty() {
+                continue;
+            }
+            if !matches!(s.state, SocketUnixState::Pair(_)) {
+                warn!(
+                    "dropping {} packets queued on unix socket {} of {}",
+                    packets.len(),
+                    s.inode,
+                    proc.pid
+                );
+                continue;
+            }
+
+            debug!(
+                "unix socket {} of {} holds {} packets",
+                s.inode,
+                proc.pid,
+                packets.len()
+            );
+            queues.push(SocketQueue {
+                inode: s.inode,
+                packets,
+            });
+        }
+    }
+
+    Ok(queues)
+}
+
+// reads what is queued on the socket of fd without consuming it, the peek
+// offset moves us past what we have already seen
+fn peek_socket(pid: i32, fd: i32, r#type: c_int) -> Result<Vec<Vec<u8>>> {
+    let sock = pidfd_getfd(pid, fd)?;
+    // the socket is shared with the process, whose own offset is put back
+    let peek_off: c_int = sockopt::get(sock.as_raw_fd(), libc::SOL_SOCKET, libc::SO_PEEK_OFF)?;
+    sockopt::set(sock.as_raw_fd(), libc::SOL_SOCKET, libc::SO_PEEK_OFF, &0)?;
+
+    let mut packets = vec![];
+    let mut buf = vec![0u8; MAX_PACKET];
+    let flags = MsgFlags::MSG_PEEK | MsgFlags::MSG_DONTWAIT | MsgFlags::MSG_TRUNC;
+    let res = loop {
+        match recv(sock.as_raw_fd(), &mut buf, flags) {
+            Ok(len) if len > buf.len() => break Err(Errno::EMSGSIZE),
+            // the peer has shut down its end
+            Ok(0) if r#type == libc::SOCK_STREAM => break Ok(()),
+            // once the queue is drained a socket shut down for reading
+            // returns nothing forever, an empty packet queued on it is lost
+            Ok(0) if read_shutdown(&sock) => break Ok(()),
+            Ok(len) => packets.push(buf[..len].to_vec()),
+            Err(Errno::EAGAIN) => break Ok(()),
+            Err(e) => break Err(e),
+        }
+    };
+    sockopt::set(
+        sock.as_raw_fd(),
+        libc::SOL_SOCKET,
+        libc::SO_PEEK_OFF,
+        &peek_off,
+    )?;
+    res?;
+
+    // a stream has no boundaries to keep
+    if r#type == libc::SOCK_STREAM && !packets.is_empty() {
+        packets = vec![packets.concat()];
+    }
+
+    Ok(packets)
+}
+
+// whether the socket was shut down for reading, by us or by its peer
+fn read_shutdown(sock: &OwnedFd) -> bool {
+    let mut fd = libc::pollfd {
+        fd: sock.as_raw_fd(),
+        events: libc::POLLRDHUP,
+        revents: 0,
+    };
+    let res = unsafe { libc::poll(&mut fd, 1, 0) };
+
+    res == 1 && fd.revents & libc::POLLRDHUP != 0
+}
+
+// a duplicate of fd of pid, sockets cannot be reopened through procfs
+pub(crate) fn pidfd_getfd(pid: i32, fd: i32) -> Result<OwnedFd> {
+    let pidfd = unsafe { libc::syscall(libc::SYS_pidfd_open, pid, 0) };
+    if pidfd < 0 {
+        return Err(Errno::last().into());
+    }
+    let pidfd = unsafe { OwnedFd::from_raw_fd(pidfd as _) };
+
+    let res = unsafe { libc::syscall(libc::SYS_pidfd_getfd, pidfd.as_raw_fd(), fd, 0) };
+    if res < 0 {
+        return Err(Errno::last().into());
+    }
+
+    Ok(unsafe { OwnedFd::from_raw_fd(res as _) })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn attr(r#type: u16, data: &[u8]) -> Vec<u8> {
+        let len = 4 + data.len() as u16;
+        let mut attr = [&len.to_ne_bytes()[..], &r#type.to_ne_bytes(), data].concat();
+        attr.resize(attr.len().next_multiple_of(4), 0);
+        attr
+    }
+
+    fn diag(state: u8, attrs: &[Vec<u8>]) -> Vec<u8> {
+        let msg = UnixDiagMsg {
+            udiag_family: libc::AF_UNIX as _,
+            udiag_type: libc::SOCK_STREAM as _,
+            udiag_state: state,
+            pad: 0,
+            udiag_ino: 1234,
+            udiag_cookie: [0; 2],
+        };
+        [as_bytes(&msg).to_vec(), attrs.concat()].concat()
+    }
+
+    #[test]
+    fn test_parse_unix_diag_listening() {
+        let rqlen = [0u32.to_ne_bytes(), 16u32.to_ne_bytes()].concat();
+        let msg = diag(
+            TCP_LISTEN,
+            &[
+                attr(UNIX_DIAG_NAME, b"/run/app.sock\0"),
+                attr(UNIX_DIAG_RQLEN, &rqlen),
+            ],
+        );
+        let (inode, sock) = parse_unix_diag(&msg).unwrap();
+        assert_eq!(inode, 1234);
+        assert_eq!(sock.r#type, libc::SOCK_STREAM);
+        assert!(sock.listening);
+        assert_eq!(sock.name, Some(PathBuf::from("/run/app.sock")));
+        assert_eq!(sock.peer, None);
+        assert_eq!(sock.wqueue, 16);
+    }
+
+    #[test]
+    fn test_parse_unix_diag_abstract_peer() {
+        let msg = diag(
+            1,
+            &[
+                attr(UNIX_DIAG_NAME, b"\0abstract"),
+                attr(UNIX_DIAG_PEER, &5678u32.to_ne_bytes()),
+            ],
+        );
+        let (_, sock) = parse_unix_diag(&msg).unwrap();
+        assert!(!sock.listening);
+        assert_eq!(
+            sock.name,
+            Some(PathBuf::from(OsStr::from_bytes(b"\0abstract")))
+        );
+        assert_eq!(sock.peer, Some(5678));
+    }
+
+    #[test]
+    fn test_parse_unix_diag_malformed() {
+        assert!(parse_unix_diag(&[0; 4]).is_err());
+        let mut msg = diag(1, &[attr(UNIX_DIAG_PEER, &[0; 4])]);
+        // an attribute claiming more than is left
+        let at = msg.len() - 8;
+        msg[at..at + 2].copy_from_slice(&64u16.to_ne_bytes());
+        assert!(parse_unix_diag(&msg).is_err());
+        assert!(parse_unix_diag(&diag(1, &[attr(UNIX_DIAG_PEER, &[0; 2])])).is_err());
+    }
+
+    #[test]
+    fn test_peek_socket_shut_down() {
+        let pid = std::process::id() as i32;
+        for r#type in [libc::SOCK_SEQPACKET, libc::SOCK_DGRAM] {
+            let mut fds = [0; 2];
+            assert_eq!(
+                unsafe { libc::socketpair(libc::AF_UNIX, r#type, 0, fds.as_mut_ptr()) },
+                0
+            );
+            let (a, b) = unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+            for packet in [&b"first"[..], b"", b"third"] {
+                send(b.as_raw_fd(), packet, MsgFlags::empty()).unwrap();
+            }
+            // a peer shutting down only ends the queue of a seqpacket socket
+            match r#type {
+                libc::SOCK_SEQPACKET => unsafe { libc::shutdown(b.as_raw_fd(), libc::SHUT_WR) },
+                _ => unsafe { libc::shutdown(a.as_raw_fd(), libc::SHUT_RD) },
+            };
+            sockopt::set(a.as_raw_fd(), libc::SOL_SOCKET, libc::SO_PEEK_OFF, &3).unwrap();
+
+            // the empty packet ends the queue early, nothing can tell it
+            // apart from the end
+            let packets = peek_socket(pid, a.as_raw_fd(), r#type).unwrap();
+            assert_eq!(packets, [b"first".to_vec()], "type {type}");
+            let peek_off: c_int =
+                sockopt::get(a.as_raw_fd(), libc::SOL_SOCKET, libc::SO_PEEK_OFF).unwrap();
+            assert_eq!(peek_off, 3);
+        }
+    }
+
+    #[test]
+    fn test_peek_socket_empty_packet() {
+        let (a, b) = std::os::unix::net::UnixDatagram::pair().unwrap();
+        for packet in [&b"first"[..], b"", b"third"] {
+            b.send(packet).unwrap();
+        }
+
+        let packets =
+            peek_socket(std::process::id() as i32, a.as_raw_fd(), libc::SOCK_DGRAM).unwrap();
+        assert_eq!(packets, [b"first".to_vec(), vec![], b"third".to_vec()]);
+    }
+}
@@ -0,0 +1,39 @@
+use std::{
+    mem::{size_of, MaybeUninit},
+    os::fd::RawFd,
+};
+
+use escapepod_common::{
+    anyhow::Result,
+    libc::{self, c_int, socklen_t},
+    nix::errno::Errno,
+};
+
+// socket options nix has no wrapper for, T must match the kernel's layout
+pub(crate) fn get<T: Copy>(fd: RawFd, level: c_int, name: c_int) -> Result<T> {
+    let mut value = MaybeUninit::<T>::zeroed();
+    let mut len = size_of::<T>() as socklen_t;
+    let res = unsafe { libc::getsockopt(fd, level, name, value.as_mut_ptr() as *mut _, &mut len) };
+    if res != 0 {
+        return Err(Errno::last().into());
+    }
+
+    Ok(unsafe { value.assume_init() })
+}
+
+pub(crate) fn set<T: ?Sized>(fd: RawFd, level: c_int, name: c_int, value: &T) -> Result<()> {
+    let res = unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            name,
+            value as *const T as *const _,
+            size_of_val(value) as socklen_t,
+        )
+    };
+    if res != 0 {
+        return Err(Errno::last().into());
+    }
+
+    Ok(())
+}